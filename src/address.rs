@@ -0,0 +1,140 @@
+//! Queues named by address, so that callers needn't care whether the queue
+//! is opened directly or reached through a server. An address is either a
+//! local directory, as given to `Queue::open`, or one of the `unix://` or
+//! `tcp://` addresses understood by `Client::open`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use errors::Result;
+use options::QueueOptions;
+use {Client, Consumer, Entry, Producer, Queue, RemoteConsumer, RemoteProducer};
+
+/// A queue opened locally or reached through a server.
+#[derive(Debug,Clone)]
+pub enum AnyQueue {
+    Local(Queue),
+    Remote(Client),
+}
+
+impl AnyQueue {
+    /// Opens the queue at `address` with the default options.
+    pub fn open(address: &str) -> Result<AnyQueue> {
+        QueueOptions::new().open_address(address)
+    }
+
+    pub fn producer(&self) -> AnyProducer {
+        match *self {
+            AnyQueue::Local(ref queue) => AnyProducer::Local(queue.producer()),
+            AnyQueue::Remote(ref client) => AnyProducer::Remote(client.producer()),
+        }
+    }
+
+    pub fn consumer(&self, name: &str) -> Result<AnyConsumer> {
+        match *self {
+            AnyQueue::Local(ref queue) => queue.consumer(name).map(AnyConsumer::Local),
+            AnyQueue::Remote(ref client) => client.consumer(name).map(AnyConsumer::Remote),
+        }
+    }
+
+    /// The last offset committed by the named consumer, or `NotFound`.
+    pub fn consumer_offset(&self, name: &str) -> Result<u64> {
+        match *self {
+            AnyQueue::Local(ref queue) => queue.consumer_offset(name),
+            AnyQueue::Remote(ref client) => client.consumer_offset(name),
+        }
+    }
+
+    pub fn consumers(&self) -> Result<BTreeMap<String, u64>> {
+        match *self {
+            AnyQueue::Local(ref queue) => queue.consumers(),
+            AnyQueue::Remote(ref client) => client.consumers(),
+        }
+    }
+
+    pub fn discard_upto(&self, limit: u64) -> Result<()> {
+        match *self {
+            AnyQueue::Local(ref queue) => queue.discard_upto(limit).map(|_| ()),
+            AnyQueue::Remote(ref client) => client.discard_upto(limit),
+        }
+    }
+}
+
+impl From<Queue> for AnyQueue {
+    fn from(queue: Queue) -> AnyQueue {
+        AnyQueue::Local(queue)
+    }
+}
+
+impl From<Client> for AnyQueue {
+    fn from(client: Client) -> AnyQueue {
+        AnyQueue::Remote(client)
+    }
+}
+
+#[derive(Debug)]
+pub enum AnyProducer {
+    Local(Producer),
+    Remote(RemoteProducer),
+}
+
+impl AnyProducer {
+    /// Appends `msg` to the queue, returning the offset it was given.
+    pub fn produce(&mut self, msg: &[u8]) -> Result<u64> {
+        match *self {
+            AnyProducer::Local(ref mut producer) => producer.produce(msg),
+            AnyProducer::Remote(ref mut producer) => producer.produce(msg),
+        }
+    }
+
+    /// Appends all of `msgs` in a single transaction, returning the offsets
+    /// they were given.
+    pub fn produce_batch<M: AsRef<[u8]>>(&mut self, msgs: &[M]) -> Result<Vec<u64>> {
+        match *self {
+            AnyProducer::Local(ref mut producer) => producer.produce_batch(msgs),
+            AnyProducer::Remote(ref mut producer) => producer.produce_batch(msgs),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AnyConsumer {
+    Local(Consumer),
+    Remote(RemoteConsumer),
+}
+
+impl AnyConsumer {
+    pub fn poll(&mut self) -> Result<Option<Entry>> {
+        match *self {
+            AnyConsumer::Local(ref mut consumer) => consumer.poll(),
+            AnyConsumer::Remote(ref mut consumer) => consumer.poll(),
+        }
+    }
+
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Result<Option<Entry>> {
+        match *self {
+            AnyConsumer::Local(ref mut consumer) => consumer.poll_timeout(timeout),
+            AnyConsumer::Remote(ref mut consumer) => consumer.poll_timeout(timeout),
+        }
+    }
+
+    pub fn commit_upto(&self, entry: &Entry) -> Result<()> {
+        match *self {
+            AnyConsumer::Local(ref consumer) => consumer.commit_upto(entry),
+            AnyConsumer::Remote(ref consumer) => consumer.commit_upto(entry),
+        }
+    }
+}
+
+impl QueueOptions {
+    /// Opens the queue at `address`, which may be a directory, opened with
+    /// these options, or the address of a server, for which they're unused.
+    pub fn open_address(&self, address: &str) -> Result<AnyQueue> {
+        if address.contains("://") {
+            Client::open(address).map(AnyQueue::Remote)
+        } else {
+            self.open(Path::new(address)).map(AnyQueue::Local)
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use lmdb_zero::{copy, open};
+
+use errors::{Error, ErrorKind, Result};
+use {Queue, QueueOptions};
+
+// What LMDB calls the data file inside an environment directory.
+const DATA_FILE: &str = "data.mdb";
+
+impl Queue {
+    /// Writes a consistent snapshot of the queue to `target`, while
+    /// producers and consumers carry on. If `compact` is set, free pages are
+    /// left out, which is slower but yields a smaller copy.
+    ///
+    /// `target` is a directory, created if need be, unless the queue was
+    /// opened with `no_subdir`, in which case it is the file to create. It
+    /// must not already hold a queue.
+    pub fn backup_to<P: AsRef<Path>>(&self, target: P, compact: bool) -> Result<()> {
+        let target = target.as_ref();
+        self.copy_to(target, compact).map_err(|e| e.in_queue(self.path()))
+    }
+
+    fn copy_to(&self, target: &Path, compact: bool) -> Result<()> {
+        let path = try!(target.to_str()
+                              .ok_or_else(|| Error::from(ErrorKind::InvalidPath(target.to_owned()))));
+        let no_subdir = try!(self.env().flags()).contains(open::NOSUBDIR);
+        try!(prepare_target(target, no_subdir));
+
+        let flags = if compact { copy::COMPACT } else { copy::Flags::empty() };
+        info!("Copying {:?} to {:?} with {:?}", self.path(), target, flags);
+        // The copy runs in its own read transaction, so must not overlap a
+        // resize either.
+        let _guard = self.inner.resize_lock.read().expect("resize lock poisoned");
+        try!(self.env().copy(path, flags));
+        Ok(())
+    }
+}
+
+impl QueueOptions {
+    /// Restores the backup at `backup` to `place`, which must not already
+    /// hold a queue, and opens it. Fails without copying anything if the
+    /// backup's format version is not the current one.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(&self, backup: P, place: Q) -> Result<Queue> {
+        let backup = try!(self.open(backup));
+        try!(backup.backup_to(place.as_ref(), false));
+        // LMDB environments can't be opened twice in one process, so let
+        // go of the backup before opening the copy.
+        drop(backup);
+        self.open(place)
+    }
+}
+
+fn prepare_target(target: &Path, no_subdir: bool) -> Result<()> {
+    let data_file = if no_subdir {
+        target.to_owned()
+    } else {
+        try!(fs::create_dir_all(target));
+        target.join(DATA_FILE)
+    };
+    if data_file.exists() {
+        let msg = format!("{:?} already holds a queue", target);
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+    }
+    Ok(())
+}
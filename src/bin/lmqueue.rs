@@ -4,24 +4,87 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-use clap::{Arg, App, SubCommand};
+extern crate byteorder;
+extern crate base64;
+use byteorder::{BigEndian, ReadBytesExt};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
-use std::io::Write;
+use std::fs::File;
+use std::path::Path;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::time::Duration;
 use std::thread;
 use std::cmp;
 
-use std::process::{Stdio, Command};
+use std::process::{self, Stdio, Command};
 
 const DEFAULT_CONSUMER: &'static str = "default";
+const DEFAULT_LISTEN: &str = "127.0.0.1:7373";
+const DEFAULT_HTTP_LISTEN: &str = "127.0.0.1:7374";
+const DEFAULT_REDIS_LISTEN: &str = "127.0.0.1:7375";
+const DEFAULT_KAFKA_LISTEN: &str = "127.0.0.1:7376";
+const DEFAULT_MQTT_LISTEN: &str = "127.0.0.1:7377";
+const DEFAULT_STOMP_LISTEN: &str = "127.0.0.1:7378";
+const DEFAULT_SOCKET_MODE: &str = "660";
+const DEFAULT_PRODUCE_BATCH: usize = 1000;
+const CAT_BATCH: usize = 100;
+const QUEUE_ADDRESS_HELP: &str = "queue directory, or the unix:// or tcp:// address of a \
+                                  server";
 
 fn main() {
     let matches = App::new("listener")
                       .version("???")
                       .author("Ceri Storey")
+                      .arg(Arg::with_name("map-size")
+                               .long("map-size")
+                               .global(true)
+                               .takes_value(true)
+                               .help("initial size of the memory map in bytes"))
+                      .arg(Arg::with_name("max-map-size")
+                               .long("max-map-size")
+                               .global(true)
+                               .takes_value(true)
+                               .help("largest the memory map may grow to, in bytes"))
+                      .arg(Arg::with_name("max-readers")
+                               .long("max-readers")
+                               .global(true)
+                               .takes_value(true)
+                               .help("maximum number of concurrent readers"))
+                      .arg(Arg::with_name("max-dbs")
+                               .long("max-dbs")
+                               .global(true)
+                               .takes_value(true)
+                               .help("maximum number of named databases"))
+                      .arg(Arg::with_name("mode")
+                               .long("mode")
+                               .global(true)
+                               .takes_value(true)
+                               .help("octal file mode used when creating the queue"))
+                      .arg(Arg::with_name("no-subdir")
+                               .long("no-subdir")
+                               .global(true)
+                               .help("treat the queue path as a file, not a directory"))
+                      .arg(Arg::with_name("no-readahead")
+                               .long("no-readahead")
+                               .global(true)
+                               .help("disable OS readahead"))
+                      .arg(Arg::with_name("write-map")
+                               .long("write-map")
+                               .global(true)
+                               .help("use a writable memory map"))
+                      .arg(Arg::with_name("no-sync")
+                               .long("no-sync")
+                               .global(true)
+                               .help("don't flush to disk on commit"))
+                      .arg(Arg::with_name("no-meta-sync")
+                               .long("no-meta-sync")
+                               .global(true)
+                               .help("don't flush the meta page on commit"))
                       .subcommand(SubCommand::with_name("consume")
                                       .about("pipes each item though a command")
-                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .help(QUEUE_ADDRESS_HELP))
                                       .arg(Arg::with_name("name")
                                                .short("n")
                                                .takes_value(true)
@@ -30,13 +93,80 @@ fn main() {
                                                .multiple(true)
                                                .index(2)
                                                .required(true)))
+                      .subcommand(SubCommand::with_name("produce")
+                                      .about("append messages, printing the offset of each")
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .help(QUEUE_ADDRESS_HELP))
+                                      .arg(Arg::with_name("message")
+                                               .short("m")
+                                               .long("message")
+                                               .takes_value(true)
+                                               .multiple(true)
+                                               .number_of_values(1)
+                                               .help("a message to append; may be repeated"))
+                                      .arg(Arg::with_name("file")
+                                               .multiple(true)
+                                               .index(2)
+                                               .help("files to read messages from, or - for \
+                                                      stdin (the default, without -m)"))
+                                      .arg(Arg::with_name("split")
+                                               .short("s")
+                                               .long("split")
+                                               .takes_value(true)
+                                               .possible_values(&["newline", "nul", "length"])
+                                               .help("split input into messages at each \
+                                                      newline or NUL, or by a 4-byte big-endian \
+                                                      length before each; by default, each \
+                                                      input is one message"))
+                                      .arg(Arg::with_name("batch-size")
+                                               .long("batch-size")
+                                               .takes_value(true)
+                                               .help("messages written per transaction \
+                                                      (defaults to 1000)")))
+                      .subcommand(SubCommand::with_name("cat")
+                                      .about("print messages with their offsets")
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .help(QUEUE_ADDRESS_HELP))
+                                      .arg(Arg::with_name("from")
+                                               .long("from")
+                                               .takes_value(true)
+                                               .conflicts_with("name")
+                                               .help("start at offset <N> (defaults to the \
+                                                      first held)"))
+                                      .arg(Arg::with_name("to")
+                                               .long("to")
+                                               .takes_value(true)
+                                               .help("stop after offset <M>"))
+                                      .arg(Arg::with_name("follow")
+                                               .short("f")
+                                               .long("follow")
+                                               .help("wait for more messages once caught up"))
+                                      .arg(Arg::with_name("format")
+                                               .long("format")
+                                               .takes_value(true)
+                                               .possible_values(&["raw", "hex", "base64", "json"])
+                                               .help("how to print each message (defaults to \
+                                                      raw); json prints a line of \
+                                                      {\"offset\":N,\"data\":\"<base64>\"}"))
+                                      .arg(Arg::with_name("name")
+                                               .short("n")
+                                               .takes_value(true)
+                                               .help("read as this consumer, committing each \
+                                                      message printed; without it, no \
+                                                      consumer offset is touched")))
                       .subcommand(SubCommand::with_name("offsets")
                                       .about("list consumer offsets")
-                                      .arg(Arg::with_name("queue").required(true)))
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .help(QUEUE_ADDRESS_HELP)))
                       .subcommand(SubCommand::with_name("trim")
                                       .about("discard upto either a specified value, or what \
                                               the earliest consumer has seen")
-                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .help(QUEUE_ADDRESS_HELP))
                                       .arg(Arg::with_name("to")
                                                .short("t")
                                                .takes_value(true)
@@ -49,42 +179,384 @@ fn main() {
                                                .index(2)
                                                .required(true)
                                                .help("delete consumer with name")))
+                      .subcommand(SubCommand::with_name("verify")
+                                      .about("check every record against its checksum")
+                                      .arg(Arg::with_name("queue").required(true)))
+                      .subcommand(SubCommand::with_name("backup")
+                                      .about("copy the queue while it is in use")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("target")
+                                               .index(2)
+                                               .required(true)
+                                               .help("where to write the copy"))
+                                      .arg(Arg::with_name("compact")
+                                               .long("compact")
+                                               .help("leave out free pages")))
+                      .subcommand(SubCommand::with_name("restore")
+                                      .about("restore a backup to a new queue")
+                                      .arg(Arg::with_name("backup").required(true))
+                                      .arg(Arg::with_name("queue")
+                                               .index(2)
+                                               .required(true)
+                                               .help("where to restore to")))
+                      .subcommand(SubCommand::with_name("dump")
+                                      .about("write the queue out in a portable format")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("output")
+                                               .short("o")
+                                               .takes_value(true)
+                                               .help("file to write to (defaults to stdout)")))
+                      .subcommand(SubCommand::with_name("load")
+                                      .about("fill an empty queue from a dump")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("input")
+                                               .short("i")
+                                               .takes_value(true)
+                                               .help("file to read from (defaults to stdin)")))
+                      .subcommand(SubCommand::with_name("serve")
+                                      .about("serve the queue over TCP or a Unix socket")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("listen")
+                                               .short("l")
+                                               .long("listen")
+                                               .takes_value(true)
+                                               .help("address to listen on (defaults to \
+                                                      127.0.0.1:7373)"))
+                                      .arg(Arg::with_name("unix")
+                                               .long("unix")
+                                               .takes_value(true)
+                                               .conflicts_with("listen")
+                                               .help("Unix socket to listen on instead of TCP"))
+                                      .arg(Arg::with_name("socket-mode")
+                                               .long("socket-mode")
+                                               .takes_value(true)
+                                               .requires("unix")
+                                               .help("octal permissions for the Unix socket \
+                                                      (defaults to 660)"))
+                                      .arg(Arg::with_name("follower-ack-timeout")
+                                               .long("follower-ack-timeout")
+                                               .takes_value(true)
+                                               .help("milliseconds producers wait for a \
+                                                      follower to acknowledge their records"))
+                                      .arg(Arg::with_name("metrics")
+                                               .long("metrics")
+                                               .takes_value(true)
+                                               .help("address to serve Prometheus metrics \
+                                                      on")))
+                      .subcommand(SubCommand::with_name("serve-http")
+                                      .about("serve the queue over HTTP")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("listen")
+                                               .short("l")
+                                               .long("listen")
+                                               .takes_value(true)
+                                               .help("address to listen on (defaults to \
+                                                      127.0.0.1:7374)")))
+                      .subcommand(SubCommand::with_name("serve-redis")
+                                      .about("serve the queue as a Redis stream")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("listen")
+                                               .short("l")
+                                               .long("listen")
+                                               .takes_value(true)
+                                               .help("address to listen on (defaults to \
+                                                      127.0.0.1:7375)")))
+                      .subcommand(SubCommand::with_name("serve-kafka")
+                                      .about("serve queues as Kafka topics")
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .multiple(true)
+                                               .help("queue to serve, as [topic=]path; the \
+                                                      topic defaults to the path's last \
+                                                      component"))
+                                      .arg(Arg::with_name("listen")
+                                               .short("l")
+                                               .long("listen")
+                                               .takes_value(true)
+                                               .help("address to listen on (defaults to \
+                                                      127.0.0.1:7376)")))
+                      .subcommand(SubCommand::with_name("serve-mqtt")
+                                      .about("serve queues to MQTT clients")
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .multiple(true)
+                                               .help("queue to store messages in, as \
+                                                      [filter=]path; the filter defaults to \
+                                                      #, and the first matching one wins"))
+                                      .arg(Arg::with_name("listen")
+                                               .short("l")
+                                               .long("listen")
+                                               .takes_value(true)
+                                               .help("address to listen on (defaults to \
+                                                      127.0.0.1:7377)")))
+                      .subcommand(SubCommand::with_name("serve-stomp")
+                                      .about("serve queues to STOMP clients")
+                                      .arg(Arg::with_name("queue")
+                                               .required(true)
+                                               .multiple(true)
+                                               .help("queue to serve, as [destination=]path; \
+                                                      the destination defaults to \
+                                                      /queue/<directory name>"))
+                                      .arg(Arg::with_name("listen")
+                                               .short("l")
+                                               .long("listen")
+                                               .takes_value(true)
+                                               .help("address to listen on (defaults to \
+                                                      127.0.0.1:7378)")))
+                      .subcommand(SubCommand::with_name("follow")
+                                      .about("replicate from a leader served by `serve`, until \
+                                              promoted")
+                                      .arg(Arg::with_name("queue").required(true))
+                                      .arg(Arg::with_name("leader")
+                                               .index(2)
+                                               .required(true)
+                                               .help("leader's address, as host:port")))
+                      .subcommand(SubCommand::with_name("promote")
+                                      .about("stop following the leader, and accept producers")
+                                      .arg(Arg::with_name("queue").required(true)))
+                      .subcommand(SubCommand::with_name("replication")
+                                      .about("show a follower's leader and lag")
+                                      .arg(Arg::with_name("queue").required(true)))
+                      .subcommand(SubCommand::with_name("stats")
+                                      .about("show what the queue holds, and the space it takes")
+                                      .arg(Arg::with_name("queue").required(true)))
+                      .subcommand(SubCommand::with_name("metrics")
+                                      .about("print the queue's metrics in the Prometheus text \
+                                              format")
+                                      .arg(Arg::with_name("queue").required(true)))
+                      .subcommand(SubCommand::with_name("migrate")
+                                      .about("upgrade the queue to the current on-disk format")
+                                      .arg(Arg::with_name("queue").required(true)))
                       .get_matches();
 
     env_logger::init().expect("env_logger::init");
 
     match matches.subcommand() {
         ("consume", Some(matches)) => {
-            process_consumer(matches.value_of("queue").expect("queue"),
+            process_consumer(&queue_options(matches),
+                             matches.value_of("queue").expect("queue"),
                              matches.value_of("name").unwrap_or(DEFAULT_CONSUMER),
                              matches.values_of("command").expect("command").collect())
         }
-        ("offsets", Some(matches)) => display_offsets(matches.value_of("queue").expect("queue")),
+        ("produce", Some(matches)) => {
+            let batch_size = if matches.is_present("batch-size") {
+                value_t!(matches, "batch-size", usize).unwrap_or_else(|e| e.exit())
+            } else {
+                DEFAULT_PRODUCE_BATCH
+            };
+            let framing = match matches.value_of("split") {
+                Some("newline") => Framing::Delimited(b'\n'),
+                Some("nul") => Framing::Delimited(0),
+                Some("length") => Framing::LengthPrefixed,
+                _ => Framing::Whole,
+            };
+            let messages: Vec<&str> = matches.values_of("message")
+                                             .map_or(Vec::new(), |m| m.collect());
+            let mut files: Vec<&str> = matches.values_of("file")
+                                              .map_or(Vec::new(), |f| f.collect());
+            if messages.is_empty() && files.is_empty() {
+                files.push("-");
+            }
+            process_produce(&queue_options(matches),
+                            matches.value_of("queue").expect("queue"),
+                            messages,
+                            files,
+                            framing,
+                            cmp::max(batch_size, 1))
+        }
+        ("cat", Some(matches)) => {
+            let from = if matches.is_present("from") {
+                value_t!(matches, "from", u64).unwrap_or_else(|e| e.exit())
+            } else {
+                0
+            };
+            let to = if matches.is_present("to") {
+                Some(value_t!(matches, "to", u64).unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
+            let format = match matches.value_of("format") {
+                Some("hex") => CatFormat::Hex,
+                Some("base64") => CatFormat::Base64,
+                Some("json") => CatFormat::Json,
+                _ => CatFormat::Raw,
+            };
+            process_cat(&queue_options(matches),
+                        matches.value_of("queue").expect("queue"),
+                        matches.value_of("name"),
+                        from,
+                        to,
+                        matches.is_present("follow"),
+                        format)
+        }
+        ("offsets", Some(matches)) => {
+            display_offsets(&queue_options(matches),
+                            matches.value_of("queue").expect("queue"))
+        }
         ("trim", Some(matches)) => {
             let upto = if matches.is_present("to") {
                 Some(value_t!(matches, "to", u64).unwrap_or_else(|e| e.exit()))
             } else {
                 None
             };
-            process_trim(matches.value_of("queue").expect("queue"), upto)
+            process_trim(&queue_options(matches),
+                         matches.value_of("queue").expect("queue"),
+                         upto)
         }
         ("rm-consumer", Some(matches)) => {
-            process_rm_consumer(matches.value_of("queue").expect("queue"),
+            process_rm_consumer(&queue_options(matches),
+                                matches.value_of("queue").expect("queue"),
                                 matches.value_of("name").expect("name"))
         }
+        ("verify", Some(matches)) => {
+            process_verify(&queue_options(matches),
+                           matches.value_of("queue").expect("queue"))
+        }
+        ("backup", Some(matches)) => {
+            process_backup(&queue_options(matches),
+                           matches.value_of("queue").expect("queue"),
+                           matches.value_of("target").expect("target"),
+                           matches.is_present("compact"))
+        }
+        ("restore", Some(matches)) => {
+            process_restore(&queue_options(matches),
+                            matches.value_of("backup").expect("backup"),
+                            matches.value_of("queue").expect("queue"))
+        }
+        ("dump", Some(matches)) => {
+            process_dump(&queue_options(matches),
+                         matches.value_of("queue").expect("queue"),
+                         matches.value_of("output"))
+        }
+        ("load", Some(matches)) => {
+            process_load(&queue_options(matches),
+                         matches.value_of("queue").expect("queue"),
+                         matches.value_of("input"))
+        }
+        ("serve", Some(matches)) if matches.is_present("unix") => {
+            let mode = matches.value_of("socket-mode").unwrap_or(DEFAULT_SOCKET_MODE);
+            let mode = u32::from_str_radix(mode, 8).unwrap_or_else(|e| {
+                clap::Error::value_validation_auto(format!("invalid socket mode {:?}: {}", mode, e))
+                    .exit()
+            });
+            process_serve_unix(&serve_options(matches),
+                               matches.value_of("queue").expect("queue"),
+                               matches.value_of("unix").expect("unix"),
+                               mode,
+                               matches.value_of("metrics"))
+        }
+        ("serve", Some(matches)) => {
+            process_serve(&serve_options(matches),
+                          matches.value_of("queue").expect("queue"),
+                          matches.value_of("listen").unwrap_or(DEFAULT_LISTEN),
+                          matches.value_of("metrics"))
+        }
+        ("serve-http", Some(matches)) => {
+            process_serve_http(&queue_options(matches),
+                               matches.value_of("queue").expect("queue"),
+                               matches.value_of("listen").unwrap_or(DEFAULT_HTTP_LISTEN))
+        }
+        ("serve-redis", Some(matches)) => {
+            process_serve_redis(&queue_options(matches),
+                                matches.value_of("queue").expect("queue"),
+                                matches.value_of("listen").unwrap_or(DEFAULT_REDIS_LISTEN))
+        }
+        ("serve-kafka", Some(matches)) => {
+            process_serve_kafka(&queue_options(matches),
+                                matches.values_of("queue").expect("queue").collect(),
+                                matches.value_of("listen").unwrap_or(DEFAULT_KAFKA_LISTEN))
+        }
+        ("serve-mqtt", Some(matches)) => {
+            process_serve_mqtt(&queue_options(matches),
+                               matches.values_of("queue").expect("queue").collect(),
+                               matches.value_of("listen").unwrap_or(DEFAULT_MQTT_LISTEN))
+        }
+        ("serve-stomp", Some(matches)) => {
+            process_serve_stomp(&queue_options(matches),
+                                matches.values_of("queue").expect("queue").collect(),
+                                matches.value_of("listen").unwrap_or(DEFAULT_STOMP_LISTEN))
+        }
+        ("follow", Some(matches)) => {
+            process_follow(&queue_options(matches),
+                           matches.value_of("queue").expect("queue"),
+                           matches.value_of("leader").expect("leader"))
+        }
+        ("promote", Some(matches)) => {
+            process_promote(&queue_options(matches),
+                            matches.value_of("queue").expect("queue"))
+        }
+        ("replication", Some(matches)) => {
+            display_replication(&queue_options(matches),
+                                matches.value_of("queue").expect("queue"))
+        }
+        ("stats", Some(matches)) => {
+            display_stats(&queue_options(matches),
+                          matches.value_of("queue").expect("queue"))
+        }
+        ("metrics", Some(matches)) => {
+            display_metrics(&queue_options(matches),
+                            matches.value_of("queue").expect("queue"))
+        }
+        ("migrate", Some(matches)) => {
+            process_migrate(&queue_options(matches),
+                            matches.value_of("queue").expect("queue"))
+        }
         _ => println!("{}", matches.usage()),
     }
 }
 
-fn process_consumer(dir: &str, consumer_name: &str, filter_command: Vec<&str>) {
-    let mut consumer = lmqueue::Consumer::new(dir, consumer_name).expect("open");
+fn queue_options(matches: &ArgMatches) -> lmqueue::QueueOptions {
+    let mut opts = lmqueue::QueueOptions::new();
+    if matches.is_present("map-size") {
+        opts = opts.map_size(value_t!(matches, "map-size", usize).unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("max-map-size") {
+        opts = opts.max_map_size(value_t!(matches, "max-map-size", usize)
+                                     .unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("max-readers") {
+        opts = opts.max_readers(value_t!(matches, "max-readers", u32).unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("max-dbs") {
+        opts = opts.max_dbs(value_t!(matches, "max-dbs", u32).unwrap_or_else(|e| e.exit()));
+    }
+    if let Some(mode) = matches.value_of("mode") {
+        let mode = lmqueue::FileMode::from_str_radix(mode, 8).unwrap_or_else(|e| {
+            clap::Error::value_validation_auto(format!("invalid file mode {:?}: {}", mode, e))
+                .exit()
+        });
+        opts = opts.file_mode(mode);
+    }
+    opts.no_subdir(matches.is_present("no-subdir"))
+        .no_readahead(matches.is_present("no-readahead"))
+        .write_map(matches.is_present("write-map"))
+        .no_sync(matches.is_present("no-sync"))
+        .no_meta_sync(matches.is_present("no-meta-sync"))
+}
+
+fn serve_options(matches: &ArgMatches) -> lmqueue::QueueOptions {
+    let opts = queue_options(matches);
+    if matches.is_present("follower-ack-timeout") {
+        let ms = value_t!(matches, "follower-ack-timeout", u64).unwrap_or_else(|e| e.exit());
+        opts.follower_ack_timeout(Duration::from_millis(ms))
+    } else {
+        opts
+    }
+}
+
+fn process_consumer(opts: &lmqueue::QueueOptions,
+                    dir: &str,
+                    consumer_name: &str,
+                    filter_command: Vec<&str>) {
+    let queue = opts.open_address(dir).expect("open");
+    let mut consumer = queue.consumer(consumer_name).expect("consumer");
 
     let mut command = Command::new(filter_command[0]);
     command.args(&filter_command[1..]);
     command.stdin(Stdio::piped());
 
     loop {
-        while let Some(data) = consumer.poll().expect("poll") {
+        while let Some(data) = poll_skipping_gaps(&mut consumer) {
             trace!("Polled for {:?}", data);
             let mut child = command.spawn().expect("spawn");
             child.stdin.as_mut().expect("stdin").write_all(&data.data).expect("write to child");
@@ -97,19 +569,276 @@ fn process_consumer(dir: &str, consumer_name: &str, filter_command: Vec<&str>) {
     }
 }
 
-fn display_offsets(dir: &str) {
-    let consumer = lmqueue::Consumer::new(dir, DEFAULT_CONSUMER).expect("open");
-    for (consumer, offset) in consumer.consumers().expect("consumers") {
+// How `produce` divides each input into messages.
+#[derive(Debug,Clone,Copy)]
+enum Framing {
+    Whole,
+    Delimited(u8),
+    // Each message follows its length, as a big-endian u32.
+    LengthPrefixed,
+}
+
+fn process_produce(opts: &lmqueue::QueueOptions,
+                   dir: &str,
+                   messages: Vec<&str>,
+                   files: Vec<&str>,
+                   framing: Framing,
+                   batch_size: usize) {
+    let queue = opts.open_address(dir).expect("open");
+    let stdout = io::stdout();
+    let mut batch = ProduceBatch::new(queue.producer(), batch_size, stdout.lock());
+    for msg in messages {
+        batch.push(msg.as_bytes().to_vec()).expect("produce");
+    }
+    for path in files {
+        if path == "-" {
+            let stdin = io::stdin();
+            let mut input = stdin.lock();
+            produce_from(&mut batch, &mut input, framing).expect("produce");
+        } else {
+            let file = File::open(path).expect("open input");
+            produce_from(&mut batch, &mut BufReader::new(file), framing).expect("produce");
+        }
+    }
+    batch.flush().expect("produce");
+}
+
+fn produce_from<R: BufRead, W: Write>(batch: &mut ProduceBatch<W>,
+                                      input: &mut R,
+                                      framing: Framing)
+                                      -> lmqueue::Result<()> {
+    match framing {
+        Framing::Whole => {
+            let mut msg = Vec::new();
+            try!(input.read_to_end(&mut msg));
+            try!(batch.push(msg));
+        }
+        Framing::Delimited(delimiter) => {
+            loop {
+                let mut msg = Vec::new();
+                if try!(input.read_until(delimiter, &mut msg)) == 0 {
+                    break;
+                }
+                if msg.last() == Some(&delimiter) {
+                    msg.pop();
+                }
+                try!(batch.push(msg));
+            }
+        }
+        Framing::LengthPrefixed => {
+            while !try!(input.fill_buf()).is_empty() {
+                let len = try!(input.read_u32::<BigEndian>()) as u64;
+                // The length can't be trusted, so only buffer what arrives.
+                let mut msg = Vec::new();
+                try!(input.by_ref().take(len).read_to_end(&mut msg));
+                if (msg.len() as u64) < len {
+                    let e = io::Error::new(io::ErrorKind::UnexpectedEof,
+                                           format!("message of {} bytes is truncated", len));
+                    return Err(e.into());
+                }
+                try!(batch.push(msg));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Messages waiting to be produced together, in one transaction. Each offset
+// given out is written to `out`.
+struct ProduceBatch<W> {
+    producer: lmqueue::AnyProducer,
+    messages: Vec<Vec<u8>>,
+    size: usize,
+    out: W,
+}
+
+impl<W: Write> ProduceBatch<W> {
+    fn new(producer: lmqueue::AnyProducer, size: usize, out: W) -> Self {
+        ProduceBatch {
+            producer,
+            messages: Vec::with_capacity(size),
+            size,
+            out,
+        }
+    }
+
+    fn push(&mut self, msg: Vec<u8>) -> lmqueue::Result<()> {
+        self.messages.push(msg);
+        if self.messages.len() >= self.size {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> lmqueue::Result<()> {
+        let offsets = try!(self.producer.produce_batch(&self.messages));
+        for offset in offsets {
+            try!(writeln!(self.out, "{}", offset));
+        }
+        self.messages.clear();
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+enum CatFormat {
+    Raw,
+    Hex,
+    Base64,
+    Json,
+}
+
+fn process_cat(opts: &lmqueue::QueueOptions,
+               dir: &str,
+               consumer_name: Option<&str>,
+               from: u64,
+               to: Option<u64>,
+               follow: bool,
+               format: CatFormat) {
+    let queue = opts.open_address(dir).expect("open");
+    if let (&lmqueue::AnyQueue::Remote(_), None) = (&queue, consumer_name) {
+        clap::Error::with_description("a served queue can only be read as a consumer; name one \
+                                       with -n",
+                                      clap::ErrorKind::MissingRequiredArgument)
+            .exit()
+    }
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    cat(&queue, &mut out, consumer_name, from, to, follow, format);
+}
+
+// Prints entries to `out` for `process_cat`. Only a named consumer commits,
+// and a served queue must be read by one.
+fn cat<W: Write>(queue: &lmqueue::AnyQueue,
+                 out: &mut W,
+                 consumer_name: Option<&str>,
+                 from: u64,
+                 to: Option<u64>,
+                 follow: bool,
+                 format: CatFormat) {
+    let past_end = |offset: u64| to.is_some_and(|to| offset > to);
+    let at_end = |offset: u64| to.is_some_and(|to| offset >= to);
+
+    if let Some(name) = consumer_name {
+        let mut consumer = queue.consumer(name).expect("consumer");
+        loop {
+            match poll_skipping_gaps(&mut consumer) {
+                Some(entry) => {
+                    if past_end(entry.offset) {
+                        break;
+                    }
+                    print_entry(out, &entry, format);
+                    flush_output(out);
+                    consumer.commit_upto(&entry).expect("commit");
+                    if at_end(entry.offset) {
+                        break;
+                    }
+                }
+                None if follow => thread::sleep(Duration::from_millis(100)),
+                None => break,
+            }
+        }
+        return;
+    }
+
+    let queue = match *queue {
+        lmqueue::AnyQueue::Local(ref queue) => queue,
+        lmqueue::AnyQueue::Remote(ref client) => panic!("{:?} needs a consumer", client),
+    };
+    let mut next = from;
+    loop {
+        let entries = queue.entries(next, CAT_BATCH).expect("entries");
+        if entries.is_empty() {
+            if !follow {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+        for entry in entries {
+            if past_end(entry.offset) {
+                flush_output(out);
+                return;
+            }
+            print_entry(out, &entry, format);
+            if at_end(entry.offset) {
+                flush_output(out);
+                return;
+            }
+            next = match entry.offset.checked_add(1) {
+                Some(next) => next,
+                None => {
+                    flush_output(out);
+                    return;
+                }
+            };
+        }
+        flush_output(out);
+    }
+    flush_output(out);
+}
+
+fn print_entry<W: Write>(out: &mut W, entry: &lmqueue::Entry, format: CatFormat) {
+    let res = match format {
+        CatFormat::Raw => {
+            write!(out, "{}\t", entry.offset)
+                .and_then(|()| out.write_all(&entry.data))
+                .and_then(|()| writeln!(out))
+        }
+        CatFormat::Hex => {
+            let hex = entry.data.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+            writeln!(out, "{}\t{}", entry.offset, hex)
+        }
+        CatFormat::Base64 => writeln!(out, "{}\t{}", entry.offset, base64::encode(&entry.data)),
+        CatFormat::Json => {
+            writeln!(out,
+                     "{{\"offset\":{},\"data\":\"{}\"}}",
+                     entry.offset,
+                     base64::encode(&entry.data))
+        }
+    };
+    exit_on_broken_pipe(res);
+}
+
+fn flush_output<W: Write>(out: &mut W) {
+    exit_on_broken_pipe(out.flush());
+}
+
+// Whoever was reading our output has gone, as when piped into `head`.
+fn exit_on_broken_pipe(res: io::Result<()>) {
+    match res {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => process::exit(0),
+        res => res.expect("write output"),
+    }
+}
+
+// Trimmed messages are gone for good, so just note that we missed them.
+fn poll_skipping_gaps(consumer: &mut lmqueue::AnyConsumer) -> Option<lmqueue::Entry> {
+    loop {
+        match consumer.poll() {
+            Err(ref e) if is_trimmed_gap(e) => warn!("{}", e),
+            res => return res.expect("poll"),
+        }
+    }
+}
+
+fn is_trimmed_gap(e: &lmqueue::Error) -> bool {
+    matches!(*e.kind(), lmqueue::ErrorKind::TrimmedGap { .. })
+}
+
+fn display_offsets(opts: &lmqueue::QueueOptions, dir: &str) {
+    let queue = opts.open_address(dir).expect("open");
+    for (consumer, offset) in queue.consumers().expect("consumers") {
         println!("{}\t{}", consumer, offset);
     }
 }
 
 
-fn process_trim(dir: &str, offset: Option<u64>) {
-    let consumer = lmqueue::Consumer::new(dir, DEFAULT_CONSUMER).expect("open");
+fn process_trim(opts: &lmqueue::QueueOptions, dir: &str, offset: Option<u64>) {
+    let queue = opts.open_address(dir).expect("open");
 
     let offset: Option<u64> = offset.or_else(|| {
-        let consumers = consumer.consumers().expect("get consumers");
+        let consumers = queue.consumers().expect("get consumers");
         consumers.values()
                  .cloned()
                  .fold(None,
@@ -117,15 +846,389 @@ fn process_trim(dir: &str, offset: Option<u64>) {
     });
     if let Some(off) = offset {
         info!("Trimming upto: {:?}", off);
-        consumer.discard_upto(off).expect("discard_upto");
+        queue.discard_upto(off).expect("discard_upto");
     } else {
         warn!("No offset found/supplied");
     }
 }
 
 
-fn process_rm_consumer(dir: &str, name: &str) {
-    let mut consumer = lmqueue::Consumer::new(dir, name).expect("open");
+fn process_rm_consumer(opts: &lmqueue::QueueOptions, dir: &str, name: &str) {
+    let queue = opts.open(dir).expect("open");
+    match queue.consumer_offset(name) {
+        Err(ref e) if is_not_found(e) => {
+            warn!("{}", e);
+            return;
+        }
+        res => {
+            res.expect("consumer offset");
+        }
+    }
+    let mut consumer = queue.consumer(name).expect("consumer");
 
     consumer.clear_offset().expect("clear_offset");
 }
+
+fn process_verify(opts: &lmqueue::QueueOptions, dir: &str) {
+    let queue = opts.open(dir).expect("open");
+    let report = queue.verify().expect("verify");
+    for problem in &report.problems {
+        println!("{}: {}", dir, problem);
+    }
+    println!("{}: {} records checked, {} problems found",
+             dir,
+             report.records,
+             report.problems.len());
+    if !report.is_ok() {
+        process::exit(1);
+    }
+}
+
+fn process_backup(opts: &lmqueue::QueueOptions, dir: &str, target: &str, compact: bool) {
+    let queue = opts.open(dir).expect("open");
+    queue.backup_to(target, compact).expect("backup");
+    println!("{}: backed up to {}", dir, target);
+}
+
+fn process_restore(opts: &lmqueue::QueueOptions, backup: &str, dir: &str) {
+    opts.restore(backup, dir).expect("restore");
+    println!("{}: restored from {}", dir, backup);
+}
+
+fn process_dump(opts: &lmqueue::QueueOptions, dir: &str, output: Option<&str>) {
+    let queue = opts.open(dir).expect("open");
+    let count = match output {
+        Some(path) => {
+            let file = File::create(path).expect("create output");
+            queue.dump_to(BufWriter::new(file)).expect("dump")
+        }
+        None => {
+            let stdout = io::stdout();
+            let out = stdout.lock();
+            queue.dump_to(BufWriter::new(out)).expect("dump")
+        }
+    };
+    info!("Dumped {} records from {}", count, dir);
+}
+
+fn process_load(opts: &lmqueue::QueueOptions, dir: &str, input: Option<&str>) {
+    let queue = opts.open(dir).expect("open");
+    let count = match input {
+        Some(path) => {
+            let file = File::open(path).expect("open input");
+            queue.load_from(BufReader::new(file)).expect("load")
+        }
+        None => {
+            let stdin = io::stdin();
+            let input = stdin.lock();
+            queue.load_from(input).expect("load")
+        }
+    };
+    println!("{}: loaded {} records", dir, count);
+}
+
+fn process_serve(opts: &lmqueue::QueueOptions, dir: &str, addr: &str, metrics: Option<&str>) {
+    let queue = opts.open(dir).expect("open");
+    if let Some(metrics) = metrics {
+        spawn_metrics_server(dir, &queue, metrics);
+    }
+    let server = lmqueue::Server::bind(&queue, addr).expect("bind");
+    println!("{}: listening on {}", dir, server.local_addr().expect("local address"));
+    server.run().expect("serve");
+}
+
+fn process_serve_unix(opts: &lmqueue::QueueOptions,
+                      dir: &str,
+                      path: &str,
+                      mode: u32,
+                      metrics: Option<&str>) {
+    let queue = opts.open(dir).expect("open");
+    if let Some(metrics) = metrics {
+        spawn_metrics_server(dir, &queue, metrics);
+    }
+    let server = lmqueue::UnixServer::bind(&queue, path, mode).expect("bind");
+    println!("{}: listening on unix://{}", dir, server.path().display());
+    server.run().expect("serve");
+}
+
+fn process_serve_http(opts: &lmqueue::QueueOptions, dir: &str, addr: &str) {
+    let queue = opts.open(dir).expect("open");
+    let server = lmqueue::HttpServer::bind(&queue, addr).expect("bind");
+    println!("{}: listening on http://{}", dir, server.local_addr().expect("local address"));
+    server.run().expect("serve");
+}
+
+fn process_serve_redis(opts: &lmqueue::QueueOptions, dir: &str, addr: &str) {
+    let queue = opts.open(dir).expect("open");
+    let server = lmqueue::RespServer::bind(&queue, addr).expect("bind");
+    println!("{}: listening on redis://{}", dir, server.local_addr().expect("local address"));
+    server.run().expect("serve");
+}
+
+fn process_serve_kafka(opts: &lmqueue::QueueOptions, queues: Vec<&str>, addr: &str) {
+    let mut server = lmqueue::KafkaServer::bind(addr).expect("bind");
+    for spec in queues {
+        let (topic, dir) = match spec.find('=') {
+            Some(i) => (spec[..i].to_string(), &spec[i + 1..]),
+            None => {
+                let name = Path::new(spec).file_name().unwrap_or_else(|| {
+                    clap::Error::value_validation_auto(format!("no topic name in {:?}", spec))
+                        .exit()
+                });
+                (name.to_string_lossy().into_owned(), spec)
+            }
+        };
+        let queue = opts.open(dir).expect("open");
+        println!("{}: serving as topic {:?}", dir, topic);
+        server = server.topic(&topic, &queue);
+    }
+    println!("listening on {}", server.local_addr().expect("local address"));
+    server.run().expect("serve");
+}
+
+fn process_serve_mqtt(opts: &lmqueue::QueueOptions, queues: Vec<&str>, addr: &str) {
+    let mut server = lmqueue::MqttServer::bind(addr).expect("bind");
+    for spec in queues {
+        let (filter, dir) = match spec.find('=') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => ("#", spec),
+        };
+        let queue = opts.open(dir).expect("open");
+        println!("{}: storing messages on {:?}", dir, filter);
+        server = server.route(filter, &queue);
+    }
+    println!("listening on {}", server.local_addr().expect("local address"));
+    server.run().expect("serve");
+}
+
+fn process_serve_stomp(opts: &lmqueue::QueueOptions, queues: Vec<&str>, addr: &str) {
+    let mut server = lmqueue::StompServer::bind(addr).expect("bind");
+    for spec in queues {
+        let (destination, dir) = match spec.find('=') {
+            Some(i) => (spec[..i].to_string(), &spec[i + 1..]),
+            None => {
+                let name = Path::new(spec)
+                               .file_name()
+                               .map(|n| n.to_string_lossy().into_owned())
+                               .unwrap_or_else(|| spec.to_string());
+                (format!("/queue/{}", name), spec)
+            }
+        };
+        let queue = opts.open(dir).expect("open");
+        println!("{}: serving as destination {:?}", dir, destination);
+        server = server.destination(&destination, &queue);
+    }
+    println!("listening on {}", server.local_addr().expect("local address"));
+    server.run().expect("serve");
+}
+
+fn process_follow(opts: &lmqueue::QueueOptions, dir: &str, leader: &str) {
+    let queue = opts.open(dir).expect("open");
+    let follower = lmqueue::Follower::new(&queue, leader).expect("follow");
+    println!("{}: following {}", dir, leader);
+    follower.run().expect("replicate");
+    println!("{}: promoted", dir);
+}
+
+fn process_promote(opts: &lmqueue::QueueOptions, dir: &str) {
+    let queue = opts.open(dir).expect("open");
+    if queue.promote().expect("promote") {
+        println!("{}: promoted", dir);
+    } else {
+        println!("{}: not a follower", dir);
+    }
+}
+
+fn display_replication(opts: &lmqueue::QueueOptions, dir: &str) {
+    let queue = opts.open(dir).expect("open");
+    match queue.replication().expect("replication") {
+        Some(status) => {
+            println!("leader\t{}", status.leader);
+            println!("leader-next\t{}", status.leader_next);
+            println!("writer-next\t{}", status.writer_next);
+            println!("lag\t{}", status.lag());
+        }
+        None => println!("{}: not a follower", dir),
+    }
+}
+
+fn spawn_metrics_server(dir: &str, queue: &lmqueue::Queue, addr: &str) {
+    let server = lmqueue::MetricsServer::bind(addr).expect("bind metrics").queue(dir, queue);
+    println!("{}: metrics on http://{}/metrics",
+             dir,
+             server.local_addr().expect("local address"));
+    thread::spawn(move || server.run().expect("serve metrics"));
+}
+
+fn display_stats(opts: &lmqueue::QueueOptions, dir: &str) {
+    let queue = opts.open(dir).expect("open");
+    let stats = queue.stats().expect("stats");
+    let offset = |o: Option<u64>| o.map(|o| o.to_string()).unwrap_or_else(|| "-".to_string());
+    println!("records\t{}", stats.records);
+    println!("payload-bytes\t{}", stats.payload_bytes);
+    println!("first-offset\t{}", offset(stats.first));
+    println!("last-offset\t{}", offset(stats.last));
+    println!("writer-next\t{}", stats.writer_next);
+    println!("map-size\t{}", stats.env.map_size);
+    println!("map-used\t{}", stats.env.used_bytes());
+    println!("map-free\t{}", stats.env.free_bytes());
+    println!("page-size\t{}", stats.env.page_size);
+    println!("last-page\t{}", stats.env.last_page);
+    println!("readers\t{}/{}", stats.env.readers, stats.env.max_readers);
+    println!();
+    println!("db\tentries\tdepth\tbranch\tleaf\toverflow");
+    for db in &stats.databases {
+        println!("{}\t{}\t{}\t{}\t{}\t{}",
+                 db.name,
+                 db.entries,
+                 db.depth,
+                 db.branch_pages,
+                 db.leaf_pages,
+                 db.overflow_pages);
+    }
+}
+
+fn display_metrics(opts: &lmqueue::QueueOptions, dir: &str) {
+    let queue = opts.open(dir).expect("open");
+    let metrics = queue.metrics().expect("metrics");
+    print!("{}", lmqueue::render_metrics(&[(dir, &metrics)]));
+}
+
+fn process_migrate(opts: &lmqueue::QueueOptions, dir: &str) {
+    let found = opts.migrate(dir).expect("migrate");
+    if found == lmqueue::FORMAT_VERSION {
+        println!("{}: already at format version {}", dir, found);
+    } else {
+        println!("{}: migrated from format version {} to {}",
+                 dir,
+                 found,
+                 lmqueue::FORMAT_VERSION);
+    }
+}
+
+fn is_not_found(e: &lmqueue::Error) -> bool {
+    matches!(*e.kind(), lmqueue::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::io::Cursor;
+
+    use super::{CatFormat, Framing, ProduceBatch, cat, produce_from};
+
+    // Produces `input` to a new queue, returning it along with what was
+    // printed. The directory goes with the queue.
+    fn produce(input: &[u8],
+               framing: Framing,
+               batch_size: usize)
+               -> (tempdir::TempDir, lmqueue::Queue, String) {
+        let dir = tempdir::TempDir::new("store").expect("store-dir");
+        let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+        let mut out = Vec::new();
+        {
+            let producer = lmqueue::AnyProducer::Local(queue.producer());
+            let mut batch = ProduceBatch::new(producer, batch_size, &mut out);
+            produce_from(&mut batch, &mut Cursor::new(input), framing).expect("produce");
+            batch.flush().expect("flush");
+        }
+        (dir, queue, String::from_utf8(out).expect("utf-8"))
+    }
+
+    fn messages(queue: &lmqueue::Queue) -> Vec<Vec<u8>> {
+        queue.entries(0, 100).expect("entries").into_iter().map(|e| e.data).collect()
+    }
+
+    #[test]
+    fn produce_splits_at_newlines() {
+        let (_dir, queue, out) = produce(b"a\n\nbc\nd", Framing::Delimited(b'\n'), 2);
+        assert_eq!(out, "1\n2\n3\n4\n");
+        assert_eq!(messages(&queue),
+                   vec![b"a".to_vec(), b"".to_vec(), b"bc".to_vec(), b"d".to_vec()]);
+    }
+
+    #[test]
+    fn produce_splits_by_length() {
+        let (_dir, queue, out) = produce(b"\0\0\0\x02hi\0\0\0\0\0\0\0\x01!",
+                                   Framing::LengthPrefixed,
+                                   10);
+        assert_eq!(out, "1\n2\n3\n");
+        assert_eq!(messages(&queue), vec![b"hi".to_vec(), b"".to_vec(), b"!".to_vec()]);
+    }
+
+    #[test]
+    fn produce_rejects_truncated_lengths() {
+        let dir = tempdir::TempDir::new("store").expect("store-dir");
+        let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+        let producer = lmqueue::AnyProducer::Local(queue.producer());
+        let mut batch = ProduceBatch::new(producer, 10, Vec::new());
+        // Claims 4GiB, which mustn't be allocated up front.
+        let input = b"\xff\xff\xff\xffshort";
+        let err = produce_from(&mut batch, &mut Cursor::new(&input[..]), Framing::LengthPrefixed)
+                      .expect_err("truncated input");
+        assert!(matches!(*err.kind(), lmqueue::ErrorKind::Io(ref e)
+                         if e.kind() == ::std::io::ErrorKind::UnexpectedEof));
+    }
+
+    fn cat_lines(queue: &lmqueue::Queue,
+                 consumer: Option<&str>,
+                 from: u64,
+                 to: Option<u64>,
+                 format: CatFormat)
+                 -> String {
+        let mut out = Vec::new();
+        cat(&queue.clone().into(), &mut out, consumer, from, to, false, format);
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    #[test]
+    fn cat_prints_between_bounds_without_committing() {
+        let (_dir, queue, _) = produce(b"a\nb\nc\nd", Framing::Delimited(b'\n'), 10);
+        assert_eq!(cat_lines(&queue, None, 0, None, CatFormat::Raw), "1\ta\n2\tb\n3\tc\n4\td\n");
+        assert_eq!(cat_lines(&queue, None, 2, Some(3), CatFormat::Hex), "2\t62\n3\t63\n");
+        assert_eq!(cat_lines(&queue, None, 4, None, CatFormat::Json),
+                   "{\"offset\":4,\"data\":\"ZA==\"}\n");
+        assert_eq!(cat_lines(&queue, None, 5, None, CatFormat::Base64), "");
+        assert!(queue.consumers().expect("consumers").is_empty());
+    }
+
+    #[test]
+    fn cat_commits_for_a_named_consumer() {
+        let (_dir, queue, _) = produce(b"a\nb\nc", Framing::Delimited(b'\n'), 10);
+        assert_eq!(cat_lines(&queue, Some("reader"), 0, Some(2), CatFormat::Base64),
+                   "1\tYQ==\n2\tYg==\n");
+        assert_eq!(queue.consumer_offset("reader").expect("offset"), 2);
+        assert_eq!(cat_lines(&queue, Some("reader"), 0, None, CatFormat::Raw), "3\tc\n");
+        assert_eq!(queue.consumer_offset("reader").expect("offset"), 3);
+        assert_eq!(queue.consumers().expect("consumers").len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn produces_and_cats_through_a_unix_socket() {
+        let dir = tempdir::TempDir::new("store").expect("store-dir");
+        let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+        let sock_dir = tempdir::TempDir::new("sock").expect("sock-dir");
+        let sock = sock_dir.path().join("q.sock");
+        let server = lmqueue::UnixServer::bind(&queue, &sock, 0o600).expect("bind");
+        ::std::thread::spawn(move || server.run().expect("run"));
+        let address = format!("unix://{}", sock.display());
+        let remote = lmqueue::QueueOptions::new().open_address(&address).expect("open");
+
+        let mut out = Vec::new();
+        {
+            let mut batch = ProduceBatch::new(remote.producer(), 10, &mut out);
+            produce_from(&mut batch, &mut Cursor::new(b"a\nb"), Framing::Delimited(b'\n'))
+                .expect("produce");
+            batch.flush().expect("flush");
+        }
+        assert_eq!(out, b"1\n2\n");
+        assert_eq!(messages(&queue), vec![b"a".to_vec(), b"b".to_vec()]);
+
+        let mut out = Vec::new();
+        cat(&remote, &mut out, Some("reader"), 0, None, false, CatFormat::Raw);
+        assert_eq!(out, b"1\ta\n2\tb\n");
+        assert_eq!(queue.consumer_offset("reader").expect("offset"), 2);
+    }
+}
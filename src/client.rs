@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use errors::{Error, Result};
+use protocol::{self, Request, Response};
+use Entry;
+
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
+
+/// A connection to a queue served by `Server`, mirroring the `Queue` API.
+///
+/// Cloning a `Client` is cheap, but clones share the one connection, and
+/// so wait for each other's requests; in particular, a consumer blocked in
+/// `poll_timeout` holds up its producers. Connect again for independent
+/// traffic.
+#[derive(Clone)]
+pub struct Client {
+    addr: String,
+    conn: Arc<Mutex<Box<dyn Stream>>>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client").field("addr", &self.addr).finish()
+    }
+}
+
+impl Client {
+    /// Connects to a queue address: either `unix://<path>` for a socket
+    /// served by `UnixServer`, as in `unix:///run/q.sock`, or
+    /// `tcp://<host>:<port>` (or plain `<host>:<port>`) for a `Server`. See
+    /// `AnyQueue` for opening local and served queues alike.
+    pub fn open(address: &str) -> Result<Client> {
+        let mut parts = address.splitn(2, "://");
+        match (parts.next(), parts.next()) {
+            (Some("unix"), Some(path)) => Client::connect_unix(path),
+            (Some("tcp"), Some(addr)) | (Some(addr), None) => Client::connect(addr),
+            _ => {
+                let msg = format!("unsupported queue address {:?}", address);
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into())
+            }
+        }
+    }
+
+    pub fn connect<A: ToSocketAddrs + fmt::Debug>(addr: A) -> Result<Client> {
+        let stream = try!(TcpStream::connect(&addr));
+        try!(stream.set_nodelay(true));
+        Ok(Client {
+            addr: format!("{:?}", addr),
+            conn: Arc::new(Mutex::new(Box::new(stream))),
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Client> {
+        let path = path.as_ref();
+        let stream = try!(UnixStream::connect(path));
+        Ok(Client {
+            addr: format!("unix://{}", path.display()),
+            conn: Arc::new(Mutex::new(Box::new(stream))),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect_unix<P: AsRef<::std::path::Path>>(_path: P) -> Result<Client> {
+        let msg = "unix sockets are not supported on this platform";
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into())
+    }
+
+    pub fn producer(&self) -> RemoteProducer {
+        RemoteProducer { client: self.clone() }
+    }
+
+    pub fn consumer(&self, name: &str) -> Result<RemoteConsumer> {
+        match try!(self.call(&Request::OpenConsumer(name.to_string()))
+                       .map_err(|e| e.for_consumer(name))) {
+            Response::Consumer(id) => {
+                Ok(RemoteConsumer {
+                    client: self.clone(),
+                    name: name.to_string(),
+                    id,
+                })
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// The last offset committed by the named consumer, or `NotFound`.
+    pub fn consumer_offset(&self, name: &str) -> Result<u64> {
+        match try!(self.call(&Request::ConsumerOffset(name.to_string()))
+                       .map_err(|e| e.for_consumer(name))) {
+            Response::Offset(offset) => Ok(offset),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn consumers(&self) -> Result<BTreeMap<String, u64>> {
+        match try!(self.call(&Request::Consumers)) {
+            Response::Consumers(consumers) => Ok(consumers),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn discard_upto(&self, limit: u64) -> Result<()> {
+        match try!(self.call(&Request::Trim(limit))) {
+            Response::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn call(&self, req: &Request) -> Result<Response> {
+        let body = try!(req.encode());
+        let mut conn = self.conn.lock().expect("connection lock poisoned");
+        try!(protocol::write_frame(&mut *conn, &body));
+        let frame = match try!(protocol::read_frame(&mut *conn)) {
+            Some(frame) => frame,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server hung up").into())
+            }
+        };
+        match try!(Response::decode(&frame)) {
+            Response::Error(e) => Err(e),
+            resp => Ok(resp),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RemoteProducer {
+    client: Client,
+}
+
+impl RemoteProducer {
+    /// Appends `msg` to the queue, returning the offset it was given.
+    pub fn produce(&mut self, msg: &[u8]) -> Result<u64> {
+        match try!(self.client.call(&Request::Produce(msg.to_vec()))) {
+            Response::Offset(offset) => Ok(offset),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Appends all of `msgs` in a single transaction, returning the offsets
+    /// they were given.
+    pub fn produce_batch<M: AsRef<[u8]>>(&mut self, msgs: &[M]) -> Result<Vec<u64>> {
+        let msgs = msgs.iter().map(|m| m.as_ref().to_vec()).collect();
+        match try!(self.client.call(&Request::ProduceBatch(msgs))) {
+            Response::Offsets(offsets) => Ok(offsets),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+/// A consumer held open by the server. Like a local `Consumer`, it reads
+/// on from its committed offset, and only commits when asked to.
+#[derive(Debug)]
+pub struct RemoteConsumer {
+    client: Client,
+    name: String,
+    id: u32,
+}
+
+impl RemoteConsumer {
+    pub fn poll(&mut self) -> Result<Option<Entry>> {
+        self.poll_timeout(Duration::from_millis(0))
+    }
+
+    /// Like `poll`, but if there is nothing to read, the server waits up to
+    /// `timeout` for something to be produced.
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Result<Option<Entry>> {
+        let timeout_ms = timeout.as_secs()
+                                .saturating_mul(1000)
+                                .saturating_add(timeout.subsec_millis() as u64);
+        let req = Request::Poll {
+            consumer: self.id,
+            timeout_ms,
+        };
+        match try!(self.client.call(&req).map_err(|e| e.for_consumer(&self.name))) {
+            Response::Entry(entry) => Ok(entry),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn commit_upto(&self, entry: &Entry) -> Result<()> {
+        let req = Request::Commit {
+            consumer: self.id,
+            offset: entry.offset,
+        };
+        match try!(self.client.call(&req).map_err(|e| e.for_consumer(&self.name))) {
+            Response::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn unexpected(resp: Response) -> Error {
+    let msg = format!("unexpected response: {:?}", resp);
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
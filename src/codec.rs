@@ -0,0 +1,148 @@
+use std::error::Error as StdError;
+use std::marker::PhantomData;
+
+use errors::{ErrorKind, Result};
+use {Consumer, Producer};
+
+/// Converts values to and from the bytes stored in the queue.
+pub trait Codec<T> {
+    type Error: StdError + Send + Sync + 'static;
+
+    fn encode(&self, value: &T) -> ::std::result::Result<Vec<u8>, Self::Error>;
+    fn decode(&self, bytes: &[u8]) -> ::std::result::Result<T, Self::Error>;
+}
+
+/// A decoded entry.
+#[derive(Debug,Clone,Eq,PartialEq)]
+pub struct TypedEntry<T> {
+    pub offset: u64,
+    pub value: T,
+}
+
+/// Wraps a `Producer`, encoding each value with `C`.
+#[derive(Debug)]
+pub struct TypedProducer<T, C> {
+    producer: Producer,
+    codec: C,
+    _values: PhantomData<fn(&T)>,
+}
+
+impl<T, C: Codec<T>> TypedProducer<T, C> {
+    pub fn new(producer: Producer, codec: C) -> Self {
+        TypedProducer {
+            producer,
+            codec,
+            _values: PhantomData,
+        }
+    }
+
+    pub fn produce(&mut self, value: &T) -> Result<u64> {
+        let bytes = try!(self.codec.encode(value).map_err(|e| ErrorKind::Encode(Box::new(e))));
+        self.producer.produce(&bytes)
+    }
+}
+
+/// Wraps a `Consumer`, decoding each entry with `C`.
+#[derive(Debug)]
+pub struct TypedConsumer<T, C> {
+    consumer: Consumer,
+    codec: C,
+    _values: PhantomData<fn() -> T>,
+}
+
+impl<T, C: Codec<T>> TypedConsumer<T, C> {
+    pub fn new(consumer: Consumer, codec: C) -> Self {
+        TypedConsumer {
+            consumer,
+            codec,
+            _values: PhantomData,
+        }
+    }
+
+    /// Like `Consumer::poll`, but decodes the entry. An entry that fails to
+    /// decode is reported as `Decode` with its offset; the consumer has still
+    /// moved past it, so polling again carries on with the next one.
+    pub fn poll(&mut self) -> Result<Option<TypedEntry<T>>> {
+        let entry = match try!(self.consumer.poll()) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match self.codec.decode(&entry.data) {
+            Ok(value) => {
+                Ok(Some(TypedEntry {
+                    offset: entry.offset,
+                    value,
+                }))
+            }
+            Err(e) => {
+                let err = ErrorKind::Decode {
+                    offset: entry.offset,
+                    cause: Box::new(e),
+                };
+                Err(self.consumer.in_context(err.into()))
+            }
+        }
+    }
+
+    pub fn commit_upto(&self, entry: &TypedEntry<T>) -> Result<()> {
+        self.consumer.commit_offset(entry.offset)
+    }
+
+    pub fn consumer(&self) -> &Consumer {
+        &self.consumer
+    }
+}
+
+#[cfg(feature = "json")]
+pub use self::json::JsonCodec;
+
+#[cfg(feature = "json")]
+mod json {
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use serde_json;
+
+    use super::Codec;
+
+    /// Stores values as JSON.
+    #[derive(Debug,Clone,Copy,Default)]
+    pub struct JsonCodec;
+
+    impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+        type Error = serde_json::Error;
+
+        fn encode(&self, value: &T) -> Result<Vec<u8>, Self::Error> {
+            serde_json::to_vec(value)
+        }
+        fn decode(&self, bytes: &[u8]) -> Result<T, Self::Error> {
+            serde_json::from_slice(bytes)
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub use self::bincode_codec::BincodeCodec;
+
+#[cfg(feature = "bincode")]
+mod bincode_codec {
+    use bincode;
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+
+    use super::Codec;
+
+    /// Stores values in bincode's compact binary format.
+    #[derive(Debug,Clone,Copy,Default)]
+    pub struct BincodeCodec;
+
+    impl<T: Serialize + DeserializeOwned> Codec<T> for BincodeCodec {
+        type Error = bincode::Error;
+
+        fn encode(&self, value: &T) -> Result<Vec<u8>, Self::Error> {
+            bincode::serialize(value)
+        }
+        fn decode(&self, bytes: &[u8]) -> Result<T, Self::Error> {
+            bincode::deserialize(bytes)
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! A portable stream of a queue's contents, independent of LMDB's file
+//! layout, page size and architecture.
+//!
+//! All integers are big-endian. A dump starts with the 8 bytes `lmqdump\n`
+//! and the queue's format version as a `u64`, followed by any number of
+//! items, each introduced by a one-byte tag:
+//!
+//! * `W`, `writer-next: u64`: the last offset handed out to a producer.
+//! * `C`, `name length: u32`, `name: [u8]`, `offset: u64`: a consumer's
+//!   committed offset. The name is UTF-8.
+//! * `R`, `offset: u64`, `length: u32`, `record: [u8]`: a stored record,
+//!   exactly as held in the `data` database; from format version 2 on, that
+//!   is a CRC32C followed by the message. Records appear in offset order.
+//! * `E`: the end of the dump. Anything after it is ignored, and a dump
+//!   without it is treated as truncated.
+//!
+//! `Queue::dump_to` writes `W`, then every `C`, then every `R`. Running
+//! totals aren't dumped; they are counted again as records are loaded, so
+//! dumps from version 2 load unchanged.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::{Error, ErrorKind, Result};
+use format::{self, FORMAT_VERSION};
+use {Queue, WRITER_NEXT, decode_data_key, decode_offset, envelope, mdb_maybe, put_record,
+     read_offset, totals, write_offset};
+
+const MAGIC: &[u8; 8] = b"lmqdump\n";
+// The oldest format whose dumps hold the same items as the current one.
+const OLDEST_LOADABLE: u64 = 2;
+const WRITER_NEXT_TAG: u8 = b'W';
+const CONSUMER_TAG: u8 = b'C';
+const RECORD_TAG: u8 = b'R';
+const END_TAG: u8 = b'E';
+// Records loaded per write transaction.
+const LOAD_BATCH: usize = 1024;
+
+impl Queue {
+    /// Writes the queue's contents to `out` in the dump format. The dump is
+    /// taken from a single read transaction, so is consistent even while
+    /// the queue is in use. Returns the number of records written.
+    pub fn dump_to<W: Write>(&self, mut out: W) -> Result<u64> {
+        let meta = self.producers_db();
+        let consumers = self.consumers_db();
+        let data = self.data_db();
+        self.read(|txn| {
+            let access = txn.access();
+            try!(out.write_all(MAGIC));
+            try!(out.write_u64::<BigEndian>(FORMAT_VERSION));
+
+            try!(out.write_u8(WRITER_NEXT_TAG));
+            try!(out.write_u64::<BigEndian>(try!(read_offset(meta, &access, WRITER_NEXT))));
+
+            let mut cursor = try!(txn.cursor(consumers));
+            let mut curr = try!(mdb_maybe(cursor.first::<str, [u8]>(&access)));
+            while let Some((name, val)) = curr {
+                try!(out.write_u8(CONSUMER_TAG));
+                try!(out.write_u32::<BigEndian>(name.len() as u32));
+                try!(out.write_all(name.as_bytes()));
+                try!(out.write_u64::<BigEndian>(try!(decode_offset(val, name))));
+                curr = try!(mdb_maybe(cursor.next::<str, [u8]>(&access)));
+            }
+
+            let mut count = 0u64;
+            let mut cursor = try!(txn.cursor(data));
+            let mut curr = try!(mdb_maybe(cursor.first::<[u8], [u8]>(&access)));
+            let mut last = 0;
+            while let Some((key, record)) = curr {
+                last = try!(decode_data_key(key, last + 1));
+                try!(out.write_u8(RECORD_TAG));
+                try!(out.write_u64::<BigEndian>(last));
+                try!(out.write_u32::<BigEndian>(record.len() as u32));
+                try!(out.write_all(record));
+                count += 1;
+                curr = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&access)));
+            }
+
+            try!(out.write_u8(END_TAG));
+            try!(out.flush());
+            debug!("Dumped {:?} records", count);
+            Ok(count)
+        })
+    }
+
+    /// Loads a dump written by `dump_to` into this queue, which must be
+    /// empty, keeping every offset as it was. Records are checked against
+    /// their checksums as they are read. Large dumps are loaded over
+    /// several transactions, so a failed load can leave a partial queue
+    /// behind. Returns the number of records loaded.
+    pub fn load_from<R: Read>(&self, input: R) -> Result<u64> {
+        self.load(input).map_err(|e| e.in_queue(self.path()))
+    }
+
+    fn load<R: Read>(&self, mut input: R) -> Result<u64> {
+        let mut magic = [0u8; 8];
+        try!(input.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(invalid_dump("not an lmqueue dump"));
+        }
+        let version = try!(input.read_u64::<BigEndian>());
+        if version > FORMAT_VERSION {
+            return Err(ErrorKind::UnsupportedFormat {
+                           found: version,
+                           supported: FORMAT_VERSION,
+                       }
+                       .into());
+        } else if version < OLDEST_LOADABLE {
+            return Err(ErrorKind::OutdatedFormat {
+                           found: version,
+                           current: FORMAT_VERSION,
+                       }
+                       .into());
+        }
+
+        let empty = try!(self.read(|txn| format::is_empty(self, txn, &txn.access())));
+        if !empty {
+            let msg = format!("{:?} already holds a queue", self.path());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+
+        let mut writer_next = None;
+        let mut consumers = Vec::new();
+        let mut batch = Vec::new();
+        let mut last = None;
+        let mut count = 0u64;
+        loop {
+            match try!(input.read_u8()) {
+                WRITER_NEXT_TAG => writer_next = Some(try!(input.read_u64::<BigEndian>())),
+                CONSUMER_TAG => {
+                    let name = try!(read_bytes(&mut input));
+                    let name = try!(String::from_utf8(name)
+                                        .map_err(|_| invalid_dump("consumer name is not UTF-8")));
+                    consumers.push((name, try!(input.read_u64::<BigEndian>())));
+                }
+                RECORD_TAG => {
+                    let offset = try!(input.read_u64::<BigEndian>());
+                    if last.map(|last| offset <= last).unwrap_or(offset == 0) {
+                        return Err(invalid_dump("records are out of order"));
+                    }
+                    let record = try!(read_bytes(&mut input));
+                    try!(envelope::unseal(offset, &record));
+                    last = Some(offset);
+                    batch.push((offset, record));
+                    if batch.len() >= LOAD_BATCH {
+                        try!(self.load_records(&batch));
+                        count += batch.len() as u64;
+                        batch.clear();
+                    }
+                }
+                END_TAG => break,
+                _ => return Err(invalid_dump("unknown item")),
+            }
+        }
+        try!(self.load_records(&batch));
+        count += batch.len() as u64;
+
+        let writer_next = try!(writer_next.ok_or_else(|| invalid_dump("no writer-next")));
+        if last.map(|last| writer_next < last).unwrap_or(false) {
+            return Err(invalid_dump("writer-next is behind the last record"));
+        }
+        let meta = self.producers_db();
+        let consumers_db = self.consumers_db();
+        try!(self.write(|txn| {
+            let mut access = txn.access();
+            try!(write_offset(meta, &mut access, WRITER_NEXT, writer_next));
+            for &(ref name, offset) in &consumers {
+                try!(write_offset(consumers_db, &mut access, name, offset));
+            }
+            Ok(())
+        }));
+        debug!("Loaded {:?} records", count);
+        self.inner.notifier.notify();
+        Ok(count)
+    }
+
+    fn load_records(&self, records: &[(u64, Vec<u8>)]) -> Result<()> {
+        let data = self.data_db();
+        let meta = self.producers_db();
+        self.write(|txn| {
+            let mut access = txn.access();
+            let mut bytes = 0;
+            for &(offset, ref record) in records {
+                try!(put_record(data, &mut access, offset, record));
+                bytes += envelope::payload_len(record) as u64;
+            }
+            totals::add(meta, &mut access, records.len() as u64, bytes)
+        })
+    }
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let len = try!(input.read_u32::<BigEndian>()) as usize;
+    let mut buf = vec![0; len];
+    try!(input.read_exact(&mut buf));
+    Ok(buf)
+}
+
+fn invalid_dump(msg: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
@@ -0,0 +1,55 @@
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+
+use lmdb_zero::open;
+
+use errors::Result;
+use QueueInner;
+
+/// How hard a queue works to get each commit onto disk.
+///
+/// Every level survives the producing process crashing, since committed
+/// pages are already in the OS's hands. They differ in what an OS crash or
+/// power loss can take with it.
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub enum Durability {
+    /// Flush data and metadata on every commit. Once `produce` or
+    /// `commit_upto` returns, the change survives an OS crash.
+    Full,
+    /// Flush data, but not the meta page, on every commit. An OS crash may
+    /// roll back the most recent commit, but the queue stays consistent.
+    NoMetaSync,
+    /// Leave flushing to the OS, a periodic sync or `Queue::sync`. An OS crash
+    /// may lose any commits since the last flush.
+    Async,
+}
+
+pub fn durability_of(flags: open::Flags) -> Durability {
+    if flags.contains(open::NOSYNC) {
+        Durability::Async
+    } else if flags.contains(open::NOMETASYNC) {
+        Durability::NoMetaSync
+    } else {
+        Durability::Full
+    }
+}
+
+/// Flushes the environment every `interval` until the queue is dropped.
+pub fn spawn_periodic_sync(queue: Weak<QueueInner>, interval: Duration) -> Result<()> {
+    try!(thread::Builder::new().name("lmqueue-sync".to_string()).spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let inner = match queue.upgrade() {
+                Some(inner) => inner,
+                None => break,
+            };
+            trace!("Periodic sync");
+            if let Err(e) = inner.env.sync(true) {
+                warn!("Periodic sync failed: {:?}", e);
+            }
+        }
+        debug!("Queue dropped; stopping periodic sync");
+    }));
+    Ok(())
+}
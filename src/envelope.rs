@@ -0,0 +1,66 @@
+//! Each record in `data` is stored as a 4-byte big-endian CRC32C of the
+//! message, followed by the message itself.
+
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crc32c::crc32c;
+use lmdb_zero::{WriteTransaction, put};
+
+use errors::{ErrorKind, Result};
+use {Queue, mdb_maybe};
+
+const CHECKSUM_LEN: usize = 4;
+
+pub fn seal(msg: &[u8]) -> Result<Vec<u8>> {
+    let mut record = Vec::with_capacity(CHECKSUM_LEN + msg.len());
+    try!(record.write_u32::<BigEndian>(crc32c(msg)));
+    record.extend_from_slice(msg);
+    Ok(record)
+}
+
+/// The length of the message held in `record`, without checking it.
+pub fn payload_len(record: &[u8]) -> usize {
+    record.len().saturating_sub(CHECKSUM_LEN)
+}
+
+/// Returns the message held in the record at `offset`, once its checksum
+/// has been verified.
+pub fn unseal(offset: u64, record: &[u8]) -> Result<&[u8]> {
+    if record.len() < CHECKSUM_LEN {
+        return Err(ErrorKind::CorruptRecord { offset }.into());
+    }
+    let (header, msg) = record.split_at(CHECKSUM_LEN);
+    let stored = try!(Cursor::new(header).read_u32::<BigEndian>());
+    let computed = crc32c(msg);
+    if stored != computed {
+        return Err(ErrorKind::ChecksumMismatch {
+                       offset,
+                       stored,
+                       computed,
+                   }
+                   .into());
+    }
+    Ok(msg)
+}
+
+/// Migrates from format version 1, where records were bare messages.
+pub fn add_checksums(queue: &Queue, txn: &WriteTransaction) -> Result<()> {
+    let mut cursor = try!(txn.cursor(queue.data_db()));
+    let mut access = txn.access();
+    let mut count = 0u64;
+    let mut curr = match try!(mdb_maybe(cursor.first::<[u8], [u8]>(&access))) {
+        Some((k, v)) => Some((k.to_vec(), try!(seal(v)))),
+        None => None,
+    };
+    while let Some((key, record)) = curr {
+        try!(cursor.overwrite(&mut access, &key[..], &record[..], put::Flags::empty()));
+        count += 1;
+        curr = match try!(mdb_maybe(cursor.next::<[u8], [u8]>(&access))) {
+            Some((k, v)) => Some((k.to_vec(), try!(seal(v)))),
+            None => None,
+        };
+    }
+    debug!("Added checksums to {:?} records", count);
+    Ok(())
+}
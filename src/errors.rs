@@ -1,9 +1,198 @@
 use lmdb_zero;
-use std;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
 
-error_chain!(
-    foreign_links {
-        lmdb_zero::error::Error, Mdb;
-        std::io::Error, Io;
+pub type Result<T> = result::Result<T, Error>;
+
+/// What went wrong, along with which queue and consumer it happened to.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    path: Option<PathBuf>,
+    consumer: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// The map has reached `QueueOptions::max_map_size`, or could not grow.
+    MapFull,
+    /// The record at, or just after, `offset` could not be decoded.
+    CorruptRecord { offset: u64 },
+    /// A stored position (a consumer offset, or `writer-next`) is malformed.
+    CorruptOffset { key: String },
+    /// The record at `offset` does not match its checksum.
+    ChecksumMismatch {
+        offset: u64,
+        stored: u32,
+        computed: u32,
+    },
+    /// A record already exists at `offset`.
+    OffsetConflict { offset: u64 },
+    /// The consumer's next message was trimmed before it was read. The
+    /// consumer has been moved on, so the next poll returns `available`.
+    TrimmedGap { expected: u64, available: u64 },
+    /// The named consumer has no committed offset.
+    NotFound,
+    /// The queue was written by a newer lmqueue, in a layout we don't know.
+    UnsupportedFormat { found: u64, supported: u64 },
+    /// The queue uses an older layout, and must be migrated before use.
+    OutdatedFormat { found: u64, current: u64 },
+    /// The queue path cannot be passed to LMDB.
+    InvalidPath(PathBuf),
+    /// A `Codec` could not encode a value.
+    Encode(Box<dyn StdError + Send + Sync>),
+    /// A `Codec` could not decode the entry at `offset`.
+    Decode {
+        offset: u64,
+        cause: Box<dyn StdError + Send + Sync>,
+    },
+    /// The queue follows `leader`, so only takes records replicated from it.
+    Following { leader: String },
+    /// No follower acknowledged `offset` in time. The records produced are
+    /// stored on the leader all the same.
+    ReplicationTimeout { offset: u64 },
+    /// A server reported a failure that has no more specific kind.
+    Remote(String),
+    Io(io::Error),
+    Mdb(lmdb_zero::Error),
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The queue the error occurred on, if known.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The consumer the error occurred for, if any.
+    pub fn consumer(&self) -> Option<&str> {
+        self.consumer.as_deref()
+    }
+
+    pub(crate) fn in_queue(mut self, path: &Path) -> Self {
+        if self.path.is_none() {
+            self.path = Some(path.to_owned());
+        }
+        self
+    }
+
+    pub(crate) fn for_consumer(mut self, name: &str) -> Self {
+        if self.consumer.is_none() {
+            self.consumer = Some(name.to_string());
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            path: None,
+            consumer: None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        ErrorKind::Io(err).into()
+    }
+}
+
+impl From<lmdb_zero::Error> for Error {
+    fn from(err: lmdb_zero::Error) -> Self {
+        match err {
+            lmdb_zero::Error::Code(lmdb_zero::error::MAP_FULL) => ErrorKind::MapFull.into(),
+            err => ErrorKind::Mdb(err).into(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::MapFull => write!(f, "queue map is full"),
+            ErrorKind::CorruptRecord { offset } => write!(f, "corrupt record at offset {}", offset),
+            ErrorKind::CorruptOffset { ref key } => write!(f, "corrupt stored offset for {:?}", key),
+            ErrorKind::ChecksumMismatch { offset, stored, computed } => {
+                write!(f,
+                       "record at offset {} has checksum {:08x}, expected {:08x}",
+                       offset,
+                       computed,
+                       stored)
+            }
+            ErrorKind::OffsetConflict { offset } => {
+                write!(f, "a record already exists at offset {}", offset)
+            }
+            ErrorKind::TrimmedGap { expected, available } => {
+                write!(f,
+                       "offset {} was trimmed; next available is {}",
+                       expected,
+                       available)
+            }
+            ErrorKind::NotFound => write!(f, "consumer not found"),
+            ErrorKind::UnsupportedFormat { found, supported } => {
+                write!(f,
+                       "queue format version {} is newer than the supported version {}",
+                       found,
+                       supported)
+            }
+            ErrorKind::OutdatedFormat { found, current } => {
+                write!(f,
+                       "queue format version {} must be migrated to version {}; \
+                        run `lmqueue migrate`",
+                       found,
+                       current)
+            }
+            ErrorKind::InvalidPath(ref p) => write!(f, "queue path is not valid UTF-8: {:?}", p),
+            ErrorKind::Encode(ref e) => write!(f, "could not encode value: {}", e),
+            ErrorKind::Decode { offset, ref cause } => {
+                write!(f, "could not decode entry at offset {}: {}", offset, cause)
+            }
+            ErrorKind::Following { ref leader } => {
+                write!(f, "queue is a follower of {}; promote it to produce", leader)
+            }
+            ErrorKind::ReplicationTimeout { offset } => {
+                write!(f, "no follower acknowledged offset {} in time", offset)
+            }
+            ErrorKind::Remote(ref msg) => write!(f, "server error: {}", msg),
+            ErrorKind::Io(ref e) => write!(f, "I/O error: {}", e),
+            ErrorKind::Mdb(ref e) => write!(f, "LMDB error: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.kind));
+        if let Some(ref path) = self.path {
+            try!(write!(f, " (queue {:?}", path));
+            if let Some(ref name) = self.consumer {
+                try!(write!(f, ", consumer {:?}", name));
+            }
+            try!(write!(f, ")"));
+        } else if let Some(ref name) = self.consumer {
+            try!(write!(f, " (consumer {:?})", name));
+        }
+        Ok(())
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self.kind {
+            ErrorKind::Io(ref e) => Some(e),
+            ErrorKind::Mdb(ref e) => Some(e),
+            ErrorKind::Encode(ref e) => Some(&**e),
+            ErrorKind::Decode { ref cause, .. } => Some(&**cause),
+            _ => None,
+        }
     }
-);
+}
@@ -0,0 +1,131 @@
+//! Versioning of the on-disk layout.
+//!
+//! Version 1 is the layout lmqueue has always used: three named databases,
+//! `prod` (producer metadata, including `writer-next`), `cons` (committed
+//! consumer offsets) and `data` (message bodies), with offsets stored as
+//! 8-byte big-endian integers, both as `data` keys and as metadata values.
+//!
+//! Version 2 prefixes each record in `data` with a CRC32C checksum of the
+//! message; see `envelope`.
+//!
+//! Version 3 keeps running totals of the records held and their payload
+//! bytes in `prod`; see `totals`.
+//!
+//! The version itself is kept in `prod` under `format-version`, in the same
+//! encoding. Queues created before it was recorded are version 1, and get
+//! stamped as such when opened; new queues start at the current version.
+
+use lmdb_zero::{ConstAccessor, ConstTransaction, Database, WriteTransaction, put};
+
+use errors::{ErrorKind, Result};
+use {Queue, WRITER_NEXT, decode_offset, encode_key, envelope, mdb_maybe, totals};
+
+/// The layout version written by this version of lmqueue.
+pub const FORMAT_VERSION: u64 = 3;
+
+const FORMAT_VERSION_KEY: &str = "format-version";
+// Queues created before the version was recorded.
+const UNVERSIONED: u64 = 1;
+
+// Rewrites whatever changed in the layout, within the transaction that also
+// bumps the recorded version.
+type Migration = fn(&Queue, &WriteTransaction) -> Result<()>;
+
+// `MIGRATIONS[n]` upgrades a queue from version `n + 1` to `n + 2`.
+static MIGRATIONS: &[(&str, Migration)] =
+    &[("add a checksum to each record", envelope::add_checksums),
+      ("count records and payload bytes", totals::count_records)];
+
+fn read_version(meta: &Database, txn: &ConstAccessor) -> Result<Option<u64>> {
+    match try!(mdb_maybe(txn.get::<str, [u8]>(meta, FORMAT_VERSION_KEY))) {
+        Some(val) => Ok(Some(try!(decode_offset(val, FORMAT_VERSION_KEY)))),
+        None => Ok(None),
+    }
+}
+
+/// Whether nothing has ever been produced to, or consumed from, the queue.
+pub fn is_empty(queue: &Queue, txn: &ConstTransaction, access: &ConstAccessor) -> Result<bool> {
+    if try!(mdb_maybe(access.get::<str, [u8]>(queue.producers_db(), WRITER_NEXT))).is_some() {
+        return Ok(false);
+    }
+    for db in &[queue.data_db(), queue.consumers_db()] {
+        let mut cursor = try!(txn.cursor(*db));
+        if try!(mdb_maybe(cursor.first::<[u8], [u8]>(access))).is_some() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The version recorded in the queue.
+pub fn stored_version(queue: &Queue) -> Result<u64> {
+    let meta = queue.producers_db();
+    queue.read(|txn| {
+        let access = txn.access();
+        read_version(meta, &access).map(|v| v.unwrap_or(UNVERSIONED))
+    })
+}
+
+/// Makes sure we understand the queue's layout, returning the version it
+/// was at when opened. Older queues are migrated if `migrate` is set, and
+/// otherwise rejected.
+pub fn check(queue: &Queue, migrate: bool) -> Result<u64> {
+    let meta = queue.producers_db();
+    let found = match try!(queue.read(|txn| read_version(meta, &txn.access()))) {
+        Some(version) => version,
+        None => {
+            try!(queue.write(|txn| {
+                let mut access = txn.access();
+                // Someone else may have got here first.
+                match try!(read_version(meta, &access)) {
+                    Some(version) => Ok(version),
+                    None => {
+                        // A brand new queue has nothing to migrate.
+                        let version = if try!(is_empty(queue, txn, &access)) {
+                            FORMAT_VERSION
+                        } else {
+                            UNVERSIONED
+                        };
+                        let encoded = try!(encode_key(version));
+                        try!(access.put(meta, FORMAT_VERSION_KEY, &encoded, put::Flags::empty()));
+                        debug!("Stamped format version {:?}", version);
+                        Ok(version)
+                    }
+                }
+            }))
+        }
+    };
+
+    if found > FORMAT_VERSION {
+        return Err(ErrorKind::UnsupportedFormat {
+                       found,
+                       supported: FORMAT_VERSION,
+                   }
+                   .into());
+    }
+    if found < FORMAT_VERSION && !migrate {
+        return Err(ErrorKind::OutdatedFormat {
+                       found,
+                       current: FORMAT_VERSION,
+                   }
+                   .into());
+    }
+
+    for from in found..FORMAT_VERSION {
+        let (description, apply) = MIGRATIONS[(from - 1) as usize];
+        info!("Migrating from format version {:?}: {}", from, description);
+        try!(queue.write(|txn| {
+            // Each step commits separately, so another process may have
+            // already taken it.
+            if try!(read_version(meta, &txn.access())) != Some(from) {
+                return Ok(());
+            }
+            try!(apply(queue, txn));
+            let encoded = try!(encode_key(from + 1));
+            try!(txn.access().put(meta, FORMAT_VERSION_KEY, &encoded, put::Flags::empty()));
+            Ok(())
+        }));
+    }
+
+    Ok(found)
+}
@@ -0,0 +1,456 @@
+//! A small HTTP/1.1 front-end, for tooling that would rather not speak the
+//! binary protocol. Each connection carries a single request.
+//!
+//! Messages are sent as raw request bodies, and returned in JSON as
+//! `{"offset": N, "data": "<base64>"}`. Failures are reported as
+//! `{"error": "<description>"}` with a suitable status.
+//!
+//! * `POST /messages` produces the request body, answering `{"offset": N}`.
+//! * `GET /messages?from=N&limit=M` reads up to `M` (default 100) entries,
+//!   starting at offset `N`, as `{"entries": [...]}`.
+//! * `GET /consumers/{name}/messages?limit=M&timeout=MS` reads the entries
+//!   after the consumer's committed offset, waiting up to `MS` milliseconds
+//!   (default 0, at most a minute) for one to arrive.
+//! * `GET /consumers/{name}/events` tails the queue after the consumer's
+//!   committed offset, or after the `Last-Event-ID` header, as server-sent
+//!   events. Each event's id is the entry's offset, and its data the
+//!   base64-encoded message.
+//! * `POST /consumers/{name}/commit?offset=N` commits up to offset `N`.
+//! * `GET /consumers` lists every consumer's committed offset, and its lag
+//!   behind the last offset produced; `GET /consumers/{name}` shows one.
+//! * `GET /stats` shows the number of entries, the first offset present, the
+//!   last offset produced and the number of consumers.
+
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use base64;
+
+use errors::{Error, ErrorKind, Result};
+use {Entry, Queue};
+
+// Requests with larger heads are refused.
+const MAX_HEAD: u64 = 64 << 10;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 10000;
+// Long polls wait no longer than this, however long they ask for.
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+// An event stream with nothing to say sends a comment this often, to find
+// out if the client has gone.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Serves a queue over HTTP; see the module documentation for the API.
+/// Each connection is handled on its own thread.
+#[derive(Debug)]
+pub struct HttpServer {
+    queue: Queue,
+    listener: TcpListener,
+}
+
+impl HttpServer {
+    pub fn bind<A: ToSocketAddrs>(queue: &Queue, addr: A) -> Result<HttpServer> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(HttpServer {
+            queue: queue.clone(),
+            listener,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            debug!("Accepted HTTP connection from {:?}", peer);
+            let queue = self.queue.clone();
+            try!(thread::Builder::new()
+                     .name("lmqueue-http".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&queue, stream) {
+                             warn!("HTTP connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+struct Request {
+    method: String,
+    path: Vec<String>,
+    query: Vec<(String, String)>,
+    last_event_id: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn param(&self, key: &str) -> Option<&str> {
+        self.query.iter().find(|kv| kv.0 == key).map(|kv| &kv.1[..])
+    }
+}
+
+enum Reply {
+    Json(u16, String),
+    NoContent,
+    Events(u64),
+}
+
+fn serve(queue: &Queue, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+    let mut out = stream;
+    let reply = match try!(read_request(&mut reader)) {
+        Ok(req) => {
+            debug!("{} /{}", req.method, req.path.join("/"));
+            route(queue, &req)
+        }
+        Err(reply) => reply,
+    };
+    match reply {
+        Reply::Json(status, body) => {
+            try!(write!(out,
+                        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: \
+                         {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        reason(status),
+                        body.len()));
+            try!(out.write_all(body.as_bytes()));
+        }
+        Reply::NoContent => {
+            try!(out.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"))
+        }
+        Reply::Events(after) => try!(stream_events(queue, &mut out, after)),
+    }
+    try!(out.flush());
+    Ok(())
+}
+
+// The outer error is for I/O failures; the inner one is a reply refusing
+// a malformed request.
+fn read_request<R: BufRead>(reader: &mut R)
+                            -> Result<::std::result::Result<Request, Reply>> {
+    let mut head = reader.take(MAX_HEAD);
+    let mut line = String::new();
+    try!(head.read_line(&mut line));
+    let (method, target) = {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(_)) => (method.to_string(), target.to_string()),
+            _ => return Ok(Err(bad_request("malformed request line"))),
+        }
+    };
+
+    let mut content_length = 0;
+    let mut last_event_id = None;
+    loop {
+        line.clear();
+        if try!(head.read_line(&mut line)) == 0 {
+            return Ok(Err(bad_request("request head too long or incomplete")));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = match header.find(':') {
+            Some(i) => (header[..i].trim().to_ascii_lowercase(), header[i + 1..].trim()),
+            None => return Ok(Err(bad_request("malformed header"))),
+        };
+        match &name[..] {
+            "content-length" => {
+                content_length = match value.parse::<u64>() {
+                    Ok(len) => len,
+                    Err(_) => return Ok(Err(bad_request("bad Content-Length"))),
+                }
+            }
+            "transfer-encoding" => {
+                return Ok(Err(json_error(411, "chunked requests are not supported")))
+            }
+            "last-event-id" => last_event_id = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    let reader = head.into_inner();
+    let mut body = Vec::new();
+    try!(reader.take(content_length).read_to_end(&mut body));
+    if body.len() as u64 != content_length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated body").into());
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (&target[..], ""),
+    };
+    let path = path.split('/')
+                   .filter(|s| !s.is_empty())
+                   .map(|s| percent_decode(s, false))
+                   .collect::<Option<Vec<_>>>();
+    let query = query.split('&')
+                     .filter(|s| !s.is_empty())
+                     .map(|pair| {
+                         let mut kv = pair.splitn(2, '=');
+                         let k = kv.next().and_then(|k| percent_decode(k, true));
+                         let v = percent_decode(kv.next().unwrap_or(""), true);
+                         k.and_then(|k| v.map(|v| (k, v)))
+                     })
+                     .collect::<Option<Vec<_>>>();
+    match (path, query) {
+        (Some(path), Some(query)) => {
+            Ok(Ok(Request {
+                method,
+                path,
+                query,
+                last_event_id,
+                body,
+            }))
+        }
+        _ => Ok(Err(bad_request("malformed percent-encoding"))),
+    }
+}
+
+fn route(queue: &Queue, req: &Request) -> Reply {
+    let path = req.path.iter().map(|s| &s[..]).collect::<Vec<_>>();
+    let res = match (&req.method[..], &path[..]) {
+        ("POST", ["messages"]) => produce(queue, req),
+        ("GET", ["messages"]) => read_range(queue, req),
+        ("GET", ["consumers"]) => list_consumers(queue, None),
+        ("GET", ["consumers", name]) => list_consumers(queue, Some(name)),
+        ("GET", ["consumers", name, "messages"]) => consumer_messages(queue, req, name),
+        ("GET", ["consumers", name, "events"]) => consumer_events(queue, req, name),
+        ("POST", ["consumers", name, "commit"]) => commit(queue, req, name),
+        ("GET", ["stats"]) => stats(queue),
+        (_, ["messages"]) |
+        (_, ["consumers"]) |
+        (_, ["consumers", _]) |
+        (_, ["consumers", _, "messages"]) |
+        (_, ["consumers", _, "events"]) |
+        (_, ["consumers", _, "commit"]) |
+        (_, ["stats"]) => Ok(json_error(405, "method not allowed")),
+        _ => Ok(json_error(404, "no such resource")),
+    };
+    res.unwrap_or_else(|e| {
+        let status = match *e.kind() {
+            ErrorKind::NotFound => 404,
+            ErrorKind::OffsetConflict { .. } => 409,
+            _ => 500,
+        };
+        json_error(status, &e.to_string())
+    })
+}
+
+fn produce(queue: &Queue, req: &Request) -> Result<Reply> {
+    let offset = try!(queue.producer().produce(&req.body));
+    Ok(Reply::Json(201, format!("{{\"offset\":{}}}", offset)))
+}
+
+fn read_range(queue: &Queue, req: &Request) -> Result<Reply> {
+    let from = match query_param(req, "from", 0) {
+        Ok(from) => from,
+        Err(reply) => return Ok(reply),
+    };
+    let limit = match limit_param(req) {
+        Ok(limit) => limit,
+        Err(reply) => return Ok(reply),
+    };
+    let entries = try!(queue.entries(from, limit));
+    Ok(Reply::Json(200, entries_json(&entries)))
+}
+
+fn consumer_messages(queue: &Queue, req: &Request, name: &str) -> Result<Reply> {
+    let limit = match limit_param(req) {
+        Ok(limit) => limit,
+        Err(reply) => return Ok(reply),
+    };
+    let timeout = match query_param(req, "timeout", 0) {
+        Ok(ms) => cmp::min(Duration::from_millis(ms), MAX_TIMEOUT),
+        Err(reply) => return Ok(reply),
+    };
+    let after = try!(queue.consumer(name)).offset;
+    // Nothing can follow the last offset there is.
+    let entries = match after.checked_add(1) {
+        Some(next) => try!(queue.wait_for_entries(next, limit, timeout)),
+        None => Vec::new(),
+    };
+    Ok(Reply::Json(200, entries_json(&entries)))
+}
+
+fn consumer_events(queue: &Queue, req: &Request, name: &str) -> Result<Reply> {
+    let after = match req.last_event_id {
+        Some(ref id) => {
+            match id.parse::<u64>() {
+                Ok(after) if after.checked_add(1).is_some() => after,
+                _ => return Ok(bad_request("bad Last-Event-ID")),
+            }
+        }
+        None => try!(queue.consumer(name)).offset,
+    };
+    Ok(Reply::Events(after))
+}
+
+fn commit(queue: &Queue, req: &Request, name: &str) -> Result<Reply> {
+    if req.param("offset").is_none() {
+        return Ok(bad_request("offset is required"));
+    }
+    let offset = match query_param(req, "offset", 0) {
+        Ok(offset) => offset,
+        Err(reply) => return Ok(reply),
+    };
+    try!(try!(queue.consumer(name)).commit_offset(offset));
+    Ok(Reply::NoContent)
+}
+
+fn list_consumers(queue: &Queue, only: Option<&str>) -> Result<Reply> {
+    let writer_next = try!(queue.writer_next());
+    let consumers = try!(queue.consumers());
+    let describe = |name: &str, offset: u64| {
+        format!("{{\"name\":{},\"offset\":{},\"lag\":{}}}",
+                json_string(name),
+                offset,
+                writer_next.saturating_sub(offset))
+    };
+    match only {
+        Some(name) => {
+            match consumers.get(name) {
+                Some(&offset) => Ok(Reply::Json(200, describe(name, offset))),
+                None => Err(Error::from(ErrorKind::NotFound).for_consumer(name)),
+            }
+        }
+        None => {
+            let items = consumers.iter()
+                                 .map(|(name, &offset)| describe(name, offset))
+                                 .collect::<Vec<_>>();
+            Ok(Reply::Json(200, format!("{{\"consumers\":[{}]}}", items.join(","))))
+        }
+    }
+}
+
+fn stats(queue: &Queue) -> Result<Reply> {
+    let count = try!(queue.record_count());
+    let first = try!(queue.entries(0, 1)).first().map(|e| e.offset.to_string());
+    let writer_next = try!(queue.writer_next());
+    let consumers = try!(queue.consumers()).len();
+    Ok(Reply::Json(200,
+                   format!("{{\"entries\":{},\"first_offset\":{},\"last_offset\":{},\
+                            \"consumers\":{}}}",
+                           count,
+                           first.unwrap_or_else(|| "null".to_string()),
+                           writer_next,
+                           consumers)))
+}
+
+fn stream_events<W: Write>(queue: &Queue, out: &mut W, mut after: u64) -> Result<()> {
+    try!(out.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: \
+                         no-cache\r\nConnection: close\r\n\r\n"));
+    try!(out.flush());
+    // Once the last offset there is has been sent, nothing more can follow.
+    while let Some(next) = after.checked_add(1) {
+        let entries = try!(queue.wait_for_entries(next, DEFAULT_LIMIT, KEEPALIVE_INTERVAL));
+        let mut chunk = String::new();
+        if entries.is_empty() {
+            chunk.push_str(": keepalive\n\n");
+        }
+        for entry in &entries {
+            chunk.push_str(&format!("id: {}\ndata: {}\n\n",
+                                    entry.offset,
+                                    base64::encode(&entry.data)));
+            after = entry.offset;
+        }
+        // Write failures just mean the client has gone away.
+        if let Err(e) = out.write_all(chunk.as_bytes()).and_then(|()| out.flush()) {
+            debug!("Event stream closed: {}", e);
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn query_param(req: &Request, key: &str, default: u64) -> ::std::result::Result<u64, Reply> {
+    match req.param(key) {
+        Some(v) => v.parse().map_err(|_| bad_request(&format!("bad {}", key))),
+        None => Ok(default),
+    }
+}
+
+fn limit_param(req: &Request) -> ::std::result::Result<usize, Reply> {
+    let limit = try!(query_param(req, "limit", DEFAULT_LIMIT as u64));
+    Ok(cmp::min(limit, MAX_LIMIT as u64) as usize)
+}
+
+fn entries_json(entries: &[Entry]) -> String {
+    let items = entries.iter()
+                       .map(|e| {
+                           format!("{{\"offset\":{},\"data\":\"{}\"}}",
+                                   e.offset,
+                                   base64::encode(&e.data))
+                       })
+                       .collect::<Vec<_>>();
+    format!("{{\"entries\":[{}]}}", items.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_error(status: u16, msg: &str) -> Reply {
+    Reply::Json(status, format!("{{\"error\":{}}}", json_string(msg)))
+}
+
+fn bad_request(msg: &str) -> Reply {
+    json_error(400, msg)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        _ => "Internal Server Error",
+    }
+}
+
+fn percent_decode(s: &str, plus_is_space: bool) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                match s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(b) => out.push(b),
+                    None => return None,
+                }
+                i += 3;
+            }
+            b'+' if plus_is_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
@@ -0,0 +1,976 @@
+//! A subset of the Kafka wire protocol, so that Kafka clients can produce
+//! to and consume from queues as if talking to a single broker.
+//!
+//! Each queue is served as a topic with a single partition, 0. Kafka
+//! offsets count from 0, so an entry's Kafka offset is one less than its
+//! queue offset; a consumer group's committed Kafka offset, being the next
+//! one to read, is then exactly the queue offset of the last entry read, and
+//! is stored as the committed offset of the consumer named after the group.
+//!
+//! Only the non-flexible versions of these APIs are supported:
+//!
+//! * `Produce` v3–7. Record values are stored as messages; keys, headers
+//!   and timestamps are dropped. Compressed and transactional batches are
+//!   refused.
+//! * `Fetch` v4–11, returning one uncompressed record batch per partition.
+//!   Fetch sessions are not supported, so every fetch is a full one.
+//! * `ListOffsets` v1–5, for the earliest and latest offsets only, since no
+//!   timestamps are kept.
+//! * `Metadata` v0–8, describing this server as the only broker.
+//! * `OffsetCommit` v2–7 and `OffsetFetch` v1–5. Group membership isn't
+//!   managed, so consumers must assign themselves the partitions.
+//! * `FindCoordinator` v0–2, naming this server.
+//! * `ApiVersions` v0–2.
+//!
+//! Clients should disable idempotent producing, which needs APIs that
+//! aren't provided.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use crc32c::crc32c;
+
+use errors::{ErrorKind, Result};
+use protocol;
+use {Entry, Queue};
+
+const PRODUCE: i16 = 0;
+const FETCH: i16 = 1;
+const LIST_OFFSETS: i16 = 2;
+const METADATA: i16 = 3;
+const OFFSET_COMMIT: i16 = 8;
+const OFFSET_FETCH: i16 = 9;
+const FIND_COORDINATOR: i16 = 10;
+const API_VERSIONS: i16 = 18;
+
+// Each API key served, with the oldest and newest versions supported.
+const APIS: &[(i16, i16, i16)] = &[(PRODUCE, 3, 7),
+                                   (FETCH, 4, 11),
+                                   (LIST_OFFSETS, 1, 5),
+                                   (METADATA, 0, 8),
+                                   (OFFSET_COMMIT, 2, 7),
+                                   (OFFSET_FETCH, 1, 5),
+                                   (FIND_COORDINATOR, 0, 2),
+                                   (API_VERSIONS, 0, 2)];
+
+const NONE: i16 = 0;
+const UNKNOWN_SERVER_ERROR: i16 = -1;
+const OFFSET_OUT_OF_RANGE: i16 = 1;
+const CORRUPT_MESSAGE: i16 = 2;
+const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+const UNSUPPORTED_VERSION: i16 = 35;
+const INVALID_REQUEST: i16 = 42;
+const UNSUPPORTED_FOR_MESSAGE_FORMAT: i16 = 43;
+const UNSUPPORTED_COMPRESSION_TYPE: i16 = 76;
+
+const NODE_ID: i32 = 0;
+const CLUSTER_ID: &str = "lmqueue";
+const LATEST: i64 = -1;
+const EARLIEST: i64 = -2;
+// Record batch attributes.
+const COMPRESSION_MASK: i16 = 0x07;
+const TRANSACTIONAL: i16 = 0x10;
+const CONTROL: i16 = 0x20;
+// Entries read per transaction while filling a fetch.
+const FETCH_CHUNK: usize = 256;
+
+/// Serves queues to Kafka clients, as described in `kafka`. Each
+/// connection is handled on its own thread.
+#[derive(Debug)]
+pub struct KafkaServer {
+    topics: BTreeMap<String, Queue>,
+    listener: TcpListener,
+}
+
+impl KafkaServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<KafkaServer> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(KafkaServer {
+            topics: BTreeMap::new(),
+            listener,
+        })
+    }
+
+    /// Serves `queue` as the topic `name`.
+    pub fn topic(mut self, name: &str, queue: &Queue) -> Self {
+        self.topics.insert(name.to_string(), queue.clone());
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        let topics = Arc::new(self.topics.clone());
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            try!(stream.set_nodelay(true));
+            debug!("Accepted connection from {:?}", peer);
+            let topics = topics.clone();
+            try!(thread::Builder::new()
+                     .name("lmqueue-kafka".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&topics, stream) {
+                             warn!("Connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+fn serve(topics: &BTreeMap<String, Queue>, mut stream: TcpStream) -> Result<()> {
+    // Clients reach the broker through whichever address they connected to.
+    let session = Session {
+        topics,
+        addr: try!(stream.local_addr()),
+    };
+    while let Some(frame) = try!(protocol::read_frame(&mut stream)) {
+        let mut req = Decoder::new(&frame);
+        let api_key = try!(req.i16());
+        let version = try!(req.i16());
+        let correlation_id = try!(req.i32());
+        let mut out = Encoder::new();
+        out.i32(correlation_id);
+        if api_key == API_VERSIONS && version > 2 {
+            // Answered in a version the client must understand, so it can
+            // pick another.
+            api_versions(&mut out, 0, UNSUPPORTED_VERSION);
+            try!(protocol::write_frame(&mut stream, &out.buf));
+            continue;
+        }
+        let supported = APIS.iter()
+                            .any(|&(key, min, max)| key == api_key && min <= version &&
+                                                    version <= max);
+        if !supported {
+            let msg = format!("unsupported request: API {} version {}", api_key, version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+        let _client_id = try!(req.nullable_string());
+        trace!("Request: API {} version {}", api_key, version);
+        let respond = match api_key {
+            PRODUCE => try!(session.produce(&mut req, version, &mut out)),
+            FETCH => try!(session.fetch(&mut req, version, &mut out)),
+            LIST_OFFSETS => try!(session.list_offsets(&mut req, version, &mut out)),
+            METADATA => try!(session.metadata(&mut req, version, &mut out)),
+            OFFSET_COMMIT => try!(session.offset_commit(&mut req, version, &mut out)),
+            OFFSET_FETCH => try!(session.offset_fetch(&mut req, version, &mut out)),
+            FIND_COORDINATOR => try!(session.find_coordinator(&mut req, version, &mut out)),
+            _ => {
+                api_versions(&mut out, version, NONE);
+                true
+            }
+        };
+        if respond {
+            try!(protocol::write_frame(&mut stream, &out.buf));
+        }
+    }
+    debug!("Connection closed");
+    Ok(())
+}
+
+fn api_versions(out: &mut Encoder, version: i16, error: i16) {
+    out.i16(error);
+    out.i32(APIS.len() as i32);
+    for &(key, min, max) in APIS {
+        out.i16(key);
+        out.i16(min);
+        out.i16(max);
+    }
+    if version >= 1 {
+        out.i32(0);
+    }
+}
+
+struct Session<'a> {
+    topics: &'a BTreeMap<String, Queue>,
+    addr: SocketAddr,
+}
+
+// Each handler decodes its request and encodes the response body, returning
+// whether a response should be sent.
+impl<'a> Session<'a> {
+    fn produce(&self, req: &mut Decoder, version: i16, out: &mut Encoder) -> io::Result<bool> {
+        let _transactional_id = try!(req.nullable_string());
+        let acks = try!(req.i16());
+        let _timeout_ms = try!(req.i32());
+        let topics = try!(req.array_len());
+        out.i32(topics as i32);
+        for _ in 0..topics {
+            let name = try!(req.string());
+            out.string(&name);
+            let partitions = try!(req.array_len());
+            out.i32(partitions as i32);
+            for _ in 0..partitions {
+                let partition = try!(req.i32());
+                let records = try!(req.nullable_bytes()).unwrap_or_default();
+                let (error, base_offset) = match self.partition(&name, partition) {
+                    Some(queue) => {
+                        match record_values(&records) {
+                            Ok(ref values) if values.is_empty() => {
+                                (NONE, queue.writer_next().map(|n| n as i64).unwrap_or(-1))
+                            }
+                            Ok(values) => {
+                                match queue.producer().produce_batch(&values) {
+                                    Ok(offsets) => (NONE, offsets[0] as i64 - 1),
+                                    Err(e) => {
+                                        warn!("Produce to {:?} failed: {}", name, e);
+                                        (UNKNOWN_SERVER_ERROR, -1)
+                                    }
+                                }
+                            }
+                            Err(error) => (error, -1),
+                        }
+                    }
+                    None => (UNKNOWN_TOPIC_OR_PARTITION, -1),
+                };
+                out.i32(partition);
+                out.i16(error);
+                out.i64(base_offset);
+                out.i64(-1);
+                if version >= 5 {
+                    let start = self.partition(&name, partition)
+                                    .and_then(|queue| log_bounds(queue).ok())
+                                    .map(|(start, _)| start)
+                                    .unwrap_or(-1);
+                    out.i64(start);
+                }
+            }
+        }
+        out.i32(0);
+        Ok(acks != 0)
+    }
+
+    fn fetch(&self, req: &mut Decoder, version: i16, out: &mut Encoder) -> io::Result<bool> {
+        let _replica_id = try!(req.i32());
+        let max_wait_ms = try!(req.i32());
+        let _min_bytes = try!(req.i32());
+        let max_bytes = try!(req.i32());
+        let _isolation_level = try!(req.i8());
+        if version >= 7 {
+            let _session_id = try!(req.i32());
+            let _session_epoch = try!(req.i32());
+        }
+        let mut wanted = Vec::new();
+        for _ in 0..try!(req.array_len()) {
+            let name = try!(req.string());
+            let mut partitions = Vec::new();
+            for _ in 0..try!(req.array_len()) {
+                let partition = try!(req.i32());
+                if version >= 9 {
+                    let _current_leader_epoch = try!(req.i32());
+                }
+                let fetch_offset = try!(req.i64());
+                if version >= 5 {
+                    let _log_start_offset = try!(req.i64());
+                }
+                let partition_max_bytes = try!(req.i32());
+                partitions.push(Want {
+                    partition,
+                    fetch_offset,
+                    max_bytes: partition_max_bytes,
+                });
+            }
+            wanted.push((name, partitions));
+        }
+        // Forgotten topics and the rack ID only matter to fetch sessions.
+
+        let mut fetched = self.fetch_once(&wanted, max_bytes);
+        let found_nothing = fetched.iter()
+                                   .flat_map(|topic| topic.1.iter())
+                                   .all(|p| p.error != NONE || p.entries.is_empty());
+        if found_nothing && max_wait_ms > 0 {
+            // Wait on the first partition that could have something to say.
+            let waiting = fetched.iter()
+                                 .flat_map(|topic| {
+                                     topic.1
+                                          .iter()
+                                          .filter(|p| p.error == NONE)
+                                          .map(move |p| (topic.0.clone(), p.fetch_offset))
+                                 })
+                                 .next();
+            if let Some((name, offset)) = waiting {
+                let queue = &self.topics[&name];
+                let wait = Duration::from_millis(max_wait_ms as u64);
+                if let Err(e) = queue.wait_for_entries(offset as u64 + 1, 1, wait) {
+                    warn!("Waiting on {:?} failed: {}", name, e);
+                }
+                fetched = self.fetch_once(&wanted, max_bytes);
+            }
+        }
+
+        out.i32(0);
+        if version >= 7 {
+            out.i16(NONE);
+            out.i32(0);
+        }
+        out.i32(fetched.len() as i32);
+        for (name, partitions) in fetched {
+            out.string(&name);
+            out.i32(partitions.len() as i32);
+            for p in partitions {
+                out.i32(p.partition);
+                out.i16(p.error);
+                out.i64(p.high_watermark);
+                out.i64(p.high_watermark);
+                if version >= 5 {
+                    out.i64(p.log_start);
+                }
+                out.i32(0);
+                if version >= 11 {
+                    out.i32(-1);
+                }
+                if p.entries.is_empty() {
+                    out.bytes(&[]);
+                } else {
+                    out.bytes(&record_batch(&p.entries));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn fetch_once(&self,
+                  wanted: &[(String, Vec<Want>)],
+                  max_bytes: i32)
+                  -> Vec<(String, Vec<Fetched>)> {
+        let mut budget = cmp::max(max_bytes, 0) as usize;
+        let mut fetched = Vec::new();
+        for topic in wanted {
+            let name = &topic.0;
+            let mut results = Vec::new();
+            for want in &topic.1 {
+                let (partition, fetch_offset) = (want.partition, want.fetch_offset);
+                let mut result = Fetched {
+                    partition,
+                    fetch_offset,
+                    error: NONE,
+                    high_watermark: -1,
+                    log_start: -1,
+                    entries: Vec::new(),
+                };
+                let queue = match self.partition(name, partition) {
+                    Some(queue) => queue,
+                    None => {
+                        result.error = UNKNOWN_TOPIC_OR_PARTITION;
+                        results.push(result);
+                        continue;
+                    }
+                };
+                let res = log_bounds(queue).and_then(|(start, end)| {
+                    result.log_start = start;
+                    result.high_watermark = end;
+                    if fetch_offset < start || fetch_offset > end {
+                        result.error = OFFSET_OUT_OF_RANGE;
+                        return Ok(());
+                    }
+                    let limit = cmp::min(cmp::max(want.max_bytes, 0) as usize, budget);
+                    result.entries = try!(read_bytes_worth(queue, fetch_offset as u64 + 1, limit));
+                    let size = result.entries.iter().map(|e| e.data.len()).sum::<usize>();
+                    budget = budget.saturating_sub(size);
+                    Ok(())
+                });
+                if let Err(e) = res {
+                    warn!("Fetch from {:?} failed: {}", name, e);
+                    result.error = UNKNOWN_SERVER_ERROR;
+                }
+                results.push(result);
+            }
+            fetched.push((name.clone(), results));
+        }
+        fetched
+    }
+
+    fn list_offsets(&self, req: &mut Decoder, version: i16, out: &mut Encoder) -> io::Result<bool> {
+        let _replica_id = try!(req.i32());
+        if version >= 2 {
+            let _isolation_level = try!(req.i8());
+        }
+        if version >= 2 {
+            out.i32(0);
+        }
+        let topics = try!(req.array_len());
+        out.i32(topics as i32);
+        for _ in 0..topics {
+            let name = try!(req.string());
+            out.string(&name);
+            let partitions = try!(req.array_len());
+            out.i32(partitions as i32);
+            for _ in 0..partitions {
+                let partition = try!(req.i32());
+                if version >= 4 {
+                    let _current_leader_epoch = try!(req.i32());
+                }
+                let timestamp = try!(req.i64());
+                let (error, offset) = match self.partition(&name, partition) {
+                    Some(queue) => {
+                        match (log_bounds(queue), timestamp) {
+                            (Ok((_, end)), LATEST) => (NONE, end),
+                            (Ok((start, _)), EARLIEST) => (NONE, start),
+                            (Ok(_), _) => (UNSUPPORTED_FOR_MESSAGE_FORMAT, -1),
+                            (Err(e), _) => {
+                                warn!("Listing offsets of {:?} failed: {}", name, e);
+                                (UNKNOWN_SERVER_ERROR, -1)
+                            }
+                        }
+                    }
+                    None => (UNKNOWN_TOPIC_OR_PARTITION, -1),
+                };
+                out.i32(partition);
+                out.i16(error);
+                out.i64(-1);
+                out.i64(offset);
+                if version >= 4 {
+                    out.i32(0);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn metadata(&self, req: &mut Decoder, version: i16, out: &mut Encoder) -> io::Result<bool> {
+        let requested = match try!(req.nullable_array_len()) {
+            // Before version 1, no topics means all of them.
+            Some(0) if version == 0 => None,
+            Some(n) => {
+                let mut names = Vec::new();
+                for _ in 0..n {
+                    names.push(try!(req.string()));
+                }
+                Some(names)
+            }
+            None => None,
+        };
+        // Topics are never created automatically, and there's no access
+        // control to report on.
+        let names = requested.unwrap_or_else(|| self.topics.keys().cloned().collect());
+
+        if version >= 3 {
+            out.i32(0);
+        }
+        out.i32(1);
+        out.i32(NODE_ID);
+        out.string(&self.addr.ip().to_string());
+        out.i32(self.addr.port() as i32);
+        if version >= 1 {
+            out.nullable_string(None);
+        }
+        if version >= 2 {
+            out.nullable_string(Some(CLUSTER_ID));
+        }
+        if version >= 1 {
+            out.i32(NODE_ID);
+        }
+        out.i32(names.len() as i32);
+        for name in names {
+            let known = self.topics.contains_key(&name);
+            out.i16(if known { NONE } else { UNKNOWN_TOPIC_OR_PARTITION });
+            out.string(&name);
+            if version >= 1 {
+                out.bool(false);
+            }
+            if known {
+                out.i32(1);
+                out.i16(NONE);
+                out.i32(0);
+                out.i32(NODE_ID);
+                if version >= 7 {
+                    out.i32(0);
+                }
+                out.i32(1);
+                out.i32(NODE_ID);
+                out.i32(1);
+                out.i32(NODE_ID);
+                if version >= 5 {
+                    out.i32(0);
+                }
+            } else {
+                out.i32(0);
+            }
+            if version >= 8 {
+                out.i32(i32::MIN);
+            }
+        }
+        if version >= 8 {
+            out.i32(i32::MIN);
+        }
+        Ok(true)
+    }
+
+    fn offset_commit(&self, req: &mut Decoder, version: i16, out: &mut Encoder) -> io::Result<bool> {
+        let group = try!(req.string());
+        let _generation_id = try!(req.i32());
+        let _member_id = try!(req.string());
+        if version <= 4 {
+            let _retention_time_ms = try!(req.i64());
+        }
+        if version >= 7 {
+            let _group_instance_id = try!(req.nullable_string());
+        }
+        if version >= 3 {
+            out.i32(0);
+        }
+        let topics = try!(req.array_len());
+        out.i32(topics as i32);
+        for _ in 0..topics {
+            let name = try!(req.string());
+            out.string(&name);
+            let partitions = try!(req.array_len());
+            out.i32(partitions as i32);
+            for _ in 0..partitions {
+                let partition = try!(req.i32());
+                let offset = try!(req.i64());
+                if version >= 6 {
+                    let _committed_leader_epoch = try!(req.i32());
+                }
+                let _metadata = try!(req.nullable_string());
+                let error = match self.partition(&name, partition) {
+                    Some(_) if offset < 0 => INVALID_REQUEST,
+                    Some(queue) => {
+                        match queue.consumer(&group).and_then(|c| c.commit_offset(offset as u64)) {
+                            Ok(()) => NONE,
+                            Err(e) => {
+                                warn!("Commit to {:?} failed: {}", name, e);
+                                UNKNOWN_SERVER_ERROR
+                            }
+                        }
+                    }
+                    None => UNKNOWN_TOPIC_OR_PARTITION,
+                };
+                out.i32(partition);
+                out.i16(error);
+            }
+        }
+        Ok(true)
+    }
+
+    fn offset_fetch(&self, req: &mut Decoder, version: i16, out: &mut Encoder) -> io::Result<bool> {
+        let group = try!(req.string());
+        let requested = match try!(req.nullable_array_len()) {
+            Some(n) => {
+                let mut topics = Vec::new();
+                for _ in 0..n {
+                    let name = try!(req.string());
+                    let mut partitions = Vec::new();
+                    for _ in 0..try!(req.array_len()) {
+                        partitions.push(try!(req.i32()));
+                    }
+                    topics.push((name, partitions));
+                }
+                topics
+            }
+            // Every topic the group has committed to.
+            None => {
+                self.topics
+                    .iter()
+                    .filter(|&(_, queue)| queue.consumer_offset(&group).is_ok())
+                    .map(|(name, _)| (name.clone(), vec![0]))
+                    .collect()
+            }
+        };
+
+        if version >= 3 {
+            out.i32(0);
+        }
+        out.i32(requested.len() as i32);
+        for (name, partitions) in requested {
+            out.string(&name);
+            out.i32(partitions.len() as i32);
+            for partition in partitions {
+                let (error, offset) = match self.partition(&name, partition) {
+                    Some(queue) => {
+                        match queue.consumer_offset(&group) {
+                            Ok(offset) => (NONE, offset as i64),
+                            Err(ref e) if is_not_found(e) => (NONE, -1),
+                            Err(e) => {
+                                warn!("Fetching offset from {:?} failed: {}", name, e);
+                                (UNKNOWN_SERVER_ERROR, -1)
+                            }
+                        }
+                    }
+                    None => (UNKNOWN_TOPIC_OR_PARTITION, -1),
+                };
+                out.i32(partition);
+                out.i64(offset);
+                if version >= 5 {
+                    out.i32(-1);
+                }
+                out.nullable_string(Some(""));
+                out.i16(error);
+            }
+        }
+        if version >= 2 {
+            out.i16(NONE);
+        }
+        Ok(true)
+    }
+
+    fn find_coordinator(&self,
+                        req: &mut Decoder,
+                        version: i16,
+                        out: &mut Encoder)
+                        -> io::Result<bool> {
+        let _key = try!(req.string());
+        if version >= 1 {
+            let _key_type = try!(req.i8());
+            out.i32(0);
+        }
+        out.i16(NONE);
+        if version >= 1 {
+            out.nullable_string(None);
+        }
+        out.i32(NODE_ID);
+        out.string(&self.addr.ip().to_string());
+        out.i32(self.addr.port() as i32);
+        Ok(true)
+    }
+
+    fn partition(&self, topic: &str, partition: i32) -> Option<&Queue> {
+        if partition == 0 {
+            self.topics.get(topic)
+        } else {
+            None
+        }
+    }
+}
+
+struct Want {
+    partition: i32,
+    fetch_offset: i64,
+    max_bytes: i32,
+}
+
+struct Fetched {
+    partition: i32,
+    fetch_offset: i64,
+    error: i16,
+    high_watermark: i64,
+    log_start: i64,
+    entries: Vec<Entry>,
+}
+
+// The Kafka offsets of the first entry held, and of the next to be
+// produced.
+fn log_bounds(queue: &Queue) -> Result<(i64, i64)> {
+    let end = try!(queue.writer_next()) as i64;
+    let start = match try!(queue.entries(0, 1)).first() {
+        Some(entry) => entry.offset as i64 - 1,
+        None => end,
+    };
+    Ok((start, end))
+}
+
+// Entries from offset `from` onwards, until they hold at least `limit`
+// bytes. The first is always included, so that large messages can't hold
+// up a consumer.
+fn read_bytes_worth(queue: &Queue, from: u64, limit: usize) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut size = 0;
+    let mut from = from;
+    loop {
+        let chunk = try!(queue.entries(from, FETCH_CHUNK));
+        let done = chunk.len() < FETCH_CHUNK;
+        for entry in chunk {
+            if !entries.is_empty() && size + entry.data.len() > limit {
+                return Ok(entries);
+            }
+            size += entry.data.len();
+            from = entry.offset + 1;
+            entries.push(entry);
+        }
+        if done {
+            return Ok(entries);
+        }
+    }
+}
+
+// The values held in a series of record batches, or a Kafka error code.
+fn record_values(data: &[u8]) -> ::std::result::Result<Vec<Vec<u8>>, i16> {
+    let mut values = Vec::new();
+    let mut input = Decoder::new(data);
+    while input.remaining() > 0 {
+        let res = read_batch(&mut input, &mut values);
+        match res {
+            Ok(NONE) => (),
+            Ok(error) => return Err(error),
+            Err(_) => return Err(CORRUPT_MESSAGE),
+        }
+    }
+    Ok(values)
+}
+
+fn read_batch(input: &mut Decoder, values: &mut Vec<Vec<u8>>) -> io::Result<i16> {
+    let _base_offset = try!(input.i64());
+    let length = try!(input.i32());
+    if length < 0 || length as usize > input.remaining() {
+        return Ok(CORRUPT_MESSAGE);
+    }
+    let batch = try!(input.take(length as usize));
+    let mut batch = Decoder::new(batch);
+    let _partition_leader_epoch = try!(batch.i32());
+    if try!(batch.i8()) != 2 {
+        return Ok(CORRUPT_MESSAGE);
+    }
+    let crc = try!(batch.i32()) as u32;
+    if crc32c(batch.rest()) != crc {
+        return Ok(CORRUPT_MESSAGE);
+    }
+    let attributes = try!(batch.i16());
+    if attributes & COMPRESSION_MASK != 0 {
+        return Ok(UNSUPPORTED_COMPRESSION_TYPE);
+    }
+    if attributes & (TRANSACTIONAL | CONTROL) != 0 {
+        return Ok(UNSUPPORTED_FOR_MESSAGE_FORMAT);
+    }
+    let _last_offset_delta = try!(batch.i32());
+    let _base_timestamp = try!(batch.i64());
+    let _max_timestamp = try!(batch.i64());
+    let _producer_id = try!(batch.i64());
+    let _producer_epoch = try!(batch.i16());
+    let _base_sequence = try!(batch.i32());
+    let count = try!(batch.i32());
+    for _ in 0..count {
+        let length = try!(batch.varint());
+        if length < 0 {
+            return Ok(CORRUPT_MESSAGE);
+        }
+        let mut record = Decoder::new(try!(batch.take(length as usize)));
+        let _attributes = try!(record.i8());
+        let _timestamp_delta = try!(record.varint());
+        let _offset_delta = try!(record.varint());
+        let _key = try!(record.varint_bytes());
+        values.push(try!(record.varint_bytes()).unwrap_or(&[]).to_vec());
+        // Headers are dropped.
+    }
+    Ok(NONE)
+}
+
+// A single uncompressed batch holding `entries`.
+fn record_batch(entries: &[Entry]) -> Vec<u8> {
+    let base = entries[0].offset;
+    let mut records = Encoder::new();
+    for entry in entries {
+        let mut record = Encoder::new();
+        record.i8(0);
+        record.varint(0);
+        record.varint((entry.offset - base) as i64);
+        record.varint(-1);
+        record.varint(entry.data.len() as i64);
+        record.raw(&entry.data);
+        record.varint(0);
+        records.varint(record.buf.len() as i64);
+        records.raw(&record.buf);
+    }
+
+    // Everything covered by the checksum.
+    let mut body = Encoder::new();
+    body.i16(0);
+    body.i32((entries[entries.len() - 1].offset - base) as i32);
+    body.i64(-1);
+    body.i64(-1);
+    body.i64(-1);
+    body.i16(-1);
+    body.i32(-1);
+    body.i32(entries.len() as i32);
+    body.raw(&records.buf);
+
+    let mut batch = Encoder::new();
+    batch.i64(base as i64 - 1);
+    batch.i32((4 + 1 + 4 + body.buf.len()) as i32);
+    batch.i32(0);
+    batch.i8(2);
+    batch.i32(crc32c(&body.buf) as i32);
+    batch.raw(&body.buf);
+    batch.buf
+}
+
+fn is_not_found(e: &::errors::Error) -> bool {
+    matches!(*e.kind(), ErrorKind::NotFound)
+}
+
+struct Decoder<'a> {
+    input: Cursor<&'a [u8]>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8]) -> Decoder<'a> {
+        Decoder { input: Cursor::new(input) }
+    }
+
+    fn remaining(&self) -> usize {
+        self.input.get_ref().len() - self.input.position() as usize
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.input.get_ref()[self.input.position() as usize..]
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(truncated());
+        }
+        let start = self.input.position() as usize;
+        self.input.set_position((start + len) as u64);
+        Ok(&self.input.get_ref()[start..start + len])
+    }
+
+    fn i8(&mut self) -> io::Result<i8> {
+        self.input.read_i8()
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        self.input.read_i16::<BigEndian>()
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        self.input.read_i32::<BigEndian>()
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        self.input.read_i64::<BigEndian>()
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        try!(self.nullable_string()).ok_or_else(|| invalid("unexpected null string"))
+    }
+
+    fn nullable_string(&mut self) -> io::Result<Option<String>> {
+        let len = try!(self.i16());
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = try!(self.take(len as usize));
+        String::from_utf8(bytes.to_vec()).map(Some).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn nullable_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let len = try!(self.i32());
+        if len < 0 {
+            return Ok(None);
+        }
+        self.take(len as usize).map(|b| Some(b.to_vec()))
+    }
+
+    fn array_len(&mut self) -> io::Result<usize> {
+        try!(self.nullable_array_len()).ok_or_else(|| invalid("unexpected null array"))
+    }
+
+    fn nullable_array_len(&mut self) -> io::Result<Option<usize>> {
+        let len = try!(self.i32());
+        if len < 0 {
+            return Ok(None);
+        }
+        // Every element takes at least a byte.
+        if len as usize > self.remaining() {
+            return Err(truncated());
+        }
+        Ok(Some(len as usize))
+    }
+
+    // A zig-zag encoded variable-length integer.
+    fn varint(&mut self) -> io::Result<i64> {
+        let mut value = 0u64;
+        for shift in 0..10 {
+            let byte = try!(self.input.read_u8());
+            value |= ((byte & 0x7f) as u64) << (shift * 7);
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err(invalid("varint is too long"))
+    }
+
+    fn varint_bytes(&mut self) -> io::Result<Option<&'a [u8]>> {
+        let len = try!(self.varint());
+        if len < 0 {
+            return Ok(None);
+        }
+        self.take(len as usize).map(Some)
+    }
+}
+
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder { buf: Vec::new() }
+    }
+
+    fn raw(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    fn i8(&mut self, v: i8) {
+        self.buf.push(v as u8);
+    }
+
+    fn i16(&mut self, v: i16) {
+        let mut b = [0; 2];
+        BigEndian::write_i16(&mut b, v);
+        self.raw(&b);
+    }
+
+    fn i32(&mut self, v: i32) {
+        let mut b = [0; 4];
+        BigEndian::write_i32(&mut b, v);
+        self.raw(&b);
+    }
+
+    fn i64(&mut self, v: i64) {
+        let mut b = [0; 8];
+        BigEndian::write_i64(&mut b, v);
+        self.raw(&b);
+    }
+
+    fn string(&mut self, s: &str) {
+        self.nullable_string(Some(s))
+    }
+
+    fn nullable_string(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => {
+                self.i16(s.len() as i16);
+                self.raw(s.as_bytes());
+            }
+            None => self.i16(-1),
+        }
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.i32(b.len() as i32);
+        self.raw(b);
+    }
+
+    fn varint(&mut self, v: i64) {
+        let mut v = ((v << 1) ^ (v >> 63)) as u64;
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    invalid("truncated request")
+}
+
@@ -1,31 +1,125 @@
 extern crate lmdb_zero;
+extern crate base64;
 extern crate byteorder;
-#[macro_use]
-extern crate error_chain;
+extern crate crc32c;
 #[macro_use]
 extern crate log;
-use std::io::Cursor;
+#[cfg(any(feature = "json", feature = "bincode"))]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "bincode")]
+extern crate bincode;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio;
+use std::io::{self, Cursor};
 use std::collections::BTreeMap;
+use std::cmp;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 
-use lmdb_zero::{Environment, EnvBuilder, Database, ConstAccessor, ReadTransaction, WriteAccessor,
-                WriteTransaction, put, open, del, error};
+use lmdb_zero::{Environment, Database, ConstAccessor, ReadTransaction, WriteAccessor,
+                WriteTransaction, put, del, error};
 
 mod errors;
-
-use errors::*;
+mod options;
+mod durability;
+mod notify;
+mod codec;
+mod format;
+mod envelope;
+mod verify;
+mod backup;
+mod dump;
+mod protocol;
+mod server;
+mod client;
+mod address;
+mod http;
+mod resp;
+mod kafka;
+mod mqtt;
+mod stomp;
+mod replication;
+mod metrics;
+mod stats;
+mod totals;
+#[cfg(feature = "async")]
+mod nonblocking;
+
+pub use errors::{Error, ErrorKind, Result};
+pub use options::QueueOptions;
+pub use durability::Durability;
+pub use codec::{Codec, TypedConsumer, TypedEntry, TypedProducer};
+#[cfg(feature = "json")]
+pub use codec::JsonCodec;
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+pub use format::FORMAT_VERSION;
+pub use verify::{Problem, Verification};
+pub use server::Server;
+#[cfg(unix)]
+pub use server::UnixServer;
+pub use client::{Client, RemoteConsumer, RemoteProducer};
+pub use address::{AnyConsumer, AnyProducer, AnyQueue};
+pub use http::HttpServer;
+pub use resp::RespServer;
+pub use kafka::KafkaServer;
+pub use mqtt::MqttServer;
+pub use stomp::StompServer;
+pub use replication::{Follower, ReplicationStatus};
+pub use metrics::{Histogram, Metrics, MetricsServer, render_metrics};
+pub use stats::{DbStats, EnvStats, Stats};
+pub use totals::Totals;
+pub use lmdb_zero::FileMode;
+#[cfg(feature = "async")]
+pub use nonblocking::{ConsumerStream, ProducerSink};
 
 const PRODUCER_OFFSETS: &'static str = "prod";
 const CONSUMER_OFFSETS: &'static str = "cons";
 const DATA: &'static str = "data";
-// 1TGB. That'll be enough, right?
-const ARBITARILY_LARGE: usize = 1 << 40;
 
 const WRITER_NEXT: &'static str = "writer-next";
 
+// How often a waiting consumer looks for entries from other processes.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A handle onto a single queue environment.
+///
+/// LMDB forbids opening the same environment more than once in a process, so
+/// a `Queue` opens it exactly once, along with the databases we use, and hands
+/// out producers and consumers that share it. Cloning a `Queue` is cheap.
+#[derive(Debug,Clone)]
+pub struct Queue {
+    inner: Arc<QueueInner>,
+}
+
+#[derive(Debug)]
+struct QueueInner {
+    path: PathBuf,
+    env: Arc<Environment>,
+    producers: Database<'static>,
+    consumers: Database<'static>,
+    data: Database<'static>,
+    // Every transaction holds this shared; changing the map size requires
+    // that there are none active in this process, so takes it exclusively.
+    resize_lock: RwLock<()>,
+    max_map_size: Option<usize>,
+    notifier: notify::CommitNotifier,
+    follower_acks: replication::FollowerAcks,
+    // How long producers wait for a follower to acknowledge, if at all.
+    follower_ack_timeout: Option<Duration>,
+    counters: metrics::Counters,
+}
+
 #[derive(Debug)]
 pub struct Producer {
-    env: Environment,
+    queue: Queue,
 }
 
 fn encode_key(val: u64) -> Result<[u8; 8]> {
@@ -38,27 +132,53 @@ fn encode_key(val: u64) -> Result<[u8; 8]> {
     Ok(key)
 }
 
-fn decode_key(val: &[u8]) -> Result<u64> {
+fn decode_key(val: &[u8]) -> Option<u64> {
+    if val.len() != 8 {
+        return None;
+    }
     let mut r = Cursor::new(val);
-    Ok(try!(r.read_u64::<BigEndian>()))
+    r.read_u64::<BigEndian>().ok()
 }
 
+// Decodes the key of a data record found while looking for `near`.
+fn decode_data_key(val: &[u8], near: u64) -> Result<u64> {
+    decode_key(val).ok_or_else(|| ErrorKind::CorruptRecord { offset: near }.into())
+}
 
+fn decode_offset(val: &[u8], key: &str) -> Result<u64> {
+    decode_key(val).ok_or_else(|| ErrorKind::CorruptOffset { key: key.to_string() }.into())
+}
 
 fn read_offset(meta: &Database, txn: &ConstAccessor, key: &str) -> Result<u64> {
-    let val = {
-        let val = match txn.get(meta, key) {
-            Ok(val) => try!(decode_key(val)),
-            Err(e) if e.code == error::NOTFOUND => 0,
-            Err(e) => return Err(e.into()),
-        };
-        val
+    let val = match try!(mdb_maybe(txn.get(meta, key))) {
+        Some(val) => try!(decode_offset(val, key)),
+        None => 0,
     };
 
     trace!("{:?} at pos: {:?}", key, val);
     Ok(val)
 }
 
+// Stores a sealed record at `offset`, refusing to replace one already there.
+fn put_record(data: &Database, txn: &mut WriteAccessor, offset: u64, record: &[u8]) -> Result<()> {
+    let key = try!(encode_key(offset));
+    match txn.put(data, &key, record, put::NOOVERWRITE) {
+        Err(error::Error::Code(error::KEYEXIST)) => {
+            Err(ErrorKind::OffsetConflict { offset }.into())
+        }
+        res => res.map_err(Error::from),
+    }
+}
+
+// How long to wait before looking again for entries, given when we must
+// stop waiting, if ever.
+fn recheck_after(deadline: Option<Instant>, now: Instant) -> Duration {
+    match deadline {
+        Some(deadline) => cmp::min(deadline - now, RECHECK_INTERVAL),
+        None => RECHECK_INTERVAL,
+    }
+}
+
 fn write_offset(meta: &Database, txn: &mut WriteAccessor, key: &str, off: u64) -> Result<()> {
     let encoded = try!(encode_key(off));
     try!(txn.put(meta, key, &encoded, put::Flags::empty()));
@@ -66,46 +186,403 @@ fn write_offset(meta: &Database, txn: &mut WriteAccessor, key: &str, off: u64) -
     Ok(())
 }
 
+impl Queue {
+    /// Opens the queue at `place` with the default `QueueOptions`.
+    pub fn open<P: AsRef<Path>>(place: P) -> Result<Self> {
+        QueueOptions::new().open(place)
+    }
+
+    pub fn producer(&self) -> Producer {
+        Producer { queue: self.clone() }
+    }
+
+    pub fn consumer(&self, name: &str) -> Result<Consumer> {
+        let offset = try!(self.read(|txn| {
+                                  let access = txn.access();
+                                  read_offset(self.consumers_db(), &access, name)
+                              })
+                              .map_err(|e| e.for_consumer(name)));
+
+        Ok(Consumer {
+            queue: self.clone(),
+            name: name.to_string(),
+            offset,
+        })
+    }
+
+    /// The last offset committed by the named consumer, or `NotFound`.
+    pub fn consumer_offset(&self, name: &str) -> Result<u64> {
+        self.read(|txn| {
+                match try!(mdb_maybe(txn.access().get::<str, [u8]>(self.consumers_db(), name))) {
+                    Some(val) => decode_offset(val, name),
+                    None => Err(ErrorKind::NotFound.into()),
+                }
+            })
+            .map_err(|e| e.for_consumer(name))
+    }
+
+    /// Up to `limit` entries, starting from the first at or after offset
+    /// `from`. Doesn't involve any consumer.
+    pub fn entries(&self, from: u64, limit: usize) -> Result<Vec<Entry>> {
+        let data = self.data_db();
+        let key = try!(encode_key(from));
+        self.read(|txn| {
+            let mut entries = Vec::new();
+            let mut cursor = try!(txn.cursor(data));
+            let access = txn.access();
+            let mut curr = try!(mdb_maybe(cursor.seek_range_k::<[u8], [u8]>(&access, &key)));
+            let mut near = from;
+            while let Some((k, v)) = curr {
+                if entries.len() >= limit {
+                    break;
+                }
+                let offset = try!(decode_data_key(k, near));
+                entries.push(Entry {
+                    offset,
+                    data: try!(envelope::unseal(offset, v)).to_vec(),
+                });
+                near = offset + 1;
+                curr = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&access)));
+            }
+            Ok(entries)
+        })
+    }
+
+    // Like `entries`, but if there are none yet, waits up to `timeout` for
+    // some to be produced.
+    fn wait_for_entries(&self, from: u64, limit: usize, timeout: Duration) -> Result<Vec<Entry>> {
+        // A timeout too long to represent is as good as forever.
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let seen = self.inner.notifier.generation();
+            let entries = try!(self.entries(from, limit));
+            let now = Instant::now();
+            if !entries.is_empty() || deadline.is_some_and(|deadline| now >= deadline) {
+                return Ok(entries);
+            }
+            self.inner.notifier.wait(seen, recheck_after(deadline, now));
+        }
+    }
+
+    // The number of records held.
+    fn record_count(&self) -> Result<u64> {
+        let data = self.data_db();
+        self.read(|txn| Ok(try!(txn.db_stat(data)).entries as u64))
+    }
+
+    // The last offset handed out to a producer.
+    fn writer_next(&self) -> Result<u64> {
+        let meta = self.producers_db();
+        self.read(|txn| read_offset(meta, &txn.access(), WRITER_NEXT))
+    }
+
+    /// Flushes committed transactions to disk. Only needed when the queue
+    /// was opened with relaxed `Durability`. If `force` is false, an `Async`
+    /// queue leaves it to the OS.
+    pub fn sync(&self, force: bool) -> Result<()> {
+        try!(self.env().sync(force).map_err(|e| Error::from(e).in_queue(self.path())));
+        Ok(())
+    }
+
+    pub fn durability(&self) -> Result<Durability> {
+        let flags = try!(self.env().flags().map_err(|e| Error::from(e).in_queue(self.path())));
+        Ok(durability::durability_of(flags))
+    }
+
+    // Runs `f` in a read transaction, adopting a new map size if another
+    // process has grown the map beyond ours.
+    fn read<T, F>(&self, mut f: F) -> Result<T>
+        where F: FnMut(&ReadTransaction) -> Result<T>
+    {
+        let res = loop {
+            let res = {
+                let _guard = self.inner.resize_lock.read().expect("resize lock poisoned");
+                ReadTransaction::new(self.env()).map_err(Error::from).and_then(|txn| f(&txn))
+            };
+            match res {
+                Err(ref e) if mdb_code(e) == Some(error::MAP_RESIZED) => {
+                    if let Err(e) = self.adopt_map_size() {
+                        break Err(e);
+                    }
+                }
+                res => break res,
+            }
+        };
+        res.map_err(|e| e.in_queue(self.path()))
+    }
+
+    // Runs `f` in a write transaction and commits it. If we run out of space,
+    // the map is grown and `f` is retried in a fresh transaction.
+    fn write<T, F>(&self, mut f: F) -> Result<T>
+        where F: FnMut(&WriteTransaction) -> Result<T>
+    {
+        let res = loop {
+            let (seen_size, res) = {
+                let _guard = self.inner.resize_lock.read().expect("resize lock poisoned");
+                let seen_size = match self.env().info() {
+                    Ok(info) => info.mapsize,
+                    Err(e) => break Err(e.into()),
+                };
+                let res = WriteTransaction::new(self.env()).map_err(Error::from).and_then(|txn| {
+                    let val = try!(f(&txn));
+                    let started = Instant::now();
+                    try!(txn.commit());
+                    self.inner.counters.committed(started.elapsed());
+                    Ok(val)
+                });
+                (seen_size, res)
+            };
+            match res {
+                Err(ref e) if is_map_full(e) => {
+                    match self.grow_map(seen_size) {
+                        Ok(true) => (),
+                        Ok(false) => break res,
+                        Err(e) => break Err(e),
+                    }
+                }
+                Err(ref e) if mdb_code(e) == Some(error::MAP_RESIZED) => {
+                    if let Err(e) = self.adopt_map_size() {
+                        break Err(e);
+                    }
+                }
+                res => break res,
+            }
+        };
+        res.map_err(|e| e.in_queue(self.path()))
+    }
+
+    // Doubles the map, unless another thread already grew it past
+    // `seen_size`. Returns false if we have reached `max_map_size`.
+    fn grow_map(&self, seen_size: usize) -> Result<bool> {
+        let _guard = self.inner.resize_lock.write().expect("resize lock poisoned");
+        let current = try!(self.env().info()).mapsize;
+        if current > seen_size {
+            debug!("Map already grown from {:?} to {:?}", seen_size, current);
+            return Ok(true);
+        }
+        let mut new_size = current.saturating_mul(2);
+        if let Some(max) = self.inner.max_map_size {
+            new_size = cmp::min(new_size, max);
+        }
+        if new_size <= current {
+            warn!("Map full at maximum size: {:?}", current);
+            return Ok(false);
+        }
+        info!("Growing map from {:?} to {:?}", current, new_size);
+        unsafe { try!(self.env().set_mapsize(new_size)) };
+        self.inner.counters.map_grown();
+        Ok(true)
+    }
+
+    fn adopt_map_size(&self) -> Result<()> {
+        let _guard = self.inner.resize_lock.write().expect("resize lock poisoned");
+        // A size of zero picks up whatever size is recorded in the
+        // environment by whoever grew it.
+        unsafe { try!(self.env().set_mapsize(0)) };
+        debug!("Adopted map size: {:?}", try!(self.env().info()).mapsize);
+        Ok(())
+    }
+
+    /// Deletes every entry up to and including offset `limit`, returning
+    /// how many there were.
+    pub fn discard_upto(&self, limit: u64) -> Result<u64> {
+        debug!("Discard upto: {:?}", limit);
+        let db = self.data_db();
+        let meta = self.producers_db();
+        let discarded = try!(self.write(|txn| {
+            debug!("open cursor for trim {:?}", self.path());
+            let mut discarded = 0;
+            let mut discarded_bytes = 0;
+            let mut cursor = try!(txn.cursor(db));
+            let mut accessor = txn.access();
+
+            let mut offset = {
+                if let Some((k, v)) = try!(mdb_maybe(cursor.first::<[u8], [u8]>(&accessor))) {
+                    Some((try!(decode_data_key(k, 0)), envelope::payload_len(v)))
+                } else {
+                    None
+                }
+            };
+
+            while let Some((candidate, len)) = offset {
+                debug!("candidate: {:?}", candidate);
+                if candidate > limit {
+                    break;
+                }
+                trace!("Discard: {:?}", candidate);
+                try!(cursor.del(&mut accessor, del::Flags::empty()));
+                discarded += 1;
+                discarded_bytes += len as u64;
+
+                offset = {
+                    if let Some((k, v)) = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&accessor))) {
+                        Some((try!(decode_data_key(k, candidate)), envelope::payload_len(v)))
+                    } else {
+                        None
+                    }
+                };
+            }
+
+            if discarded > 0 {
+                try!(totals::remove(meta, &mut accessor, discarded, discarded_bytes));
+            }
+            Ok(discarded)
+        }));
+        self.inner.counters.trimmed(discarded);
+        Ok(discarded)
+    }
+
+    /// Every consumer's committed offset.
+    pub fn consumers(&self) -> Result<BTreeMap<String, u64>> {
+        let db = self.consumers_db();
+        self.read(|txn| {
+            let mut ret = BTreeMap::new();
+
+            debug!("open cursor for consumers in {:?}", self.path());
+            let mut cursor = try!(txn.cursor(db));
+            let accessor = txn.access();
+            let mut curr = try!(mdb_maybe(cursor.first(&accessor)));
+            debug!("First: {:?}", curr);
+            while let Some(kv) = curr {
+                let (k, v): (&str, &[u8]) = kv;
+                let offset = try!(decode_offset(v, k));
+                ret.insert(k.to_string(), offset);
+                curr = try!(mdb_maybe(cursor.next(&accessor)));
+                debug!("Next: {:?}", curr);
+            }
+
+            Ok(ret)
+        })
+    }
+
+    /// The on-disk layout version recorded in the queue.
+    pub fn format_version(&self) -> Result<u64> {
+        format::stored_version(self)
+    }
+
+    /// The path the queue was opened at.
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    fn env(&self) -> &Environment {
+        &self.inner.env
+    }
+    fn producers_db(&self) -> &Database<'static> {
+        &self.inner.producers
+    }
+    fn consumers_db(&self) -> &Database<'static> {
+        &self.inner.consumers
+    }
+    fn data_db(&self) -> &Database<'static> {
+        &self.inner.data
+    }
+}
+
 impl Producer {
-    pub fn new<P: AsRef<str>>(place: P) -> Result<Self> {
+    /// Opens a private environment at `place`. Prefer `Queue::producer` when
+    /// the same process also consumes from the queue.
+    pub fn new<P: AsRef<Path>>(place: P) -> Result<Self> {
         debug!("Producer Open env at: {:?}", place.as_ref());
-        let mut b = try!(EnvBuilder::new());
-        try!(b.set_maxdbs(3));
-        try!(b.set_mapsize(ARBITARILY_LARGE));
-        let env = unsafe { try!(b.open(place.as_ref(), open::Flags::empty(), 0o777)) };
-
-        Ok(Producer { env: env })
+        let queue = try!(Queue::open(place));
+        Ok(queue.producer())
     }
-    fn meta(&self) -> Result<Database> {
-        Ok(try!(open_db(&self.env, PRODUCER_OFFSETS)))
+
+    /// Appends `msg` to the queue, returning the offset it was given.
+    pub fn produce(&mut self, msg: &[u8]) -> Result<u64> {
+        let offsets = try!(self.produce_batch(&[msg]));
+        Ok(offsets[0])
     }
-    fn data(&self) -> Result<Database> {
-        Ok(try!(open_db(&self.env, DATA)))
+
+    /// Appends all of `msgs` in a single transaction, so that either all or
+    /// none of them are stored, returning the offsets they were given.
+    pub fn produce_batch<M: AsRef<[u8]>>(&mut self, msgs: &[M]) -> Result<Vec<u64>> {
+        if msgs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let started = Instant::now();
+        let meta = self.queue.producers_db();
+        let data = self.queue.data_db();
+        let mut records = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            records.push(try!(envelope::seal(msg.as_ref())));
+        }
+        let bytes = msgs.iter().map(|msg| msg.as_ref().len()).sum::<usize>();
+        let offsets = try!(self.queue.write(|txn| {
+            let mut acc = txn.access();
+            if let Some(leader) = try!(replication::leader_of(meta, &acc)) {
+                return Err(ErrorKind::Following { leader }.into());
+            }
+            let mut offset = try!(read_offset(meta, &acc, WRITER_NEXT));
+            let mut offsets = Vec::with_capacity(records.len());
+            for record in &records {
+                // Move to next slot.
+                offset += 1;
+                try!(put_record(data, &mut acc, offset, record));
+                trace!("wrote: {:?}", record);
+                offsets.push(offset);
+            }
+            try!(write_offset(meta, &mut acc, WRITER_NEXT, offset));
+            try!(totals::add(meta, &mut acc, records.len() as u64, bytes as u64));
+            debug!("Produced at offsets: {:?}", offsets);
+            Ok(offsets)
+        }));
+        self.queue.inner.counters.produced(msgs.len(), bytes, started.elapsed());
+        try!(self.committed(offsets[offsets.len() - 1]));
+        Ok(offsets)
     }
 
-    pub fn produce(&mut self, msg: &[u8]) -> Result<()> {
-        let meta = try!(self.meta());
-        let data = try!(self.data());
-        let txn = try!(WriteTransaction::new(&self.env));
-        {
+    /// Stores `msg` at exactly `offset`, for tools that copy records between
+    /// queues and must keep their offsets. Fails with `OffsetConflict` if
+    /// there is already a record there. `writer-next` is moved up to
+    /// `offset` if it is behind, so later calls to `produce` carry on after
+    /// it; offsets skipped over are left as a gap. A record written into
+    /// such a gap later on is only seen by consumers that have not yet read
+    /// past it: those that have never go back for it.
+    pub fn produce_at(&mut self, offset: u64, msg: &[u8]) -> Result<()> {
+        if offset == 0 {
+            let msg = "offset 0 is never used";
+            return Err(Error::from(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                           .in_queue(self.queue.path()));
+        }
+        let started = Instant::now();
+        let meta = self.queue.producers_db();
+        let data = self.queue.data_db();
+        let record = try!(envelope::seal(msg));
+        try!(self.queue.write(|txn| {
             let mut acc = txn.access();
-            // Move to next slot.
-            let offset = try!(read_offset(&meta, &acc, WRITER_NEXT)) + 1;
-            let key = try!(encode_key(offset));
-            try!(acc.put(&data, &key, msg, put::NOOVERWRITE));
-            trace!("wrote: {:?}", msg);
-            try!(write_offset(&meta, &mut acc, WRITER_NEXT, offset));
+            if let Some(leader) = try!(replication::leader_of(meta, &acc)) {
+                return Err(ErrorKind::Following { leader }.into());
+            }
+            try!(put_record(data, &mut acc, offset, &record));
+            if try!(read_offset(meta, &acc, WRITER_NEXT)) < offset {
+                try!(write_offset(meta, &mut acc, WRITER_NEXT, offset));
+            }
+            try!(totals::add(meta, &mut acc, 1, msg.len() as u64));
             debug!("Produced at offset: {:?}", offset);
-        }
-        try!(txn.commit());
+            Ok(())
+        }));
+        self.queue.inner.counters.produced(1, msg.len(), started.elapsed());
+        self.committed(offset)
+    }
 
+    // Wakes local readers once records up to `last` are committed, then
+    // waits for a follower to acknowledge them, if we have been asked to.
+    fn committed(&self, last: u64) -> Result<()> {
+        self.queue.inner.notifier.notify();
+        if let Some(timeout) = self.queue.inner.follower_ack_timeout {
+            if !self.queue.inner.follower_acks.wait(last, timeout) {
+                let e = Error::from(ErrorKind::ReplicationTimeout { offset: last });
+                return Err(e.in_queue(self.queue.path()));
+            }
+        }
         Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct Consumer {
-    env: Environment,
+    queue: Queue,
     name: String,
     offset: u64,
 }
@@ -117,57 +594,77 @@ pub struct Entry {
 }
 
 
-fn open_db<'a>(env: &'a Environment, name: &str) -> Result<Database<'a>> {
-    let db = try!(Database::open(env,
+fn open_db(env: &Arc<Environment>, name: &str) -> Result<Database<'static>> {
+    let db = try!(Database::open(env.clone(),
                                  Some(name),
                                  &lmdb_zero::DatabaseOptions::new(lmdb_zero::db::CREATE)));
     Ok(db)
 }
 impl Consumer {
-    pub fn new<P: AsRef<str>>(place: P, name: &str) -> Result<Self> {
+    /// Opens a private environment at `place`. Prefer `Queue::consumer` when
+    /// the same process also produces to the queue.
+    pub fn new<P: AsRef<Path>>(place: P, name: &str) -> Result<Self> {
         debug!("Consumer Open env at: {:?}", place.as_ref());
-        let mut b = try!(EnvBuilder::new());
-        try!(b.set_maxdbs(3));
-        try!(b.set_mapsize(ARBITARILY_LARGE));
-        let env = unsafe { try!(b.open(place.as_ref(), open::Flags::empty(), 0o777)) };
-        let offset = {
-            let meta = try!(open_db(&env, CONSUMER_OFFSETS));
-            let txn = try!(ReadTransaction::new(&env));
-            try!(read_offset(&meta, &txn.access(), name))
-        };
-
-        Ok(Consumer {
-            env: env,
-            name: name.to_string(),
-            offset: offset,
-        })
+        let queue = try!(Queue::open(place));
+        queue.consumer(name)
     }
 
-    fn meta(&self) -> Result<Database> {
-        Ok(try!(open_db(&self.env, CONSUMER_OFFSETS)))
-    }
-    fn data(&self) -> Result<Database> {
-        Ok(try!(open_db(&self.env, DATA)))
+    /// Returns the next entry after our current position, if any.
+    ///
+    /// If we have a position, and the entries following it have since been
+    /// trimmed away, this fails with `TrimmedGap` and moves on to the
+    /// earliest remaining entry, which the next call returns.
+    pub fn poll(&mut self) -> Result<Option<Entry>> {
+        let res = self.read_next();
+        if let Ok(ref entry) = res {
+            self.queue.inner.counters.polled(entry.is_some());
+        }
+        res
     }
 
-    pub fn poll(&mut self) -> Result<Option<Entry>> {
-        let entry = {
-            let data = try!(self.data());
-            let txn = try!(ReadTransaction::new(&self.env));
-            let next_offset = self.offset + 1;
-            let key = try!(encode_key(next_offset));
+    // `poll`, without counting it.
+    fn read_next(&mut self) -> Result<Option<Entry>> {
+        let data = self.queue.data_db();
+        let next_offset = self.offset + 1;
+        let key = try!(encode_key(next_offset));
+        let has_position = self.offset > 0;
+        let res = self.queue.read(|txn| {
             debug!("open cursor for {:?}", self);
-            let mut cursor = try!(txn.cursor(&data).chain_err(|| "get cursor"));
+            let mut cursor = try!(txn.cursor(data));
+            let access = txn.access();
             debug!("Attempt read from: {:?}", next_offset);
-            match try!(mdb_maybe(cursor.seek_range_k::<[u8], [u8]>(&txn.access(), &key))) {
+            match try!(mdb_maybe(cursor.seek_range_k::<[u8], [u8]>(&access, &key))) {
                 Some((k, v)) => {
-                    let off = try!(decode_key(k));
-                    Entry {
+                    let off = try!(decode_data_key(k, next_offset));
+                    let entry = Entry {
                         offset: off,
-                        data: v.to_vec(),
+                        data: try!(envelope::unseal(off, v)).to_vec(),
+                    };
+                    let is_first = try!(mdb_maybe(cursor.prev::<[u8], [u8]>(&access))).is_none();
+                    if has_position && off > next_offset && is_first {
+                        return Err(ErrorKind::TrimmedGap {
+                                       expected: next_offset,
+                                       available: off,
+                                   }
+                                   .into());
                     }
+                    Ok(Some(entry))
                 }
-                None => return Ok(None),
+                None => Ok(None),
+            }
+        });
+        let entry = match res {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                if let ErrorKind::TrimmedGap { expected, available } = *e.kind() {
+                    warn!("{:?}: offsets {:?} to {:?} were trimmed before being read",
+                          self.name,
+                          expected,
+                          available - 1);
+                    self.offset = available - 1;
+                }
+                return Err(e.for_consumer(&self.name));
             }
         };
         trace!("read {:?}", entry);
@@ -176,92 +673,59 @@ impl Consumer {
         Ok(Some(entry))
     }
 
-    pub fn commit_upto(&self, entry: &Entry) -> Result<()> {
-        let meta = try!(self.meta());
-        let txn = try!(WriteTransaction::new(&self.env));
-        try!(write_offset(&meta, &mut txn.access(), &self.name, entry.offset));
-        try!(txn.commit());
-        Ok(())
-    }
-
-    pub fn discard_upto(&self, limit: u64) -> Result<()> {
-        debug!("Discard upto: {:?}", limit);
-        let db = try!(self.data());
-        // The transaction can be used for database created /before/ the txn,
-        // so ensure we create the db before the txn. Otherwise, lmdb returns
-        // the helpful `-EINVAL`.
-        let txn = try!(WriteTransaction::new(&self.env));
-        {
-            debug!("open cursor for trim {:?}", self);
-            let mut cursor = try!(txn.cursor(&db).chain_err(|| "get cursor"));
-            let mut accessor = txn.access();
-
-            let mut offset = {
-                if let Some((k, _)) = try!(mdb_maybe(cursor.first::<[u8], [u8]>(&accessor))) {
-                    Some(try!(decode_key(k)))
-                } else {
-                    None
-                }
-            };
-
-            while let Some(candidate) = offset {
-                debug!("candidate: {:?}", candidate);
-                if candidate > limit {
-                    break;
-                }
-                trace!("Discard: {:?}", candidate);
-                try!(cursor.del(&mut accessor, del::Flags::empty()));
-
-                offset = {
-                    if let Some((k, _)) = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&accessor))) {
-                        Some(try!(decode_key(k)))
-                    } else {
-                        None
-                    }
-                };
+    /// Like `poll`, but if there is nothing to read, waits up to `timeout`
+    /// for something to be produced. A timeout too long to represent waits
+    /// until something is.
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Result<Option<Entry>> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let seen = self.queue.inner.notifier.generation();
+            if let Some(entry) = try!(self.read_next()) {
+                self.queue.inner.counters.polled(true);
+                return Ok(Some(entry));
+            }
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                self.queue.inner.counters.polled(false);
+                return Ok(None);
             }
+            // Producers in other processes don't notify us.
+            self.queue.inner.notifier.wait(seen, recheck_after(deadline, now));
         }
+    }
 
-        try!(txn.commit());
+    pub fn commit_upto(&self, entry: &Entry) -> Result<()> {
+        self.commit_offset(entry.offset)
+    }
 
-        Ok(())
+    fn commit_offset(&self, offset: u64) -> Result<()> {
+        let meta = self.queue.consumers_db();
+        self.queue
+            .write(|txn| write_offset(meta, &mut txn.access(), &self.name, offset))
+            .map_err(|e| self.in_context(e))
     }
 
+    fn in_context(&self, e: Error) -> Error {
+        e.in_queue(self.queue.path()).for_consumer(&self.name)
+    }
 
-    pub fn consumers(&self) -> Result<BTreeMap<String, u64>> {
-        let db = try!(self.meta());
-        // The transaction can be used for database created /before/ the txn,
-        // so ensure we create the db before the txn. Otherwise, lmdb returns
-        // the helpful `-EINVAL`.
-        let txn = try!(ReadTransaction::new(&self.env));
-        let mut ret = BTreeMap::new();
-
-        debug!("open cursor for {:?}", self);
-        let mut cursor = try!(txn.cursor(&db).chain_err(|| "get cursor"));
-        let accessor = txn.access();
-        let mut curr = try!(mdb_maybe(cursor.first(&accessor)));
-        debug!("First: {:?}", curr);
-        while let Some(kv) = curr {
-            let (k, v): (&str, &[u8]) = kv;
-            let offset = try!(decode_key(v));
-            ret.insert(k.to_string(), offset);
-            curr = try!(mdb_maybe(cursor.next(&accessor)));
-            debug!("Next: {:?}", curr);
-        }
+    pub fn discard_upto(&self, limit: u64) -> Result<u64> {
+        self.queue.discard_upto(limit)
+    }
 
-        Ok(ret)
+    pub fn consumers(&self) -> Result<BTreeMap<String, u64>> {
+        self.queue.consumers()
     }
 
     pub fn clear_offset(&mut self) -> Result<()> {
-        let db = try!(self.meta());
-        // The transaction can be used for database created /before/ the txn,
-        // so ensure we create the db before the txn. Otherwise, lmdb returns
-        // the helpful `-EINVAL`.
-        let txn = try!(WriteTransaction::new(&self.env));
-
-        try!(mdb_maybe(txn.access().del_key(&db, &*self.name)));
-        try!(txn.commit());
-        Ok(())
+        let db = self.queue.consumers_db();
+        let name = &self.name;
+        self.queue
+            .write(|txn| {
+                try!(mdb_maybe(txn.access().del_key(db, &**name)));
+                Ok(())
+            })
+            .map_err(|e| e.for_consumer(name))
     }
 }
 
@@ -269,7 +733,18 @@ fn mdb_maybe<T>(res: ::std::result::Result<T, lmdb_zero::Error>)
                 -> ::std::result::Result<Option<T>, lmdb_zero::Error> {
     match res {
         Ok(kv) => Ok(Some(kv)),
-        Err(e) if e.code == error::NOTFOUND => Ok(None),
+        Err(error::Error::Code(error::NOTFOUND)) => Ok(None),
         Err(e) => Err(e),
     }
 }
+
+fn mdb_code(e: &Error) -> Option<c_int> {
+    match *e.kind() {
+        ErrorKind::Mdb(error::Error::Code(code)) => Some(code),
+        _ => None,
+    }
+}
+
+fn is_map_full(e: &Error) -> bool {
+    matches!(*e.kind(), ErrorKind::MapFull)
+}
@@ -0,0 +1,332 @@
+//! Counters kept by each `Queue`, and their export in the Prometheus text
+//! format.
+//!
+//! Counters and histograms cover what this process has done since it opened
+//! the queue; other processes sharing the queue keep their own. Gauges, such
+//! as map usage and consumer offsets, are read from the queue itself, so
+//! reflect every process.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use errors::Result;
+use Queue;
+
+// Upper bounds of the latency histogram buckets, in microseconds.
+const BUCKETS: [u64; 12] = [500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000,
+                            1000000, 2500000];
+// Requests with larger heads are refused.
+const MAX_HEAD: u64 = 64 << 10;
+
+/// A snapshot of a queue's metrics, from `Queue::metrics`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Metrics {
+    pub produced_messages: u64,
+    pub produced_bytes: u64,
+    /// How long each call to produce took, including its commit.
+    pub produce_latency: Histogram,
+    /// Calls to `Consumer::poll` or `poll_timeout`, and those that found
+    /// nothing.
+    pub polls: u64,
+    pub empty_polls: u64,
+    /// How long each write transaction took to commit.
+    pub commit_latency: Histogram,
+    /// Calls to `discard_upto`, and the records they removed.
+    pub trims: u64,
+    pub trimmed_messages: u64,
+    /// How often the map was found full and grown.
+    pub map_grows: u64,
+    /// The size of the memory map, and how much of it is in use.
+    pub map_size: u64,
+    pub map_used: u64,
+    /// The last offset handed out to a producer.
+    pub writer_next: u64,
+    /// Every consumer's committed offset.
+    pub consumers: BTreeMap<String, u64>,
+}
+
+/// Latencies, counted into buckets.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Histogram {
+    /// The upper bound of each bucket in seconds, and how many
+    /// observations were no larger.
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    /// The sum of all observations, in seconds.
+    pub sum: f64,
+}
+
+// The live counters behind `Metrics`.
+#[derive(Debug,Default)]
+pub struct Counters {
+    produced_messages: AtomicU64,
+    produced_bytes: AtomicU64,
+    produce_latency: LatencyHistogram,
+    polls: AtomicU64,
+    empty_polls: AtomicU64,
+    commit_latency: LatencyHistogram,
+    trims: AtomicU64,
+    trimmed_messages: AtomicU64,
+    map_grows: AtomicU64,
+}
+
+impl Counters {
+    pub fn produced(&self, messages: usize, bytes: usize, took: Duration) {
+        self.produced_messages.fetch_add(messages as u64, Ordering::Relaxed);
+        self.produced_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.produce_latency.observe(took);
+    }
+
+    pub fn polled(&self, found: bool) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.empty_polls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn committed(&self, took: Duration) {
+        self.commit_latency.observe(took);
+    }
+
+    pub fn trimmed(&self, messages: u64) {
+        self.trims.fetch_add(1, Ordering::Relaxed);
+        self.trimmed_messages.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    pub fn map_grown(&self) {
+        self.map_grows.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug,Default)]
+struct LatencyHistogram {
+    // Not cumulative; each observation is counted in just the one bucket.
+    buckets: [AtomicU64; 12],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn observe(&self, took: Duration) {
+        let micros = took.as_secs() * 1000000 + took.subsec_micros() as u64;
+        if let Some(i) = BUCKETS.iter().position(|&bound| micros <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let mut total = 0;
+        let mut buckets = Vec::with_capacity(BUCKETS.len());
+        for (bound, count) in BUCKETS.iter().zip(&self.buckets) {
+            total += count.load(Ordering::Relaxed);
+            buckets.push((*bound as f64 / 1e6, total));
+        }
+        Histogram {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        }
+    }
+}
+
+impl Queue {
+    /// The queue's counters, and gauges read from the queue as it stands.
+    pub fn metrics(&self) -> Result<Metrics> {
+        let info = try!(self.env().info());
+        let page_size = try!(self.env().stat()).psize as u64;
+        let counters = &self.inner.counters;
+        Ok(Metrics {
+            produced_messages: counters.produced_messages.load(Ordering::Relaxed),
+            produced_bytes: counters.produced_bytes.load(Ordering::Relaxed),
+            produce_latency: counters.produce_latency.snapshot(),
+            polls: counters.polls.load(Ordering::Relaxed),
+            empty_polls: counters.empty_polls.load(Ordering::Relaxed),
+            commit_latency: counters.commit_latency.snapshot(),
+            trims: counters.trims.load(Ordering::Relaxed),
+            trimmed_messages: counters.trimmed_messages.load(Ordering::Relaxed),
+            map_grows: counters.map_grows.load(Ordering::Relaxed),
+            map_size: info.mapsize as u64,
+            map_used: (info.last_pgno as u64 + 1) * page_size,
+            writer_next: try!(self.writer_next()),
+            consumers: try!(self.consumers()),
+        })
+    }
+}
+
+// Picks a value out of `Metrics` for a metric family.
+type Reading = fn(&Metrics) -> u64;
+type HistogramReading = fn(&Metrics) -> &Histogram;
+
+/// Renders the metrics of each named queue in the Prometheus text format,
+/// labelling each sample with `queue`.
+pub fn render_metrics(queues: &[(&str, &Metrics)]) -> String {
+    let mut out = String::new();
+    let counters: [(&str, &str, Reading); 7] =
+        [("lmqueue_produced_messages_total", "Messages produced.", |m| m.produced_messages),
+         ("lmqueue_produced_bytes_total", "Message bytes produced.", |m| m.produced_bytes),
+         ("lmqueue_polls_total", "Consumer polls.", |m| m.polls),
+         ("lmqueue_empty_polls_total", "Consumer polls that found nothing.", |m| m.empty_polls),
+         ("lmqueue_trims_total", "Calls to discard old records.", |m| m.trims),
+         ("lmqueue_trimmed_messages_total", "Records discarded.", |m| m.trimmed_messages),
+         ("lmqueue_map_grows_total", "Times the memory map was grown.", |m| m.map_grows)];
+    for &(name, help, value) in &counters {
+        family(&mut out, name, help, "counter");
+        for &(queue, metrics) in queues {
+            sample(&mut out, name, &[("queue", queue)], value(metrics) as f64);
+        }
+    }
+    let histograms: [(&str, &str, HistogramReading); 2] =
+        [("lmqueue_produce_duration_seconds",
+          "Time taken to produce, including the commit.",
+          |m| &m.produce_latency),
+         ("lmqueue_commit_duration_seconds",
+          "Time taken to commit write transactions.",
+          |m| &m.commit_latency)];
+    for &(name, help, value) in &histograms {
+        family(&mut out, name, help, "histogram");
+        for &(queue, metrics) in queues {
+            let histogram = value(metrics);
+            let bucket = format!("{}_bucket", name);
+            for &(bound, count) in &histogram.buckets {
+                sample(&mut out,
+                       &bucket,
+                       &[("queue", queue), ("le", &bound.to_string())],
+                       count as f64);
+            }
+            sample(&mut out,
+                   &bucket,
+                   &[("queue", queue), ("le", "+Inf")],
+                   histogram.count as f64);
+            sample(&mut out, &format!("{}_sum", name), &[("queue", queue)], histogram.sum);
+            sample(&mut out,
+                   &format!("{}_count", name),
+                   &[("queue", queue)],
+                   histogram.count as f64);
+        }
+    }
+    let gauges: [(&str, &str, Reading); 3] =
+        [("lmqueue_map_size_bytes", "Size of the memory map.", |m| m.map_size),
+         ("lmqueue_map_used_bytes", "Bytes of the memory map in use.", |m| m.map_used),
+         ("lmqueue_writer_next", "Last offset handed out to a producer.", |m| m.writer_next)];
+    for &(name, help, value) in &gauges {
+        family(&mut out, name, help, "gauge");
+        for &(queue, metrics) in queues {
+            sample(&mut out, name, &[("queue", queue)], value(metrics) as f64);
+        }
+    }
+    let name = "lmqueue_consumer_offset";
+    family(&mut out, name, "Consumer's committed offset.", "gauge");
+    for &(queue, metrics) in queues {
+        for (consumer, &offset) in &metrics.consumers {
+            sample(&mut out, name, &[("queue", queue), ("consumer", consumer)], offset as f64);
+        }
+    }
+    out
+}
+
+fn family(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels = labels.iter()
+                       .map(|l| format!("{}=\"{}\"", l.0, escape_label(l.1)))
+                       .collect::<Vec<_>>();
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics of one or more queues to Prometheus, at
+/// `GET /metrics`. Each connection is handled on its own thread.
+#[derive(Debug)]
+pub struct MetricsServer {
+    queues: Vec<(String, Queue)>,
+    listener: TcpListener,
+}
+
+impl MetricsServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<MetricsServer> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(MetricsServer {
+            queues: Vec::new(),
+            listener,
+        })
+    }
+
+    /// Reports on `queue`, labelled as `name`.
+    pub fn queue(mut self, name: &str, queue: &Queue) -> Self {
+        self.queues.push((name.to_string(), queue.clone()));
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            debug!("Accepted metrics connection from {:?}", peer);
+            let queues = self.queues.clone();
+            try!(thread::Builder::new()
+                     .name("lmqueue-metrics".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&queues, stream) {
+                             warn!("Metrics connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+fn serve(queues: &[(String, Queue)], stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(try!(stream.try_clone()).take(MAX_HEAD));
+    let mut out = stream;
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    let target = {
+        let mut parts = line.split_whitespace();
+        (parts.next(), parts.next())
+    };
+    // Drain the rest of the head; we don't need any of it.
+    loop {
+        let mut header = String::new();
+        if try!(reader.read_line(&mut header)) == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let (status, content_type, body) = match target {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            let mut snapshots = Vec::with_capacity(queues.len());
+            for queue in queues {
+                snapshots.push((&queue.0[..], try!(queue.1.metrics())));
+            }
+            let refs = snapshots.iter().map(|s| (s.0, &s.1)).collect::<Vec<_>>();
+            ("200 OK", "text/plain; version=0.0.4", render_metrics(&refs))
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    try!(write!(out,
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: \
+                 close\r\n\r\n",
+                status,
+                content_type,
+                body.len()));
+    try!(out.write_all(body.as_bytes()));
+    try!(out.flush());
+    Ok(())
+}
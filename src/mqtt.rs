@@ -0,0 +1,710 @@
+//! An MQTT 3.1.1 broker that keeps published messages in queues, so that
+//! persistent sessions survive restarts of the broker.
+//!
+//! Messages are routed to a queue by matching their topic against each
+//! route's topic filter in turn; messages no route matches are dropped.
+//! Each is stored as its QoS (one byte) and topic (a `u16` length and UTF-8
+//! name), followed by the payload. Entries in any other form are skipped.
+//!
+//! Every subscription is a named consumer in each routed queue, called
+//! `mqtt:<client id>`, a NUL, the granted QoS as a digit, then the filter.
+//! A QoS 1 delivery is committed once it and every delivery before it have
+//! been acknowledged, so anything unacknowledged when a connection is lost
+//! is delivered again once the client reconnects. Clean sessions remove
+//! their consumers when they end.
+//!
+//! QoS 2 publishes are accepted, but stored and delivered as QoS 1, so may
+//! be duplicated. Retained messages and authentication aren't supported, and
+//! a message matching several of a client's subscriptions is delivered once
+//! for each.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use errors::Result;
+use {Consumer, Queue, RECHECK_INTERVAL};
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const ACCEPTED: u8 = 0;
+const UNACCEPTABLE_PROTOCOL: u8 = 1;
+const IDENTIFIER_REJECTED: u8 = 2;
+const SUBSCRIPTION_FAILED: u8 = 0x80;
+
+const CONSUMER_PREFIX: &str = "mqtt:";
+// Largest packet we'll accept, as MQTT's remaining length allows.
+const MAX_PACKET: usize = 268435455;
+// Unacknowledged QoS 1 deliveries allowed per connection.
+const MAX_INFLIGHT: usize = 64;
+// Entries read from a queue at a time when delivering.
+const DELIVERY_BATCH: usize = 64;
+// How long a new connection has to send CONNECT.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Numbers the IDs given to clients that don't supply one.
+static NEXT_ANONYMOUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Serves queues to MQTT clients, as described in `mqtt`. Each connection
+/// is handled on its own thread.
+#[derive(Debug)]
+pub struct MqttServer {
+    routes: Vec<(String, Queue)>,
+    listener: TcpListener,
+}
+
+impl MqttServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<MqttServer> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(MqttServer {
+            routes: Vec::new(),
+            listener,
+        })
+    }
+
+    /// Stores messages whose topics match `filter` in `queue`, unless an
+    /// earlier route matched them.
+    pub fn route(mut self, filter: &str, queue: &Queue) -> Self {
+        self.routes.push((filter.to_string(), queue.clone()));
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        let mut queues: Vec<Queue> = Vec::new();
+        for route in &self.routes {
+            if !queues.iter().any(|q| q.path() == route.1.path()) {
+                queues.push(route.1.clone());
+            }
+        }
+        let broker = Arc::new(Broker {
+            routes: self.routes.clone(),
+            queues,
+            clients: Mutex::new(HashMap::new()),
+        });
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            try!(stream.set_nodelay(true));
+            debug!("Accepted connection from {:?}", peer);
+            let broker = broker.clone();
+            try!(thread::Builder::new()
+                     .name("lmqueue-mqtt".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&broker, stream) {
+                             warn!("Connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+struct Broker {
+    routes: Vec<(String, Queue)>,
+    // Every queue routed to, once each.
+    queues: Vec<Queue>,
+    // Connected clients, each with a flag telling it to give way to a newer
+    // connection with the same client ID.
+    clients: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+struct Packet {
+    kind: u8,
+    flags: u8,
+    body: Vec<u8>,
+}
+
+struct Connect {
+    client_id: String,
+    clean_session: bool,
+    keep_alive: Duration,
+    will: Option<(String, Vec<u8>, u8)>,
+}
+
+fn serve(broker: &Broker, mut stream: TcpStream) -> Result<()> {
+    try!(stream.set_read_timeout(Some(RECHECK_INTERVAL)));
+    let mut reader = PacketReader { buf: Vec::new() };
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let packet = loop {
+        if let Some(packet) = try!(reader.next(&mut stream)) {
+            break packet;
+        }
+        if Instant::now() >= deadline {
+            return Err(invalid("no CONNECT received").into());
+        }
+    };
+    if packet.kind != CONNECT {
+        return Err(invalid("expected CONNECT").into());
+    }
+    let connect = match try!(parse_connect(&packet.body)) {
+        Ok(connect) => connect,
+        Err(code) => {
+            try!(write_packet(&mut stream, CONNACK << 4, &[0, code]));
+            return Ok(());
+        }
+    };
+    debug!("Client {:?} connected", connect.client_id);
+
+    let replaced = Arc::new(AtomicBool::new(false));
+    if let Some(old) = broker.clients
+                             .lock()
+                             .expect("client registry poisoned")
+                             .insert(connect.client_id.clone(), replaced.clone()) {
+        old.store(true, Ordering::SeqCst);
+    }
+    let mut session = Session {
+        broker,
+        client_id: connect.client_id.clone(),
+        cursors: Vec::new(),
+        next_packet_id: 1,
+    };
+    let present = try!(session.start(connect.clean_session));
+    try!(write_packet(&mut stream, CONNACK << 4, &[present as u8, ACCEPTED]));
+
+    let res = session.run(&mut stream, &mut reader, &connect, &replaced);
+    let graceful = match res {
+        Ok(graceful) => graceful,
+        Err(ref e) => {
+            debug!("Client {:?} lost: {}", connect.client_id, e);
+            false
+        }
+    };
+    if !graceful {
+        if let Some((ref topic, ref payload, qos)) = connect.will {
+            if let Err(e) = broker.publish(topic, payload, qos) {
+                warn!("Publishing will of {:?} failed: {}", connect.client_id, e);
+            }
+        }
+    }
+    {
+        let mut clients = broker.clients.lock().expect("client registry poisoned");
+        if clients.get(&connect.client_id).map(|c| Arc::ptr_eq(c, &replaced)).unwrap_or(false) {
+            clients.remove(&connect.client_id);
+        }
+    }
+    if connect.clean_session && !replaced.load(Ordering::SeqCst) {
+        try!(session.clear());
+    }
+    debug!("Client {:?} disconnected", connect.client_id);
+    res.map(|_| ())
+}
+
+// The parsed CONNECT, or the return code refusing it.
+fn parse_connect(body: &[u8]) -> io::Result<::std::result::Result<Connect, u8>> {
+    let mut input = Decoder { input: body };
+    let protocol = try!(input.string());
+    let level = try!(input.u8());
+    if protocol != "MQTT" || level != 4 {
+        return Ok(Err(UNACCEPTABLE_PROTOCOL));
+    }
+    let flags = try!(input.u8());
+    let keep_alive = try!(input.u16());
+    let mut client_id = try!(input.string());
+    let clean_session = flags & 0x02 != 0;
+    let will = if flags & 0x04 != 0 {
+        let topic = try!(input.string());
+        let payload = try!(input.binary()).to_vec();
+        Some((topic, payload, cmp::min((flags >> 3) & 0x03, 1)))
+    } else {
+        None
+    };
+    // Usernames and passwords are read past, but not checked.
+    if flags & 0x80 != 0 {
+        try!(input.string());
+    }
+    if flags & 0x40 != 0 {
+        try!(input.binary());
+    }
+    if client_id.is_empty() {
+        if !clean_session {
+            return Ok(Err(IDENTIFIER_REJECTED));
+        }
+        client_id = format!("anon-{}", NEXT_ANONYMOUS.fetch_add(1, Ordering::SeqCst));
+    }
+    if client_id.contains('\0') {
+        return Ok(Err(IDENTIFIER_REJECTED));
+    }
+    Ok(Ok(Connect {
+        client_id,
+        clean_session,
+        keep_alive: Duration::from_secs(keep_alive as u64),
+        will,
+    }))
+}
+
+impl Broker {
+    // Stores a message in the first queue routed to for its topic.
+    fn publish(&self, topic: &str, payload: &[u8], qos: u8) -> Result<()> {
+        match self.routes.iter().find(|route| topic_matches(&route.0, topic)) {
+            Some(route) => {
+                try!(route.1.producer().produce(&encode_message(qos, topic, payload)));
+            }
+            None => debug!("No route for topic {:?}; dropped", topic),
+        }
+        Ok(())
+    }
+}
+
+// A subscription's position in one queue.
+struct Cursor {
+    consumer: Consumer,
+    filter: String,
+    qos: u8,
+    // The last offset read, and the last committed.
+    position: u64,
+    committed: u64,
+    // Offsets delivered at QoS 1 but not yet acknowledged, by packet ID.
+    inflight: BTreeMap<u64, u16>,
+}
+
+impl Cursor {
+    // Commits as far as every delivery has been acknowledged.
+    fn commit(&mut self) -> Result<()> {
+        let upto = match self.inflight.keys().next() {
+            Some(&first) => first - 1,
+            None => self.position,
+        };
+        if upto > self.committed {
+            try!(self.consumer.commit_offset(upto));
+            self.committed = upto;
+        }
+        Ok(())
+    }
+}
+
+struct Session<'a> {
+    broker: &'a Broker,
+    client_id: String,
+    cursors: Vec<Cursor>,
+    next_packet_id: u16,
+}
+
+impl<'a> Session<'a> {
+    // Picks up the session's subscriptions, or clears them away for a clean
+    // session. Returns whether there was a session to pick up.
+    fn start(&mut self, clean: bool) -> Result<bool> {
+        if clean {
+            try!(self.clear());
+            return Ok(false);
+        }
+        let prefix = self.consumer_prefix();
+        for queue in &self.broker.queues {
+            for (name, offset) in try!(queue.consumers()) {
+                if !name.starts_with(&prefix) {
+                    continue;
+                }
+                let sub = &name[prefix.len()..];
+                let qos = match sub.chars().next().and_then(|c| c.to_digit(10)) {
+                    Some(qos) => qos as u8,
+                    None => continue,
+                };
+                self.cursors.push(Cursor {
+                    consumer: try!(queue.consumer(&name)),
+                    filter: sub[1..].to_string(),
+                    qos,
+                    position: offset,
+                    committed: offset,
+                    inflight: BTreeMap::new(),
+                });
+            }
+        }
+        Ok(!self.cursors.is_empty())
+    }
+
+    // Removes every consumer belonging to this client.
+    fn clear(&mut self) -> Result<()> {
+        self.cursors.clear();
+        let prefix = self.consumer_prefix();
+        for queue in &self.broker.queues {
+            for (name, _) in try!(queue.consumers()) {
+                if name.starts_with(&prefix) {
+                    try!(try!(queue.consumer(&name)).clear_offset());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn consumer_prefix(&self) -> String {
+        format!("{}{}\0", CONSUMER_PREFIX, self.client_id)
+    }
+
+    // Handles packets and delivers messages until the client disconnects,
+    // returning whether it did so gracefully.
+    fn run(&mut self,
+           stream: &mut TcpStream,
+           reader: &mut PacketReader,
+           connect: &Connect,
+           replaced: &AtomicBool)
+           -> Result<bool> {
+        let mut last_heard = Instant::now();
+        loop {
+            if replaced.load(Ordering::SeqCst) {
+                debug!("Client {:?} replaced by a newer connection", self.client_id);
+                return Ok(true);
+            }
+            while let Some(packet) = try!(reader.next(stream)) {
+                last_heard = Instant::now();
+                if packet.kind == DISCONNECT {
+                    return Ok(true);
+                }
+                try!(self.handle(stream, packet));
+            }
+            // Clients get half as long again as they asked for.
+            if connect.keep_alive > Duration::from_secs(0) &&
+               last_heard.elapsed() > connect.keep_alive + connect.keep_alive / 2 {
+                return Err(invalid("keep-alive expired").into());
+            }
+            try!(self.deliver(stream));
+        }
+    }
+
+    fn handle(&mut self, stream: &mut TcpStream, packet: Packet) -> Result<()> {
+        let mut input = Decoder { input: &packet.body };
+        match packet.kind {
+            PUBLISH => {
+                let qos = (packet.flags >> 1) & 0x03;
+                let topic = try!(input.string());
+                let packet_id = if qos > 0 { Some(try!(input.u16())) } else { None };
+                if topic.contains(['+', '#']) {
+                    return Err(invalid("wildcard in topic name").into());
+                }
+                try!(self.broker.publish(&topic, input.input, cmp::min(qos, 1)));
+                match (qos, packet_id) {
+                    (1, Some(id)) => try!(write_packet(stream, PUBACK << 4, &u16_bytes(id))),
+                    (2, Some(id)) => try!(write_packet(stream, PUBREC << 4, &u16_bytes(id))),
+                    _ => (),
+                }
+            }
+            PUBACK => {
+                let id = try!(input.u16());
+                for cursor in &mut self.cursors {
+                    let acked = cursor.inflight
+                                      .iter()
+                                      .find(|&(_, &pid)| pid == id)
+                                      .map(|(&offset, _)| offset);
+                    if let Some(offset) = acked {
+                        cursor.inflight.remove(&offset);
+                        try!(cursor.commit());
+                        break;
+                    }
+                }
+            }
+            PUBREL => {
+                let id = try!(input.u16());
+                try!(write_packet(stream, PUBCOMP << 4, &u16_bytes(id)));
+            }
+            SUBSCRIBE => {
+                let id = try!(input.u16());
+                let mut granted = Vec::new();
+                while !input.input.is_empty() {
+                    let filter = try!(input.string());
+                    let qos = cmp::min(try!(input.u8()) & 0x03, 1);
+                    granted.push(if valid_filter(&filter) {
+                        try!(self.subscribe(&filter, qos));
+                        qos
+                    } else {
+                        SUBSCRIPTION_FAILED
+                    });
+                }
+                let mut body = u16_bytes(id).to_vec();
+                body.extend_from_slice(&granted);
+                try!(write_packet(stream, SUBACK << 4, &body));
+            }
+            UNSUBSCRIBE => {
+                let id = try!(input.u16());
+                while !input.input.is_empty() {
+                    let filter = try!(input.string());
+                    try!(self.unsubscribe(&filter));
+                }
+                try!(write_packet(stream, UNSUBACK << 4, &u16_bytes(id)));
+            }
+            PINGREQ => try!(write_packet(stream, PINGRESP << 4, &[])),
+            kind => {
+                return Err(invalid(&format!("unexpected packet type {}", kind)).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&mut self, filter: &str, qos: u8) -> Result<()> {
+        if self.cursors.iter().any(|c| c.filter == filter && c.qos == qos) {
+            return Ok(());
+        }
+        try!(self.unsubscribe(filter));
+        let name = format!("{}{}{}", self.consumer_prefix(), qos, filter);
+        for queue in &self.broker.queues {
+            // Only messages published from now on.
+            let offset = try!(queue.writer_next());
+            let consumer = try!(queue.consumer(&name));
+            try!(consumer.commit_offset(offset));
+            self.cursors.push(Cursor {
+                consumer,
+                filter: filter.to_string(),
+                qos,
+                position: offset,
+                committed: offset,
+                inflight: BTreeMap::new(),
+            });
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, filter: &str) -> Result<()> {
+        let (gone, kept) = self.cursors.drain(..).partition(|c| c.filter == filter);
+        self.cursors = kept;
+        for mut cursor in gone {
+            try!(cursor.consumer.clear_offset());
+        }
+        Ok(())
+    }
+
+    // Sends each subscription whatever has arrived for it, as far as the
+    // limit on unacknowledged deliveries allows.
+    fn deliver(&mut self, stream: &mut TcpStream) -> Result<()> {
+        for i in 0..self.cursors.len() {
+            let inflight = self.cursors.iter().map(|c| c.inflight.len()).sum::<usize>();
+            if inflight >= MAX_INFLIGHT {
+                return Ok(());
+            }
+            let entries = {
+                let cursor = &self.cursors[i];
+                try!(cursor.consumer.queue.entries(cursor.position + 1, DELIVERY_BATCH))
+            };
+            let mut room = MAX_INFLIGHT - inflight;
+            for entry in entries {
+                if room == 0 {
+                    break;
+                }
+                let packet_id = self.next_packet_id;
+                let cursor = &mut self.cursors[i];
+                cursor.position = entry.offset;
+                let (qos, topic, payload) = match decode_message(&entry.data) {
+                    Some(message) => message,
+                    None => {
+                        debug!("Skipping entry {} in {:?}: not an MQTT message",
+                               entry.offset,
+                               cursor.consumer.queue.path());
+                        continue;
+                    }
+                };
+                if !topic_matches(&cursor.filter, topic) {
+                    continue;
+                }
+                let qos = cmp::min(qos, cursor.qos);
+                let mut body = Vec::new();
+                body.extend_from_slice(&u16_bytes(topic.len() as u16));
+                body.extend_from_slice(topic.as_bytes());
+                if qos > 0 {
+                    body.extend_from_slice(&u16_bytes(packet_id));
+                    cursor.inflight.insert(entry.offset, packet_id);
+                    self.next_packet_id = packet_id.checked_add(1).unwrap_or(1);
+                    room -= 1;
+                }
+                body.extend_from_slice(payload);
+                try!(write_packet(stream, PUBLISH << 4 | qos << 1, &body));
+            }
+            try!(self.cursors[i].commit());
+        }
+        Ok(())
+    }
+}
+
+fn encode_message(qos: u8, topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(3 + topic.len() + payload.len());
+    message.push(qos);
+    message.extend_from_slice(&u16_bytes(topic.len() as u16));
+    message.extend_from_slice(topic.as_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+fn decode_message(message: &[u8]) -> Option<(u8, &str, &[u8])> {
+    let mut input = Decoder { input: message };
+    let qos = match input.u8() {
+        Ok(qos) if qos <= 1 => qos,
+        _ => return None,
+    };
+    input.binary()
+         .ok()
+         .and_then(|topic| str::from_utf8(topic).ok())
+         .map(|topic| (qos, topic, input.input))
+}
+
+/// Whether `topic` matches the MQTT topic filter `filter`.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards at the start don't match topics reserved by the server.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (p, Some(level)) if p == level => (),
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn valid_filter(filter: &str) -> bool {
+    let levels = filter.split('/').collect::<Vec<_>>();
+    !filter.is_empty() && !filter.contains('\0') &&
+    levels.iter().enumerate().all(|(i, level)| {
+        match *level {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['+', '#']),
+        }
+    })
+}
+
+fn u16_bytes(v: u16) -> [u8; 2] {
+    let mut b = [0; 2];
+    BigEndian::write_u16(&mut b, v);
+    b
+}
+
+fn write_packet<W: Write>(out: &mut W, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    out.write_all(&packet)
+}
+
+// Reads packets from a stream with a read timeout, keeping any partial
+// packet until the rest arrives.
+struct PacketReader {
+    buf: Vec<u8>,
+}
+
+impl PacketReader {
+    // The next packet, or `None` if one hasn't arrived by the timeout.
+    fn next(&mut self, stream: &mut TcpStream) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = try!(self.parse()) {
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0; 4096];
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client hung up"))
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn parse(&mut self) -> io::Result<Option<Packet>> {
+        let mut len = 0usize;
+        let mut header_len = 1;
+        loop {
+            let byte = match self.buf.get(header_len) {
+                Some(&byte) => byte,
+                None => return Ok(None),
+            };
+            len |= ((byte & 0x7f) as usize) << (7 * (header_len - 1));
+            header_len += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header_len > 4 {
+                return Err(invalid("remaining length is too long"));
+            }
+        }
+        if len > MAX_PACKET {
+            return Err(invalid("packet is too large"));
+        }
+        if self.buf.len() < header_len + len {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(header_len + len);
+        let packet = Packet {
+            kind: self.buf[0] >> 4,
+            flags: self.buf[0] & 0x0f,
+            body: self.buf[header_len..].to_vec(),
+        };
+        self.buf = rest;
+        Ok(Some(packet))
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn u8(&mut self) -> io::Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.take(2).map(BigEndian::read_u16)
+    }
+
+    fn binary(&mut self) -> io::Result<&'a [u8]> {
+        let len = try!(self.u16()) as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let bytes = try!(self.binary());
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.input.len() {
+            return Err(invalid("truncated packet"));
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
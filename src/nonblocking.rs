@@ -0,0 +1,218 @@
+//! `futures` adapters for use from inside a tokio runtime.
+//!
+//! LMDB calls block, so all queue work is handed to tokio's blocking thread
+//! pool. Both adapters must be driven from within a runtime that has the
+//! time driver enabled.
+
+use std::io;
+use std::mem;
+use std::panic;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{Future, FutureExt, Sink, Stream, future};
+use tokio::task::{JoinError, JoinHandle, spawn_blocking};
+use tokio::time::{Sleep, sleep};
+
+use errors::{Error, Result};
+use {Consumer, Entry, Producer, Queue};
+
+// Commits from other processes don't wake us, so look again this often.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Messages buffered by a sink before `poll_ready` starts flushing.
+const DEFAULT_SINK_CAPACITY: usize = 64;
+
+/// A `Stream` of entries for a named consumer. Entries are yielded as soon as
+/// a producer in this process commits them, or within the poll interval for
+/// producers elsewhere.
+///
+/// Like `Consumer::poll`, reading an entry does not commit it; use
+/// `commit_upto` once it has been dealt with.
+pub struct ConsumerStream {
+    queue: Queue,
+    name: String,
+    poll_interval: Duration,
+    state: StreamState,
+}
+
+enum StreamState {
+    Idle(Consumer),
+    Polling(JoinHandle<(Consumer, Result<Option<Entry>>)>, u64),
+    Waiting(Consumer, u64, Pin<Box<Sleep>>),
+    Empty,
+}
+
+impl Consumer {
+    pub fn into_stream(self) -> ConsumerStream {
+        ConsumerStream {
+            queue: self.queue.clone(),
+            name: self.name.clone(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            state: StreamState::Idle(self),
+        }
+    }
+}
+
+impl ConsumerStream {
+    /// How often to look for entries committed by other processes.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Records that this consumer has dealt with everything up to `entry`.
+    pub fn commit_upto(&self, entry: &Entry) -> impl Future<Output = Result<()>> {
+        let queue = self.queue.clone();
+        let name = self.name.clone();
+        let entry = entry.clone();
+        // Spawned when first polled, so this may be called outside a runtime.
+        future::lazy(move |_| {
+            spawn_blocking(move || {
+                let consumer = try!(queue.consumer(&name));
+                consumer.commit_upto(&entry)
+            })
+        })
+            .then(async_result)
+    }
+}
+
+impl Stream for ConsumerStream {
+    type Item = Result<Entry>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            this.state = match mem::replace(&mut this.state, StreamState::Empty) {
+                StreamState::Idle(mut consumer) => {
+                    // Taken before polling, so that a commit that lands while
+                    // we look is still noticed.
+                    let seen = this.queue.inner.notifier.generation();
+                    let task = spawn_blocking(move || {
+                        let res = consumer.poll();
+                        (consumer, res)
+                    });
+                    StreamState::Polling(task, seen)
+                }
+                StreamState::Polling(mut task, seen) => {
+                    match Pin::new(&mut task).poll(cx) {
+                        Poll::Pending => {
+                            this.state = StreamState::Polling(task, seen);
+                            return Poll::Pending;
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(join_error(e)))),
+                        Poll::Ready(Ok((consumer, Ok(None)))) => {
+                            let timer = Box::pin(sleep(this.poll_interval));
+                            StreamState::Waiting(consumer, seen, timer)
+                        }
+                        Poll::Ready(Ok((consumer, res))) => {
+                            this.state = StreamState::Idle(consumer);
+                            return Poll::Ready(res.transpose());
+                        }
+                    }
+                }
+                StreamState::Waiting(consumer, seen, mut timer) => {
+                    let committed = !this.queue.inner.notifier.register(seen, cx.waker());
+                    if committed || timer.as_mut().poll(cx).is_ready() {
+                        StreamState::Idle(consumer)
+                    } else {
+                        this.state = StreamState::Waiting(consumer, seen, timer);
+                        return Poll::Pending;
+                    }
+                }
+                // Only seen if a blocking task failed and took the consumer
+                // with it.
+                StreamState::Empty => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// A `Sink` that produces each message it is sent. Messages are buffered and
+/// produced from tokio's blocking pool, each buffer-full in one transaction;
+/// `flush` waits until they are all committed.
+pub struct ProducerSink {
+    queue: Queue,
+    capacity: usize,
+    buffer: Vec<Vec<u8>>,
+    in_flight: Option<JoinHandle<Result<()>>>,
+}
+
+impl Producer {
+    pub fn into_sink(self) -> ProducerSink {
+        ProducerSink {
+            queue: self.queue,
+            capacity: DEFAULT_SINK_CAPACITY,
+            buffer: Vec::new(),
+            in_flight: None,
+        }
+    }
+}
+
+impl ProducerSink {
+    /// How many messages to buffer before applying back-pressure.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+}
+
+impl Sink<Vec<u8>> for ProducerSink {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        if self.buffer.len() < self.capacity {
+            Poll::Ready(Ok(()))
+        } else {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Vec<u8>) -> Result<()> {
+        self.buffer.push(msg);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let Some(mut task) = this.in_flight.take() {
+                match Pin::new(&mut task).poll(cx) {
+                    Poll::Pending => {
+                        this.in_flight = Some(task);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(res) => {
+                        if let Err(e) = res.map_err(join_error).and_then(|r| r) {
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+            }
+            if this.buffer.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let batch = mem::take(&mut this.buffer);
+            let mut producer = this.queue.producer();
+            // One transaction, so either all of the batch is stored or none.
+            this.in_flight = Some(spawn_blocking(move || {
+                producer.produce_batch(&batch).map(|_| ())
+            }));
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+fn async_result<T>(task: JoinHandle<Result<T>>) -> impl Future<Output = Result<T>> {
+    task.map(|res| res.map_err(join_error).and_then(|r| r))
+}
+
+fn join_error(e: JoinError) -> Error {
+    if e.is_panic() {
+        panic::resume_unwind(e.into_panic());
+    }
+    io::Error::new(io::ErrorKind::Interrupted, e).into()
+}
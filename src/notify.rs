@@ -0,0 +1,63 @@
+use std::sync::{Condvar, Mutex};
+use std::task::Waker;
+use std::time::Duration;
+
+/// Lets readers in this process wait for producers in this process to
+/// commit. Commits from other processes aren't seen, so waiters should
+/// still re-check the queue periodically.
+#[derive(Debug,Default)]
+pub struct CommitNotifier {
+    state: Mutex<NotifyState>,
+    committed: Condvar,
+}
+
+#[derive(Debug,Default)]
+struct NotifyState {
+    generation: u64,
+    wakers: Vec<Waker>,
+}
+
+impl CommitNotifier {
+    /// Bumped on every commit; take this before checking the queue, and a
+    /// commit that races with the check will still be noticed.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().expect("notifier lock poisoned").generation
+    }
+
+    pub fn notify(&self) {
+        let wakers = {
+            let mut state = self.state.lock().expect("notifier lock poisoned");
+            state.generation = state.generation.wrapping_add(1);
+            state.wakers.drain(..).collect::<Vec<_>>()
+        };
+        self.committed.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Blocks until there has been a commit since `seen`, or `timeout`
+    /// passes.
+    pub fn wait(&self, seen: u64, timeout: Duration) {
+        let state = self.state.lock().expect("notifier lock poisoned");
+        if state.generation == seen {
+            let _ = self.committed
+                        .wait_timeout(state, timeout)
+                        .expect("notifier lock poisoned");
+        }
+    }
+
+    /// Arranges for `waker` to be woken by the next commit. Returns false,
+    /// without registering, if there has been a commit since `seen`.
+    #[cfg(feature = "async")]
+    pub fn register(&self, seen: u64, waker: &Waker) -> bool {
+        let mut state = self.state.lock().expect("notifier lock poisoned");
+        if state.generation != seen {
+            return false;
+        }
+        if !state.wakers.iter().any(|w| w.will_wake(waker)) {
+            state.wakers.push(waker.clone());
+        }
+        true
+    }
+}
@@ -0,0 +1,211 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use lmdb_zero::{EnvBuilder, FileMode, open};
+
+use errors::{Error, ErrorKind, Result};
+use durability::{Durability, spawn_periodic_sync};
+use notify::CommitNotifier;
+use replication::FollowerAcks;
+use metrics::Counters;
+use format;
+use {Queue, QueueInner, open_db, PRODUCER_OFFSETS, CONSUMER_OFFSETS, DATA};
+
+// Small enough for a restrictive `ulimit -v` or a 32-bit address space; the
+// map is grown on demand when it fills up.
+const DEFAULT_MAP_SIZE: usize = 64 << 20;
+// We use `PRODUCER_OFFSETS`, `CONSUMER_OFFSETS` and `DATA`.
+const DEFAULT_MAX_DBS: u32 = 3;
+const DEFAULT_FILE_MODE: FileMode = 0o777;
+
+/// Controls how a `Queue`'s underlying LMDB environment is opened.
+///
+/// ```no_run
+/// let queue = lmqueue::QueueOptions::new()
+///                 .map_size(1 << 30)
+///                 .no_readahead(true)
+///                 .open("/var/lib/my-queue")
+///                 .expect("open");
+/// ```
+#[derive(Debug,Clone)]
+pub struct QueueOptions {
+    map_size: usize,
+    max_map_size: Option<usize>,
+    max_readers: Option<u32>,
+    max_dbs: u32,
+    file_mode: FileMode,
+    flags: open::Flags,
+    sync_interval: Option<Duration>,
+    follower_ack_timeout: Option<Duration>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            map_size: DEFAULT_MAP_SIZE,
+            max_map_size: None,
+            max_readers: None,
+            max_dbs: DEFAULT_MAX_DBS,
+            file_mode: DEFAULT_FILE_MODE,
+            flags: open::Flags::empty(),
+            sync_interval: None,
+            follower_ack_timeout: None,
+        }
+    }
+}
+
+impl QueueOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The initial size of the memory map. It is doubled whenever a write
+    /// finds it full, up to `max_map_size`.
+    pub fn map_size(mut self, bytes: usize) -> Self {
+        self.map_size = bytes;
+        self
+    }
+
+    /// The largest the map may grow to; past this, writes fail with
+    /// `MDB_MAP_FULL`. Unlimited by default.
+    pub fn max_map_size(mut self, bytes: usize) -> Self {
+        self.max_map_size = Some(bytes);
+        self
+    }
+
+    /// The number of reader slots; LMDB defaults to 126.
+    pub fn max_readers(mut self, readers: u32) -> Self {
+        self.max_readers = Some(readers);
+        self
+    }
+
+    /// The number of named databases; must be at least 3 for the queue itself.
+    pub fn max_dbs(mut self, dbs: u32) -> Self {
+        self.max_dbs = dbs;
+        self
+    }
+
+    /// Unix permissions used when creating the environment's files.
+    pub fn file_mode(mut self, mode: FileMode) -> Self {
+        self.file_mode = mode;
+        self
+    }
+
+    /// Treat the path as the data file itself, rather than a directory.
+    pub fn no_subdir(self, on: bool) -> Self {
+        self.flag(open::NOSUBDIR, on)
+    }
+
+    /// Turn off OS readahead, which can help when the queue exceeds RAM.
+    pub fn no_readahead(self, on: bool) -> Self {
+        self.flag(open::NORDAHEAD, on)
+    }
+
+    /// Use a writable memory map.
+    pub fn write_map(self, on: bool) -> Self {
+        self.flag(open::WRITEMAP, on)
+    }
+
+    /// Don't flush system buffers on commit; a crash may lose recent writes.
+    pub fn no_sync(self, on: bool) -> Self {
+        self.flag(open::NOSYNC, on)
+    }
+
+    /// Flush data but not the meta page on commit; a crash may undo the last
+    /// transaction.
+    pub fn no_meta_sync(self, on: bool) -> Self {
+        self.flag(open::NOMETASYNC, on)
+    }
+
+    /// Sets `no_sync` and `no_meta_sync` to match the given level.
+    pub fn durability(self, durability: Durability) -> Self {
+        match durability {
+            Durability::Full => self.no_sync(false).no_meta_sync(false),
+            Durability::NoMetaSync => self.no_sync(false).no_meta_sync(true),
+            Durability::Async => self.no_sync(true).no_meta_sync(false),
+        }
+    }
+
+    /// Flush the environment to disk from a background thread every
+    /// `interval`, bounding how much an OS crash can lose with relaxed
+    /// durability. The thread exits once the `Queue` is dropped.
+    pub fn sync_every(mut self, interval: Duration) -> Self {
+        self.sync_interval = Some(interval);
+        self
+    }
+
+    /// Have producers wait up to `timeout` for a follower replicating
+    /// through a `Server` in this process to acknowledge what they produce,
+    /// failing with `ReplicationTimeout` if none does.
+    pub fn follower_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.follower_ack_timeout = Some(timeout);
+        self
+    }
+
+    fn flag(mut self, flag: open::Flags, on: bool) -> Self {
+        if on {
+            self.flags.insert(flag);
+        } else {
+            self.flags.remove(flag);
+        }
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, place: P) -> Result<Queue> {
+        let place = place.as_ref();
+        self.open_at(place, false).map(|(queue, _)| queue).map_err(|e| e.in_queue(place))
+    }
+
+    /// Opens the queue at `place` and brings it up to the current
+    /// `FORMAT_VERSION`. Returns the version it was at beforehand.
+    pub fn migrate<P: AsRef<Path>>(&self, place: P) -> Result<u64> {
+        let place = place.as_ref();
+        self.open_at(place, true).map(|(_, found)| found).map_err(|e| e.in_queue(place))
+    }
+
+    fn open_at(&self, place: &Path, migrate: bool) -> Result<(Queue, u64)> {
+        debug!("Queue Open env at: {:?} with {:?}", place, self);
+        let path = try!(place.to_str()
+                             .ok_or_else(|| Error::from(ErrorKind::InvalidPath(place.to_owned()))));
+
+        let mut b = try!(EnvBuilder::new());
+        try!(b.set_maxdbs(self.max_dbs));
+        try!(b.set_mapsize(self.map_size));
+        if let Some(readers) = self.max_readers {
+            try!(b.set_maxreaders(readers));
+        }
+        let env = unsafe { try!(b.open(path, self.flags, self.file_mode)) };
+        let env = Arc::new(env);
+
+        // A transaction can only see databases opened /before/ it began,
+        // otherwise lmdb returns the helpful `-EINVAL`. So open them all
+        // up-front.
+        let producers = try!(open_db(&env, PRODUCER_OFFSETS));
+        let consumers = try!(open_db(&env, CONSUMER_OFFSETS));
+        let data = try!(open_db(&env, DATA));
+
+        let inner = Arc::new(QueueInner {
+            path: place.to_owned(),
+            env,
+            producers,
+            consumers,
+            data,
+            resize_lock: RwLock::new(()),
+            max_map_size: self.max_map_size,
+            notifier: CommitNotifier::default(),
+            follower_acks: FollowerAcks::default(),
+            follower_ack_timeout: self.follower_ack_timeout,
+            counters: Counters::default(),
+        });
+
+        let queue = Queue { inner };
+        let found = try!(format::check(&queue, migrate));
+
+        if let Some(interval) = self.sync_interval {
+            try!(spawn_periodic_sync(Arc::downgrade(&queue.inner), interval));
+        }
+
+        Ok((queue, found))
+    }
+}
@@ -0,0 +1,347 @@
+//! The framed binary protocol spoken by `Server` and `Client`.
+//!
+//! All integers are big-endian. Every message, in either direction, is a
+//! frame: a `u32` length followed by that many bytes of body. Byte strings
+//! within a body are a `u32` length followed by the bytes; names are UTF-8
+//! byte strings. A request body is a one-byte opcode followed by its fields:
+//!
+//! | op | request          | fields                           | response    |
+//! |----|------------------|----------------------------------|-------------|
+//! | 1  | produce          | message                          | offset      |
+//! | 2  | batch produce    | `u32` count, that many messages  | offsets     |
+//! | 3  | open consumer    | name                             | consumer    |
+//! | 4  | poll             | `u32` consumer, `u64` timeout ms | entry       |
+//! | 5  | commit           | `u32` consumer, `u64` offset     | done        |
+//! | 6  | consumer offset  | name                             | offset      |
+//! | 7  | list offsets     |                                  | consumers   |
+//! | 8  | trim             | `u64` offset to discard up to    | done        |
+//! | 9  | replicate        | `u64` follower's writer-next     | done        |
+//!
+//! Consumers are opened per connection, and referred to by the number the
+//! server hands back; each keeps its own read position, starting after its
+//! committed offset. A poll with a non-zero timeout waits that long for an
+//! entry to arrive. Once a replicate request is answered, the connection
+//! carries the stream described in `replication` instead.
+//!
+//! A response body is a one-byte status followed by its fields:
+//!
+//! | status | response  | fields                                          |
+//! |--------|-----------|-------------------------------------------------|
+//! | 0      | done      |                                                 |
+//! | 1      | offset    | `u64`                                           |
+//! | 2      | offsets   | `u32` count, that many `u64`s                   |
+//! | 3      | consumer  | `u32`                                           |
+//! | 4      | entry     | `u8` 0 for none, or 1, `u64` offset and message |
+//! | 5      | consumers | `u32` count, that many names each with a `u64`  |
+//! | 255    | error     | `u8` code, then fields as below                 |
+//!
+//! Error codes are 1 for not found, 2 for a trimmed gap (`u64` expected,
+//! `u64` available), 3 for an offset conflict (`u64` offset), 4 for a full
+//! map, and 0 for anything else, with a UTF-8 description.
+
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::{Error, ErrorKind};
+use Entry;
+
+#[derive(Debug,Clone,Eq,PartialEq)]
+pub enum Request {
+    Produce(Vec<u8>),
+    ProduceBatch(Vec<Vec<u8>>),
+    OpenConsumer(String),
+    Poll { consumer: u32, timeout_ms: u64 },
+    Commit { consumer: u32, offset: u64 },
+    ConsumerOffset(String),
+    Consumers,
+    Trim(u64),
+    Replicate(u64),
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Done,
+    Offset(u64),
+    Offsets(Vec<u64>),
+    Consumer(u32),
+    Entry(Option<Entry>),
+    Consumers(BTreeMap<String, u64>),
+    Error(Error),
+}
+
+/// Reads one frame, or `None` if the stream ends cleanly before it starts.
+pub fn read_frame<R: Read>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    let mut got = 0;
+    while got < len.len() {
+        match try!(input.read(&mut len[got..])) {
+            0 if got == 0 => return Ok(None),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame")),
+            n => got += n,
+        }
+    }
+    let len = try!(Cursor::new(&len[..]).read_u32::<BigEndian>()) as u64;
+    // Grown as the body arrives, so a bogus length can't exhaust memory.
+    let mut body = Vec::new();
+    try!(input.take(len).read_to_end(&mut body));
+    if body.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"));
+    }
+    Ok(Some(body))
+}
+
+pub fn write_frame<W: Write>(out: &mut W, body: &[u8]) -> io::Result<()> {
+    if body.len() > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    try!(frame.write_u32::<BigEndian>(body.len() as u32));
+    frame.extend_from_slice(body);
+    try!(out.write_all(&frame));
+    out.flush()
+}
+
+impl Request {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match *self {
+            Request::Produce(ref msg) => {
+                try!(out.write_u8(1));
+                try!(put_bytes(&mut out, msg));
+            }
+            Request::ProduceBatch(ref msgs) => {
+                try!(out.write_u8(2));
+                try!(put_len(&mut out, msgs.len()));
+                for msg in msgs {
+                    try!(put_bytes(&mut out, msg));
+                }
+            }
+            Request::OpenConsumer(ref name) => {
+                try!(out.write_u8(3));
+                try!(put_bytes(&mut out, name.as_bytes()));
+            }
+            Request::Poll { consumer, timeout_ms } => {
+                try!(out.write_u8(4));
+                try!(out.write_u32::<BigEndian>(consumer));
+                try!(out.write_u64::<BigEndian>(timeout_ms));
+            }
+            Request::Commit { consumer, offset } => {
+                try!(out.write_u8(5));
+                try!(out.write_u32::<BigEndian>(consumer));
+                try!(out.write_u64::<BigEndian>(offset));
+            }
+            Request::ConsumerOffset(ref name) => {
+                try!(out.write_u8(6));
+                try!(put_bytes(&mut out, name.as_bytes()));
+            }
+            Request::Consumers => try!(out.write_u8(7)),
+            Request::Trim(limit) => {
+                try!(out.write_u8(8));
+                try!(out.write_u64::<BigEndian>(limit));
+            }
+            Request::Replicate(from) => {
+                try!(out.write_u8(9));
+                try!(out.write_u64::<BigEndian>(from));
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Request> {
+        let mut r = Cursor::new(body);
+        let req = match try!(r.read_u8()) {
+            1 => Request::Produce(try!(get_bytes(&mut r))),
+            2 => {
+                let count = try!(r.read_u32::<BigEndian>());
+                let mut msgs = Vec::new();
+                for _ in 0..count {
+                    msgs.push(try!(get_bytes(&mut r)));
+                }
+                Request::ProduceBatch(msgs)
+            }
+            3 => Request::OpenConsumer(try!(get_string(&mut r))),
+            4 => {
+                Request::Poll {
+                    consumer: try!(r.read_u32::<BigEndian>()),
+                    timeout_ms: try!(r.read_u64::<BigEndian>()),
+                }
+            }
+            5 => {
+                Request::Commit {
+                    consumer: try!(r.read_u32::<BigEndian>()),
+                    offset: try!(r.read_u64::<BigEndian>()),
+                }
+            }
+            6 => Request::ConsumerOffset(try!(get_string(&mut r))),
+            7 => Request::Consumers,
+            8 => Request::Trim(try!(r.read_u64::<BigEndian>())),
+            9 => Request::Replicate(try!(r.read_u64::<BigEndian>())),
+            op => return Err(invalid(&format!("unknown request {}", op))),
+        };
+        try!(expect_end(&r));
+        Ok(req)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match *self {
+            Response::Done => try!(out.write_u8(0)),
+            Response::Offset(offset) => {
+                try!(out.write_u8(1));
+                try!(out.write_u64::<BigEndian>(offset));
+            }
+            Response::Offsets(ref offsets) => {
+                try!(out.write_u8(2));
+                try!(put_len(&mut out, offsets.len()));
+                for &offset in offsets {
+                    try!(out.write_u64::<BigEndian>(offset));
+                }
+            }
+            Response::Consumer(id) => {
+                try!(out.write_u8(3));
+                try!(out.write_u32::<BigEndian>(id));
+            }
+            Response::Entry(None) => {
+                try!(out.write_u8(4));
+                try!(out.write_u8(0));
+            }
+            Response::Entry(Some(ref entry)) => {
+                try!(out.write_u8(4));
+                try!(out.write_u8(1));
+                try!(out.write_u64::<BigEndian>(entry.offset));
+                try!(put_bytes(&mut out, &entry.data));
+            }
+            Response::Consumers(ref consumers) => {
+                try!(out.write_u8(5));
+                try!(put_len(&mut out, consumers.len()));
+                for (name, &offset) in consumers {
+                    try!(put_bytes(&mut out, name.as_bytes()));
+                    try!(out.write_u64::<BigEndian>(offset));
+                }
+            }
+            Response::Error(ref e) => {
+                try!(out.write_u8(255));
+                match *e.kind() {
+                    ErrorKind::NotFound => try!(out.write_u8(1)),
+                    ErrorKind::TrimmedGap { expected, available } => {
+                        try!(out.write_u8(2));
+                        try!(out.write_u64::<BigEndian>(expected));
+                        try!(out.write_u64::<BigEndian>(available));
+                    }
+                    ErrorKind::OffsetConflict { offset } => {
+                        try!(out.write_u8(3));
+                        try!(out.write_u64::<BigEndian>(offset));
+                    }
+                    ErrorKind::MapFull => try!(out.write_u8(4)),
+                    ErrorKind::Remote(ref msg) => {
+                        try!(out.write_u8(0));
+                        try!(put_bytes(&mut out, msg.as_bytes()));
+                    }
+                    ref kind => {
+                        try!(out.write_u8(0));
+                        try!(put_bytes(&mut out, kind.to_string().as_bytes()));
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Response> {
+        let mut r = Cursor::new(body);
+        let resp = match try!(r.read_u8()) {
+            0 => Response::Done,
+            1 => Response::Offset(try!(r.read_u64::<BigEndian>())),
+            2 => {
+                let count = try!(r.read_u32::<BigEndian>());
+                let mut offsets = Vec::new();
+                for _ in 0..count {
+                    offsets.push(try!(r.read_u64::<BigEndian>()));
+                }
+                Response::Offsets(offsets)
+            }
+            3 => Response::Consumer(try!(r.read_u32::<BigEndian>())),
+            4 => {
+                match try!(r.read_u8()) {
+                    0 => Response::Entry(None),
+                    _ => {
+                        let offset = try!(r.read_u64::<BigEndian>());
+                        Response::Entry(Some(Entry {
+                            offset,
+                            data: try!(get_bytes(&mut r)),
+                        }))
+                    }
+                }
+            }
+            5 => {
+                let count = try!(r.read_u32::<BigEndian>());
+                let mut consumers = BTreeMap::new();
+                for _ in 0..count {
+                    let name = try!(get_string(&mut r));
+                    consumers.insert(name, try!(r.read_u64::<BigEndian>()));
+                }
+                Response::Consumers(consumers)
+            }
+            255 => {
+                let kind = match try!(r.read_u8()) {
+                    1 => ErrorKind::NotFound,
+                    2 => {
+                        ErrorKind::TrimmedGap {
+                            expected: try!(r.read_u64::<BigEndian>()),
+                            available: try!(r.read_u64::<BigEndian>()),
+                        }
+                    }
+                    3 => ErrorKind::OffsetConflict { offset: try!(r.read_u64::<BigEndian>()) },
+                    4 => ErrorKind::MapFull,
+                    _ => ErrorKind::Remote(try!(get_string(&mut r))),
+                };
+                Response::Error(kind.into())
+            }
+            status => return Err(invalid(&format!("unknown response {}", status))),
+        };
+        try!(expect_end(&r));
+        Ok(resp)
+    }
+}
+
+pub fn put_len(out: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    if len > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too long to encode"));
+    }
+    out.write_u32::<BigEndian>(len as u32)
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    try!(put_len(out, bytes.len()));
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+pub fn get_bytes(r: &mut Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+    let len = try!(r.read_u32::<BigEndian>()) as u64;
+    let mut bytes = Vec::new();
+    try!(r.take(len).read_to_end(&mut bytes));
+    if bytes.len() as u64 != len {
+        return Err(invalid("truncated field"));
+    }
+    Ok(bytes)
+}
+
+pub fn get_string(r: &mut Cursor<&[u8]>) -> io::Result<String> {
+    String::from_utf8(try!(get_bytes(r))).map_err(|_| invalid("name is not UTF-8"))
+}
+
+pub fn expect_end(r: &Cursor<&[u8]>) -> io::Result<()> {
+    if r.position() != r.get_ref().len() as u64 {
+        return Err(invalid("trailing bytes in frame"));
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,385 @@
+//! Leader-follower replication over the `protocol` connection.
+//!
+//! A follower connects to a `Server` in front of the leader and sends a
+//! replicate request carrying its own `writer-next`. From then on the
+//! connection runs in lock-step: the leader sends an update whenever records
+//! arrive, or every `RECHECK_INTERVAL` if none do, and the follower answers
+//! each with its `writer-next` once the update is committed.
+//!
+//! An update body is the leader's `u64` writer-next, the `u64` first offset
+//! it still holds (zero if none), a `u32` count of records, each a `u64`
+//! offset and a message, and then a `u8` 1 followed by every consumer's
+//! offset, as in a consumers response, if they have changed, or else a 0.
+//! The follower's acknowledgement body is a single `u64`.
+//!
+//! Records are stored at the leader's offsets, and trimmed along with the
+//! leader's. A following queue records its leader under `leader` in `prod`,
+//! and refuses to be produced to until it is promoted.
+
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use lmdb_zero::{ConstAccessor, Database, put};
+
+use errors::{ErrorKind, Result};
+use protocol::{self, Request, Response};
+use {Entry, Queue, RECHECK_INTERVAL, WRITER_NEXT, envelope, mdb_maybe, put_record, read_offset,
+     totals, write_offset};
+
+const LEADER: &str = "leader";
+const LEADER_NEXT: &str = "leader-next";
+
+// Records sent in a single update.
+const UPDATE_BATCH: usize = 256;
+// How long a follower waits to hear from its leader before reconnecting.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a follower waits before reconnecting after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a following queue has got to, from `Queue::replication`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ReplicationStatus {
+    /// The address of the leader.
+    pub leader: String,
+    /// The leader's `writer-next` when we last heard from it.
+    pub leader_next: u64,
+    /// Our own `writer-next`.
+    pub writer_next: u64,
+}
+
+impl ReplicationStatus {
+    /// The number of offsets we are behind the leader, as of when we last
+    /// heard from it.
+    pub fn lag(&self) -> u64 {
+        self.leader_next.saturating_sub(self.writer_next)
+    }
+}
+
+/// Keeps a queue in step with a leader, as described in `replication`.
+#[derive(Debug)]
+pub struct Follower {
+    queue: Queue,
+    leader: String,
+}
+
+impl Follower {
+    /// Marks `queue` as following the leader served at `leader`, a
+    /// `host:port`. From now on it refuses producers until promoted.
+    pub fn new(queue: &Queue, leader: &str) -> Result<Follower> {
+        let meta = queue.producers_db();
+        try!(queue.write(|txn| {
+            try!(txn.access().put(meta, LEADER, leader, put::Flags::empty()));
+            Ok(())
+        }));
+        Ok(Follower {
+            queue: queue.clone(),
+            leader: leader.to_string(),
+        })
+    }
+
+    /// Replicates from the leader until the queue is promoted, reconnecting
+    /// whenever the connection fails.
+    pub fn run(&self) -> Result<()> {
+        while try!(self.queue.replication()).is_some() {
+            match self.follow() {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!("Replicating from {:?} failed: {}", self.leader, e);
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        }
+        info!("{:?} promoted; no longer following {:?}", self.queue.path(), self.leader);
+        Ok(())
+    }
+
+    // Applies updates until the queue is promoted.
+    fn follow(&self) -> Result<()> {
+        let mut stream = try!(TcpStream::connect(&*self.leader));
+        try!(stream.set_nodelay(true));
+        try!(stream.set_read_timeout(Some(LEADER_TIMEOUT)));
+        let from = try!(self.queue.writer_next());
+        debug!("Following {:?} from {:?}", self.leader, from);
+        try!(protocol::write_frame(&mut stream, &try!(Request::Replicate(from).encode())));
+        match try!(Response::decode(&try!(read_frame(&mut stream)))) {
+            Response::Done => (),
+            Response::Error(e) => return Err(e),
+            other => return Err(ErrorKind::Remote(format!("unexpected {:?}", other)).into()),
+        }
+        loop {
+            let update = try!(Update::decode(&try!(read_frame(&mut stream))));
+            if !try!(self.queue.apply(&update)) {
+                return Ok(());
+            }
+            let mut ack = Vec::new();
+            try!(ack.write_u64::<BigEndian>(try!(self.queue.writer_next())));
+            try!(protocol::write_frame(&mut stream, &ack));
+        }
+    }
+}
+
+/// The highest offset any follower of this process has acknowledged, for
+/// producers that wait on it.
+#[derive(Debug,Default)]
+pub struct FollowerAcks {
+    acked: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl FollowerAcks {
+    fn acknowledge(&self, offset: u64) {
+        let mut acked = self.acked.lock().expect("acks lock poisoned");
+        if offset > *acked {
+            *acked = offset;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Waits up to `timeout` for a follower to acknowledge `offset`;
+    /// returns whether one did.
+    pub fn wait(&self, offset: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut acked = self.acked.lock().expect("acks lock poisoned");
+        while *acked < offset {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            acked = self.changed
+                        .wait_timeout(acked, deadline - now)
+                        .expect("acks lock poisoned")
+                        .0;
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Update {
+    writer_next: u64,
+    first: u64,
+    records: Vec<Entry>,
+    consumers: Option<BTreeMap<String, u64>>,
+}
+
+impl Update {
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        try!(out.write_u64::<BigEndian>(self.writer_next));
+        try!(out.write_u64::<BigEndian>(self.first));
+        try!(protocol::put_len(&mut out, self.records.len()));
+        for record in &self.records {
+            try!(out.write_u64::<BigEndian>(record.offset));
+            try!(protocol::put_bytes(&mut out, &record.data));
+        }
+        match self.consumers {
+            Some(ref consumers) => {
+                try!(out.write_u8(1));
+                try!(protocol::put_len(&mut out, consumers.len()));
+                for (name, &offset) in consumers {
+                    try!(protocol::put_bytes(&mut out, name.as_bytes()));
+                    try!(out.write_u64::<BigEndian>(offset));
+                }
+            }
+            None => try!(out.write_u8(0)),
+        }
+        Ok(out)
+    }
+
+    fn decode(body: &[u8]) -> io::Result<Update> {
+        let mut r = Cursor::new(body);
+        let writer_next = try!(r.read_u64::<BigEndian>());
+        let first = try!(r.read_u64::<BigEndian>());
+        let count = try!(r.read_u32::<BigEndian>());
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let offset = try!(r.read_u64::<BigEndian>());
+            records.push(Entry {
+                offset,
+                data: try!(protocol::get_bytes(&mut r)),
+            });
+        }
+        let consumers = match try!(r.read_u8()) {
+            0 => None,
+            _ => {
+                let count = try!(r.read_u32::<BigEndian>());
+                let mut consumers = BTreeMap::new();
+                for _ in 0..count {
+                    let name = try!(protocol::get_string(&mut r));
+                    consumers.insert(name, try!(r.read_u64::<BigEndian>()));
+                }
+                Some(consumers)
+            }
+        };
+        try!(protocol::expect_end(&r));
+        Ok(Update {
+            writer_next,
+            first,
+            records,
+            consumers,
+        })
+    }
+}
+
+fn read_frame<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    match try!(protocol::read_frame(input)) {
+        Some(frame) => Ok(frame),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer hung up").into()),
+    }
+}
+
+/// Streams updates to a follower that has asked to replicate from offset
+/// `from`, until it goes away.
+pub fn lead<S: Read + Write>(queue: &Queue, mut stream: S, from: u64) -> Result<()> {
+    let writer_next = try!(queue.writer_next());
+    if from > writer_next {
+        let msg = format!("follower is at offset {}, ahead of the leader at {}",
+                          from,
+                          writer_next);
+        let resp = Response::Error(ErrorKind::Remote(msg).into());
+        try!(protocol::write_frame(&mut stream, &try!(resp.encode())));
+        return Ok(());
+    }
+    try!(protocol::write_frame(&mut stream, &try!(Response::Done.encode())));
+    debug!("Replicating to follower from {:?}", from);
+    let mut position = from;
+    let mut sent_consumers = None;
+    loop {
+        let records = try!(queue.wait_for_entries(position + 1, UPDATE_BATCH, RECHECK_INTERVAL));
+        let consumers = try!(queue.consumers());
+        let changed = sent_consumers.as_ref() != Some(&consumers);
+        let update = Update {
+            writer_next: try!(queue.writer_next()),
+            first: try!(queue.entries(0, 1)).first().map(|e| e.offset).unwrap_or(0),
+            records,
+            consumers: if changed { Some(consumers.clone()) } else { None },
+        };
+        if let Some(last) = update.records.last() {
+            position = last.offset;
+        }
+        try!(protocol::write_frame(&mut stream, &try!(update.encode())));
+        let ack = match try!(protocol::read_frame(&mut stream)) {
+            Some(ack) => try!(Cursor::new(ack).read_u64::<BigEndian>()),
+            None => break,
+        };
+        trace!("Follower acknowledged {:?}", ack);
+        queue.inner.follower_acks.acknowledge(ack);
+        sent_consumers = Some(consumers);
+    }
+    debug!("Follower went away");
+    Ok(())
+}
+
+/// The leader a queue follows, if any.
+pub fn leader_of(meta: &Database, access: &ConstAccessor) -> Result<Option<String>> {
+    match try!(mdb_maybe(access.get::<str, str>(meta, LEADER))) {
+        Some(leader) => Ok(Some(leader.to_string())),
+        None => Ok(None),
+    }
+}
+
+impl Queue {
+    /// Where replication has got to, or `None` if the queue isn't a
+    /// follower.
+    pub fn replication(&self) -> Result<Option<ReplicationStatus>> {
+        let meta = self.producers_db();
+        self.read(|txn| {
+            let access = txn.access();
+            let leader = match try!(leader_of(meta, &access)) {
+                Some(leader) => leader,
+                None => return Ok(None),
+            };
+            Ok(Some(ReplicationStatus {
+                leader,
+                leader_next: try!(read_offset(meta, &access, LEADER_NEXT)),
+                writer_next: try!(read_offset(meta, &access, WRITER_NEXT)),
+            }))
+        })
+    }
+
+    /// Stops following the leader, so the queue accepts producers again,
+    /// carrying on from the last offset replicated. A running `Follower`
+    /// notices and stops. Returns whether the queue was following.
+    pub fn promote(&self) -> Result<bool> {
+        let meta = self.producers_db();
+        self.write(|txn| {
+            let mut access = txn.access();
+            try!(mdb_maybe(access.del_key(meta, LEADER_NEXT)));
+            Ok(try!(mdb_maybe(access.del_key(meta, LEADER))).is_some())
+        })
+    }
+
+    // Commits an update from the leader; returns false, without applying
+    // it, if we have been promoted.
+    fn apply(&self, update: &Update) -> Result<bool> {
+        let meta = self.producers_db();
+        let data = self.data_db();
+        let cons = self.consumers_db();
+        let mut sealed = Vec::with_capacity(update.records.len());
+        for record in &update.records {
+            sealed.push(try!(envelope::seal(&record.data)));
+        }
+        let applied = try!(self.write(|txn| {
+            let stale = match update.consumers {
+                Some(ref consumers) => {
+                    let mut names = Vec::new();
+                    let mut cursor = try!(txn.cursor(cons));
+                    let access = txn.access();
+                    let mut curr = try!(mdb_maybe(cursor.first::<str, [u8]>(&access)));
+                    while let Some((name, _)) = curr {
+                        if !consumers.contains_key(name) {
+                            names.push(name.to_string());
+                        }
+                        curr = try!(mdb_maybe(cursor.next::<str, [u8]>(&access)));
+                    }
+                    names
+                }
+                None => Vec::new(),
+            };
+            let mut access = txn.access();
+            if try!(leader_of(meta, &access)).is_none() {
+                return Ok(false);
+            }
+            let mut writer_next = try!(read_offset(meta, &access, WRITER_NEXT));
+            let mut bytes = 0;
+            for (record, sealed) in update.records.iter().zip(&sealed) {
+                try!(put_record(data, &mut access, record.offset, sealed));
+                writer_next = ::std::cmp::max(writer_next, record.offset);
+                bytes += record.data.len() as u64;
+            }
+            try!(write_offset(meta, &mut access, WRITER_NEXT, writer_next));
+            if !update.records.is_empty() {
+                try!(totals::add(meta, &mut access, update.records.len() as u64, bytes));
+            }
+            try!(write_offset(meta, &mut access, LEADER_NEXT, update.writer_next));
+            if let Some(ref consumers) = update.consumers {
+                for name in &stale {
+                    try!(access.del_key(cons, &**name));
+                }
+                for (name, &offset) in consumers {
+                    try!(write_offset(cons, &mut access, name, offset));
+                }
+            }
+            Ok(true)
+        }));
+        if !applied {
+            return Ok(false);
+        }
+        if !update.records.is_empty() {
+            self.inner.notifier.notify();
+        }
+        // A leader holding nothing has trimmed everything it wrote.
+        let trimmed = if update.first > 0 { update.first - 1 } else { update.writer_next };
+        let first = try!(self.entries(0, 1)).first().map(|e| e.offset);
+        if first.is_some_and(|first| first <= trimmed) {
+            try!(self.discard_upto(trimmed));
+        }
+        Ok(true)
+    }
+}
+
@@ -0,0 +1,829 @@
+//! A subset of the Redis protocol (RESP), presenting the queue as a Redis
+//! stream so that stock Redis clients can use it.
+//!
+//! Every key names the one queue being served. An entry's ID is its offset
+//! followed by `-0`, and its only field is `data`, holding the message.
+//!
+//! * `XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold] * field value`
+//!   produces `value`. Only generated IDs, and a single field, are allowed.
+//! * `XLEN key` counts the entries held.
+//! * `XRANGE key start end [COUNT n]` reads entries by ID.
+//! * `XREAD [COUNT n] [BLOCK ms] STREAMS key... id...` reads the entries
+//!   after each ID, or `$` for only new entries, waiting up to `ms`
+//!   milliseconds (or forever, for 0) for one to arrive.
+//! * `XGROUP CREATE|SETID key group id|$` and `XGROUP DESTROY key group`
+//!   manage the named consumer `group`.
+//! * `XREADGROUP GROUP group consumer [COUNT n] [BLOCK ms] [NOACK] STREAMS
+//!   key id` reads on from the group's committed offset. With `>` it returns
+//!   entries not yet delivered on this connection; with any other ID, those
+//!   delivered but not yet acknowledged. Consumer names are ignored: the
+//!   group is a single consumer, and the entries it has delivered are only
+//!   remembered per connection.
+//! * `XACK key group id...` commits the group up to the largest ID given.
+//!   As with `commit_upto`, that acknowledges every earlier entry too.
+//! * `XTRIM key MAXLEN|MINID [=|~] threshold` discards old entries.
+//! * `PING`, `ECHO`, `COMMAND` and `QUIT` behave as usual.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::thread;
+use std::time::Duration;
+
+use errors::{ErrorKind, Result};
+use {Entry, Queue, decode_data_key, mdb_maybe, totals};
+
+// Longest line, and largest bulk string, we'll accept from a client.
+const MAX_LINE: usize = 64 << 10;
+const MAX_BULK: usize = 512 << 20;
+const MAX_ARGS: usize = 1 << 20;
+// Entries read per transaction when a range has no count.
+const RANGE_CHUNK: usize = 1024;
+// How long a `BLOCK 0` read waits before checking again.
+const BLOCK_FOREVER_STEP: Duration = Duration::from_secs(3600);
+
+/// Serves a queue to Redis clients, as described in `resp`. Each connection
+/// is handled on its own thread.
+#[derive(Debug)]
+pub struct RespServer {
+    queue: Queue,
+    listener: TcpListener,
+}
+
+impl RespServer {
+    pub fn bind<A: ToSocketAddrs>(queue: &Queue, addr: A) -> Result<RespServer> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(RespServer {
+            queue: queue.clone(),
+            listener,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            try!(stream.set_nodelay(true));
+            debug!("Accepted connection from {:?}", peer);
+            let queue = self.queue.clone();
+            try!(thread::Builder::new()
+                     .name("lmqueue-resp".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&queue, stream) {
+                             warn!("Connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(u64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    NilArray,
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match *self {
+            Reply::Status(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(ref e) => {
+                // Errors are a single line.
+                let e = e.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{}\r\n", e).as_bytes())
+            }
+            Reply::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(ref b) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(ref items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write_to(out);
+                }
+            }
+            Reply::NilArray => out.extend_from_slice(b"*-1\r\n"),
+        }
+    }
+}
+
+fn err(msg: &str) -> Reply {
+    Reply::Error(format!("ERR {}", msg))
+}
+
+fn syntax_error() -> Reply {
+    err("syntax error")
+}
+
+fn serve(queue: &Queue, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+    let mut out = stream;
+    let mut session = Session {
+        queue: queue.clone(),
+        delivered: HashMap::new(),
+    };
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let mut buf = Vec::new();
+                    err(&format!("Protocol error: {}", e)).write_to(&mut buf);
+                    try!(out.write_all(&buf));
+                }
+                return Err(e.into());
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        trace!("Command: {}", name);
+        let reply = session.handle(&name, &args[1..]);
+        let mut buf = Vec::new();
+        reply.write_to(&mut buf);
+        try!(out.write_all(&buf));
+        if name == "QUIT" {
+            break;
+        }
+    }
+    debug!("Connection closed");
+    Ok(())
+}
+
+// Reads a command, either as an array of bulk strings, or inline as words
+// on a line. Returns `None` when the client hangs up between commands.
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match try!(read_line(reader)) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line.split(|&b| b == b' ' || b == b'\t')
+                       .filter(|w| !w.is_empty())
+                       .map(|w| w.to_vec())
+                       .collect();
+        return Ok(Some(args));
+    }
+    let count = try!(parse_length(&line[1..], MAX_ARGS));
+    let mut args = Vec::with_capacity(cmp::min(count, 64));
+    for _ in 0..count {
+        let line = try!(try!(read_line(reader)).ok_or_else(truncated));
+        if line.first() != Some(&b'$') {
+            return Err(invalid("expected a bulk string"));
+        }
+        let len = try!(parse_length(&line[1..], MAX_BULK));
+        let mut arg = vec![0; len + 2];
+        try!(reader.read_exact(&mut arg));
+        if &arg[len..] != b"\r\n" {
+            return Err(invalid("bulk string is not terminated"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    try!(reader.by_ref().take(MAX_LINE as u64 + 2).read_until(b'\n', &mut line));
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() > MAX_LINE {
+            invalid("line too long")
+        } else {
+            truncated()
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(s: &[u8], max: usize) -> io::Result<usize> {
+    match str::from_utf8(s).ok().and_then(|s| s.parse::<usize>().ok()) {
+        Some(n) if n <= max => Ok(n),
+        _ => Err(invalid("invalid length")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "client hung up mid-command")
+}
+
+struct Session {
+    queue: Queue,
+    // The last offset delivered to each group on this connection.
+    delivered: HashMap<String, u64>,
+}
+
+impl Session {
+    fn handle(&mut self, name: &str, args: &[Vec<u8>]) -> Reply {
+        // Only the message given to `XADD` may be binary.
+        let (words, value) = match (name, args.split_last()) {
+            ("XADD", Some((value, rest))) => (rest, Some(&value[..])),
+            _ => (args, None),
+        };
+        let args = match words.iter()
+                              .map(|a| str::from_utf8(a))
+                              .collect::<::std::result::Result<Vec<_>, _>>() {
+            Ok(args) => args,
+            Err(_) => return err("arguments must be UTF-8"),
+        };
+        let res = match name {
+            "PING" => {
+                match args.len() {
+                    0 => Ok(Reply::Status("PONG")),
+                    1 => Ok(Reply::Bulk(args[0].as_bytes().to_vec())),
+                    _ => return wrong_arity(name),
+                }
+            }
+            "ECHO" if args.len() == 1 => Ok(Reply::Bulk(args[0].as_bytes().to_vec())),
+            "QUIT" => Ok(Reply::Status("OK")),
+            "COMMAND" => Ok(Reply::Array(Vec::new())),
+            "XADD" => return self.xadd(&args, value),
+            "XLEN" if args.len() == 1 => self.queue.record_count().map(Reply::Int),
+            "XRANGE" => return self.xrange(&args),
+            "XREAD" => return self.xread(&args),
+            "XGROUP" => return self.xgroup(&args),
+            "XREADGROUP" => return self.xreadgroup(&args),
+            "XACK" => return self.xack(&args),
+            "XTRIM" => return self.xtrim(&args),
+            "ECHO" | "XLEN" => return wrong_arity(name),
+            _ => return err(&format!("unknown command '{}'", name)),
+        };
+        res.unwrap_or_else(|e| err(&e.to_string()))
+    }
+
+    fn xadd(&mut self, args: &[&str], value: Option<&[u8]>) -> Reply {
+        let msg = match value {
+            Some(msg) if args.len() >= 3 => msg,
+            _ => return wrong_arity("XADD"),
+        };
+        let mut i = 1;
+        if args[i].eq_ignore_ascii_case("NOMKSTREAM") {
+            i += 1;
+        }
+        let trim = match parse_trim(&args[i..]) {
+            Ok(Some((trim, used))) => {
+                i += used;
+                Some(trim)
+            }
+            Ok(None) => None,
+            Err(reply) => return reply,
+        };
+        if args.len() != i + 2 {
+            return err("only a single field is supported");
+        }
+        if args[i] != "*" {
+            return err("only auto-generated IDs are supported");
+        }
+        let res = self.queue.producer().produce(msg).and_then(|offset| {
+            if let Some(trim) = trim {
+                try!(self.trim(trim));
+            }
+            Ok(offset)
+        });
+        match res {
+            Ok(offset) => Reply::Bulk(format_id(offset).into_bytes()),
+            Err(e) => err(&e.to_string()),
+        }
+    }
+
+    fn xrange(&mut self, args: &[&str]) -> Reply {
+        if args.len() != 3 && args.len() != 5 {
+            return wrong_arity("XRANGE");
+        }
+        let start = match args[1] {
+            "-" => Some(0),
+            s if s.starts_with('(') => {
+                match parse_id(&s[1..]) {
+                    Some((ms, _)) => ms.checked_add(1),
+                    None => return invalid_id(),
+                }
+            }
+            s => {
+                match parse_id(s) {
+                    Some((ms, 0)) => Some(ms),
+                    Some((ms, _)) => ms.checked_add(1),
+                    None => return invalid_id(),
+                }
+            }
+        };
+        // Nothing can come after the last possible ID.
+        let start = match start {
+            Some(start) => start,
+            None => return Reply::Array(Vec::new()),
+        };
+        let end = match args[2] {
+            "+" => u64::MAX,
+            s if s.starts_with('(') => {
+                match parse_id(&s[1..]) {
+                    Some((0, 0)) => return Reply::Array(Vec::new()),
+                    Some((ms, 0)) => ms - 1,
+                    Some((ms, _)) => ms,
+                    None => return invalid_id(),
+                }
+            }
+            s => {
+                match parse_id(s) {
+                    Some((ms, _)) => ms,
+                    None => return invalid_id(),
+                }
+            }
+        };
+        let count = if args.len() == 5 {
+            if !args[3].eq_ignore_ascii_case("COUNT") {
+                return syntax_error();
+            }
+            match args[4].parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => return not_an_integer(),
+            }
+        } else {
+            usize::MAX
+        };
+        match read_range(&self.queue, start, end, count) {
+            Ok(entries) => entries_reply(&entries),
+            Err(e) => err(&e.to_string()),
+        }
+    }
+
+    fn xread(&mut self, args: &[&str]) -> Reply {
+        let opts = match parse_read_opts(args, false) {
+            Ok(opts) => opts,
+            Err(reply) => return reply,
+        };
+        let mut afters = Vec::new();
+        for id in &opts.ids {
+            let after = match *id {
+                "$" => {
+                    match self.queue.writer_next() {
+                        Ok(next) => next,
+                        Err(e) => return err(&e.to_string()),
+                    }
+                }
+                id => {
+                    match parse_id(id) {
+                        Some((ms, _)) => ms,
+                        None => return invalid_id(),
+                    }
+                }
+            };
+            afters.push(after);
+        }
+        let first = match afters.iter().cloned().min().unwrap_or(0).checked_add(1) {
+            Some(first) => first,
+            None => return Reply::NilArray,
+        };
+        let res = self.wait_from(first, opts.block).and_then(|()| {
+            let mut streams = Vec::new();
+            for (key, &after) in opts.keys.iter().zip(&afters) {
+                let from = match after.checked_add(1) {
+                    Some(from) => from,
+                    None => continue,
+                };
+                let entries = try!(self.queue.entries(from, opts.count));
+                if !entries.is_empty() {
+                    streams.push(Reply::Array(vec![Reply::Bulk(key.as_bytes().to_vec()),
+                                                   entries_reply(&entries)]));
+                }
+            }
+            Ok(streams)
+        });
+        match res {
+            Ok(ref streams) if streams.is_empty() => Reply::NilArray,
+            Ok(streams) => Reply::Array(streams),
+            Err(e) => err(&e.to_string()),
+        }
+    }
+
+    fn xgroup(&mut self, args: &[&str]) -> Reply {
+        let sub = args.first().map(|s| s.to_uppercase()).unwrap_or_default();
+        match (&*sub, args.len()) {
+            ("CREATE", n) if n >= 4 => {
+                // MKSTREAM and ENTRIESREAD make no difference here.
+                match self.queue.consumer_offset(args[2]) {
+                    Ok(_) => {
+                        let msg = "BUSYGROUP Consumer Group name already exists";
+                        return Reply::Error(msg.to_string());
+                    }
+                    Err(ref e) if is_not_found(e) => (),
+                    Err(e) => return err(&e.to_string()),
+                }
+                self.set_group(args[2], args[3])
+            }
+            ("SETID", n) if n >= 4 => {
+                match self.group_offset(args[1], args[2], "XGROUP") {
+                    Ok(_) => self.set_group(args[2], args[3]),
+                    Err(reply) => reply,
+                }
+            }
+            ("DESTROY", 3) => {
+                match self.queue.consumer_offset(args[2]) {
+                    Ok(_) => (),
+                    Err(ref e) if is_not_found(e) => return Reply::Int(0),
+                    Err(e) => return err(&e.to_string()),
+                }
+                self.delivered.remove(args[2]);
+                let res = self.queue.consumer(args[2]).and_then(|mut c| c.clear_offset());
+                match res {
+                    Ok(()) => Reply::Int(1),
+                    Err(e) => err(&e.to_string()),
+                }
+            }
+            ("CREATE", _) | ("SETID", _) | ("DESTROY", _) => wrong_arity("XGROUP"),
+            _ => err(&format!("unknown XGROUP subcommand '{}'", sub)),
+        }
+    }
+
+    fn set_group(&mut self, group: &str, id: &str) -> Reply {
+        let offset = match id {
+            "$" => {
+                match self.queue.writer_next() {
+                    Ok(next) => next,
+                    Err(e) => return err(&e.to_string()),
+                }
+            }
+            id => {
+                match parse_id(id) {
+                    Some((ms, _)) => ms,
+                    None => return invalid_id(),
+                }
+            }
+        };
+        self.delivered.remove(group);
+        match self.queue.consumer(group).and_then(|c| c.commit_offset(offset)) {
+            Ok(()) => Reply::Status("OK"),
+            Err(e) => err(&e.to_string()),
+        }
+    }
+
+    fn xreadgroup(&mut self, args: &[&str]) -> Reply {
+        if args.len() < 3 || !args[0].eq_ignore_ascii_case("GROUP") {
+            return syntax_error();
+        }
+        let group = args[1];
+        let opts = match parse_read_opts(&args[3..], true) {
+            Ok(opts) => opts,
+            Err(reply) => return reply,
+        };
+        if opts.keys.len() != 1 {
+            return err("only one stream per XREADGROUP is supported");
+        }
+        let key = opts.keys[0];
+        let committed = match self.group_offset(key, group, "XREADGROUP") {
+            Ok(offset) => offset,
+            Err(reply) => return reply,
+        };
+        let delivered = cmp::max(committed, *self.delivered.get(group).unwrap_or(&0));
+
+        let res = if opts.ids[0] == ">" {
+            let next = match delivered.checked_add(1) {
+                Some(next) => next,
+                None => return Reply::NilArray,
+            };
+            self.wait_from(next, opts.block).and_then(|()| {
+                let entries = try!(self.queue.entries(next, opts.count));
+                if let Some(last) = entries.last() {
+                    if opts.no_ack {
+                        try!(self.queue.consumer(group).and_then(|c| c.commit_offset(last.offset)));
+                    }
+                    self.delivered.insert(group.to_string(), last.offset);
+                }
+                Ok(entries)
+            })
+        } else {
+            // Entries delivered on this connection but not acknowledged.
+            let after = match parse_id(opts.ids[0]) {
+                Some((ms, _)) => cmp::max(ms, committed),
+                None => return invalid_id(),
+            };
+            let count = cmp::min(opts.count as u64, delivered.saturating_sub(after)) as usize;
+            match after.checked_add(1) {
+                Some(from) => {
+                    self.queue.entries(from, count).map(|entries| {
+                        entries.into_iter().filter(|e| e.offset <= delivered).collect()
+                    })
+                }
+                None => Ok(Vec::new()),
+            }
+        };
+        match res {
+            Ok(ref entries) if entries.is_empty() && opts.ids[0] == ">" => Reply::NilArray,
+            Ok(entries) => {
+                Reply::Array(vec![Reply::Array(vec![Reply::Bulk(key.as_bytes().to_vec()),
+                                                    entries_reply(&entries)])])
+            }
+            Err(e) => err(&e.to_string()),
+        }
+    }
+
+    fn xack(&mut self, args: &[&str]) -> Reply {
+        if args.len() < 3 {
+            return wrong_arity("XACK");
+        }
+        let group = args[1];
+        let mut ids = Vec::new();
+        for id in &args[2..] {
+            match parse_id(id) {
+                Some((ms, _)) => ids.push(ms),
+                None => return invalid_id(),
+            }
+        }
+        let committed = match self.queue.consumer_offset(group) {
+            Ok(offset) => offset,
+            Err(ref e) if is_not_found(e) => return Reply::Int(0),
+            Err(e) => return err(&e.to_string()),
+        };
+        let acked = ids.iter().filter(|&&id| id > committed).count() as u64;
+        let upto = ids.iter().cloned().max().unwrap_or(0);
+        if upto > committed {
+            if let Err(e) = self.queue.consumer(group).and_then(|c| c.commit_offset(upto)) {
+                return err(&e.to_string());
+            }
+        }
+        Reply::Int(acked)
+    }
+
+    fn xtrim(&mut self, args: &[&str]) -> Reply {
+        match parse_trim(&args[1..]) {
+            Ok(Some((trim, used))) if used == args.len() - 1 => {
+                match self.trim(trim) {
+                    Ok(removed) => Reply::Int(removed),
+                    Err(e) => err(&e.to_string()),
+                }
+            }
+            Ok(_) => syntax_error(),
+            Err(reply) => reply,
+        }
+    }
+
+    // Discards entries as `XTRIM` would, returning how many went.
+    fn trim(&self, trim: Trim) -> Result<u64> {
+        let limit = match trim {
+            Trim::MaxLen(len) => {
+                match try!(self.newest_dropped(len)) {
+                    Some(offset) => offset,
+                    None => return Ok(0),
+                }
+            }
+            Trim::MinId(id) => id.saturating_sub(1),
+        };
+        if limit == 0 {
+            return Ok(0);
+        }
+        self.queue.discard_upto(limit)
+    }
+
+    // The offset of the last record to go if only the newest `len` are kept,
+    // counting records rather than offsets, as there may be gaps.
+    fn newest_dropped(&self, len: u64) -> Result<Option<u64>> {
+        let data = self.queue.data_db();
+        let meta = self.queue.producers_db();
+        self.queue.read(|txn| {
+            let access = txn.access();
+            let excess = try!(totals::read(meta, &access)).records.saturating_sub(len);
+            if excess == 0 {
+                return Ok(None);
+            }
+            let mut cursor = try!(txn.cursor(data));
+            let mut curr = try!(mdb_maybe(cursor.first::<[u8], [u8]>(&access)));
+            let mut near = 0;
+            for _ in 1..excess {
+                if let Some((k, _)) = curr {
+                    near = try!(decode_data_key(k, near)).saturating_add(1);
+                }
+                curr = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&access)));
+            }
+            match curr {
+                Some((k, _)) => decode_data_key(k, near).map(Some),
+                None => Ok(None),
+            }
+        })
+    }
+
+    // The named group's committed offset, or a `NOGROUP` error.
+    fn group_offset(&self, key: &str, group: &str, cmd: &str) -> ::std::result::Result<u64, Reply> {
+        match self.queue.consumer_offset(group) {
+            Ok(offset) => Ok(offset),
+            Err(ref e) if is_not_found(e) => {
+                Err(Reply::Error(format!("NOGROUP No such key '{}' or consumer group '{}' in \
+                                          {} with GROUP option",
+                                         key,
+                                         group,
+                                         cmd)))
+            }
+            Err(e) => Err(err(&e.to_string())),
+        }
+    }
+
+    // Waits, if asked to, for an entry at or after offset `from`.
+    fn wait_from(&self, from: u64, block: Option<Duration>) -> Result<()> {
+        match block {
+            None => Ok(()),
+            Some(timeout) if timeout == Duration::from_secs(0) => {
+                while try!(self.queue.wait_for_entries(from, 1, BLOCK_FOREVER_STEP)).is_empty() {}
+                Ok(())
+            }
+            Some(timeout) => self.queue.wait_for_entries(from, 1, timeout).map(|_| ()),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Trim {
+    MaxLen(u64),
+    MinId(u64),
+}
+
+// Parses a `MAXLEN|MINID [=|~] threshold [LIMIT count]` clause at the start
+// of `args`, returning it and the number of arguments it took up.
+fn parse_trim(args: &[&str]) -> ::std::result::Result<Option<(Trim, usize)>, Reply> {
+    let kind = match args.first() {
+        Some(s) if s.eq_ignore_ascii_case("MAXLEN") => "MAXLEN",
+        Some(s) if s.eq_ignore_ascii_case("MINID") => "MINID",
+        _ => return Ok(None),
+    };
+    let mut i = 1;
+    if args.get(i).map(|&s| s == "=" || s == "~").unwrap_or(false) {
+        i += 1;
+    }
+    let threshold = match args.get(i) {
+        Some(s) => *s,
+        None => return Err(syntax_error()),
+    };
+    i += 1;
+    let trim = if kind == "MAXLEN" {
+        match threshold.parse() {
+            Ok(n) => Trim::MaxLen(n),
+            Err(_) => return Err(not_an_integer()),
+        }
+    } else {
+        match parse_id(threshold) {
+            Some((ms, _)) => Trim::MinId(ms),
+            None => return Err(invalid_id()),
+        }
+    };
+    // We always trim exactly, so a limit makes no difference.
+    if args.get(i).map(|s| s.eq_ignore_ascii_case("LIMIT")).unwrap_or(false) {
+        if args.get(i + 1).and_then(|s| s.parse::<u64>().ok()).is_none() {
+            return Err(not_an_integer());
+        }
+        i += 2;
+    }
+    Ok(Some((trim, i)))
+}
+
+struct ReadOpts<'a> {
+    count: usize,
+    block: Option<Duration>,
+    no_ack: bool,
+    keys: Vec<&'a str>,
+    ids: Vec<&'a str>,
+}
+
+// Parses the options of `XREAD`, or those following the group and consumer
+// of `XREADGROUP`.
+fn parse_read_opts<'a>(args: &[&'a str],
+                       group: bool)
+                       -> ::std::result::Result<ReadOpts<'a>, Reply> {
+    let mut opts = ReadOpts {
+        count: usize::MAX,
+        block: None,
+        no_ack: false,
+        keys: Vec::new(),
+        ids: Vec::new(),
+    };
+    let mut i = 0;
+    loop {
+        let word = match args.get(i) {
+            Some(word) => word.to_uppercase(),
+            None => return Err(syntax_error()),
+        };
+        match &*word {
+            "COUNT" | "BLOCK" => {
+                let n = match args.get(i + 1).and_then(|s| s.parse::<u64>().ok()) {
+                    Some(n) => n,
+                    None => return Err(not_an_integer()),
+                };
+                if word == "COUNT" {
+                    opts.count = cmp::max(n, 1) as usize;
+                } else {
+                    opts.block = Some(Duration::from_millis(n));
+                }
+                i += 2;
+            }
+            "NOACK" if group => {
+                opts.no_ack = true;
+                i += 1;
+            }
+            "STREAMS" => {
+                i += 1;
+                break;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    let rest = &args[i..];
+    if rest.is_empty() || rest.len() % 2 == 1 {
+        return Err(err("Unbalanced 'xread' list of streams: for each stream key an ID or '$' \
+                        must be specified."));
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    opts.keys = keys.to_vec();
+    opts.ids = ids.to_vec();
+    Ok(opts)
+}
+
+// Entries with offsets from `start` to `end` inclusive, at most `count` of
+// them.
+fn read_range(queue: &Queue, start: u64, end: u64, count: usize) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut from = start;
+    while entries.len() < count && from <= end {
+        let chunk = try!(queue.entries(from, cmp::min(count - entries.len(), RANGE_CHUNK)));
+        let done = chunk.len() < RANGE_CHUNK;
+        for entry in chunk {
+            if entry.offset > end {
+                return Ok(entries);
+            }
+            let next = entry.offset.checked_add(1);
+            entries.push(entry);
+            match next {
+                Some(next) => from = next,
+                None => return Ok(entries),
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+fn entries_reply(entries: &[Entry]) -> Reply {
+    Reply::Array(entries.iter()
+                        .map(|e| {
+                            Reply::Array(vec![Reply::Bulk(format_id(e.offset).into_bytes()),
+                                              Reply::Array(vec![Reply::Bulk(b"data".to_vec()),
+                                                                Reply::Bulk(e.data.clone())])])
+                        })
+                        .collect())
+}
+
+fn format_id(offset: u64) -> String {
+    format!("{}-0", offset)
+}
+
+// Parses a stream ID, `<ms>-<seq>` or just `<ms>`, into its parts. The
+// first part is the offset.
+fn parse_id(s: &str) -> Option<(u64, u64)> {
+    let mut parts = s.splitn(2, '-');
+    parts.next().and_then(|ms| ms.parse().ok()).and_then(|ms| {
+        match parts.next() {
+            Some(seq) => seq.parse().ok().map(|seq| (ms, seq)),
+            None => Some((ms, 0)),
+        }
+    })
+}
+
+fn is_not_found(e: &::errors::Error) -> bool {
+    matches!(*e.kind(), ErrorKind::NotFound)
+}
+
+fn wrong_arity(cmd: &str) -> Reply {
+    err(&format!("wrong number of arguments for '{}' command", cmd.to_lowercase()))
+}
+
+fn invalid_id() -> Reply {
+    err("Invalid stream ID specified as stream command argument")
+}
+
+fn not_an_integer() -> Reply {
+    err("value is not an integer or out of range")
+}
+
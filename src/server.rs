@@ -0,0 +1,287 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::ffi::OsString;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::process;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use errors::{ErrorKind, Result};
+use protocol::{self, Request, Response};
+use replication;
+use {Consumer, Producer, Queue};
+
+// Limits on what one connection may ask of us.
+const DEFAULT_MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_SESSION_CONSUMERS: usize = 256;
+
+/// Serves a queue over TCP, using the protocol described in `protocol`.
+/// Each connection is handled on its own thread.
+#[derive(Debug)]
+pub struct Server {
+    queue: Queue,
+    listener: TcpListener,
+    max_poll_timeout: Duration,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(queue: &Queue, addr: A) -> Result<Server> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(Server {
+            queue: queue.clone(),
+            listener,
+            max_poll_timeout: DEFAULT_MAX_POLL_TIMEOUT,
+        })
+    }
+
+    /// The longest a client's poll may wait, however long it asks for.
+    /// Defaults to a minute.
+    pub fn max_poll_timeout(mut self, timeout: Duration) -> Self {
+        self.max_poll_timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            try!(stream.set_nodelay(true));
+            debug!("Accepted connection from {:?}", peer);
+            let queue = self.queue.clone();
+            let max_poll_timeout = self.max_poll_timeout;
+            try!(thread::Builder::new()
+                     .name("lmqueue-conn".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&queue, stream, max_poll_timeout) {
+                             warn!("Connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+/// Serves a queue over a Unix domain socket, using the same protocol as
+/// `Server`. Access is controlled by the socket file's permissions, so only
+/// processes that may write to it can use the queue. The socket is removed
+/// when the server is dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixServer {
+    queue: Queue,
+    path: PathBuf,
+    listener: UnixListener,
+    max_poll_timeout: Duration,
+}
+
+#[cfg(unix)]
+impl UnixServer {
+    /// Listens on a socket created at `path` with permissions `mode`. A
+    /// stale socket left behind by a server that has gone away is replaced,
+    /// but one that is still being served is not.
+    pub fn bind<P: AsRef<Path>>(queue: &Queue, path: P, mode: u32) -> Result<UnixServer> {
+        let path = path.as_ref();
+        try!(remove_stale_socket(path));
+        let listener = try!(bind_private(path, mode));
+        Ok(UnixServer {
+            queue: queue.clone(),
+            path: path.to_path_buf(),
+            listener,
+            max_poll_timeout: DEFAULT_MAX_POLL_TIMEOUT,
+        })
+    }
+
+    /// As `Server::max_poll_timeout`.
+    pub fn max_poll_timeout(mut self, timeout: Duration) -> Self {
+        self.max_poll_timeout = timeout;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            debug!("Accepted connection on {:?}", self.path);
+            let queue = self.queue.clone();
+            let path = self.path.clone();
+            let max_poll_timeout = self.max_poll_timeout;
+            try!(thread::Builder::new()
+                     .name("lmqueue-conn".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&queue, stream, max_poll_timeout) {
+                             warn!("Connection on {:?} failed: {}", path, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove socket {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Binds the socket inside a directory only we can enter, so that nobody can
+// connect before it has its permissions, then links it in at `path`. Unlike
+// a rename, linking won't replace whatever else may have appeared there.
+#[cfg(unix)]
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener> {
+    let name = try!(path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+    }));
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}.tmp", process::id()));
+    let dir = path.with_file_name(dir_name);
+    try!(fs::DirBuilder::new().mode(0o700).create(&dir));
+    let private = dir.join("socket");
+    let res = UnixListener::bind(&private).and_then(|listener| {
+        try!(fs::set_permissions(&private, fs::Permissions::from_mode(mode)));
+        try!(fs::hard_link(&private, path));
+        Ok(listener)
+    });
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!("Could not remove {:?}: {}", dir, e);
+    }
+    res.map_err(From::from)
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {}
+        // Let bind report on anything else that's in the way.
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            debug!("Removing stale socket {:?}", path);
+            try!(fs::remove_file(path));
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Answers requests arriving on `stream` until it is closed. Polls wait no
+/// longer than `max_poll_timeout`.
+pub fn serve<S: Read + Write>(queue: &Queue,
+                              mut stream: S,
+                              max_poll_timeout: Duration)
+                              -> Result<()> {
+    let mut session = Session {
+        queue: queue.clone(),
+        producer: queue.producer(),
+        consumers: Vec::new(),
+        max_poll_timeout,
+    };
+    while let Some(frame) = try!(protocol::read_frame(&mut stream)) {
+        let resp = match Request::decode(&frame) {
+            Ok(Request::Replicate(from)) => return replication::lead(queue, stream, from),
+            Ok(req) => {
+                trace!("Request: {:?}", req);
+                session.handle(req)
+            }
+            // We can't tell where the next frame starts, so give up.
+            Err(e) => {
+                let resp = Response::Error(ErrorKind::Remote(e.to_string()).into());
+                try!(protocol::write_frame(&mut stream, &try!(resp.encode())));
+                return Err(e.into());
+            }
+        };
+        try!(protocol::write_frame(&mut stream, &try!(resp.encode())));
+    }
+    debug!("Connection closed");
+    Ok(())
+}
+
+struct Session {
+    queue: Queue,
+    producer: Producer,
+    // Indexed by the ids handed out, along with the names they were opened
+    // with; opening a name again gives back the same id.
+    consumers: Vec<(String, Consumer)>,
+    max_poll_timeout: Duration,
+}
+
+impl Session {
+    fn handle(&mut self, req: Request) -> Response {
+        let res = match req {
+            Request::Produce(msg) => self.producer.produce(&msg).map(Response::Offset),
+            Request::ProduceBatch(msgs) => {
+                self.producer.produce_batch(&msgs).map(Response::Offsets)
+            }
+            Request::OpenConsumer(name) => self.open_consumer(name).map(Response::Consumer),
+            Request::Poll { consumer, timeout_ms } => {
+                let timeout = cmp::min(Duration::from_millis(timeout_ms), self.max_poll_timeout);
+                self.consumer(consumer).and_then(|consumer| {
+                    let res = if timeout_ms == 0 {
+                        consumer.poll()
+                    } else {
+                        consumer.poll_timeout(timeout)
+                    };
+                    res.map(Response::Entry)
+                })
+            }
+            Request::Commit { consumer, offset } => {
+                self.consumer(consumer)
+                    .and_then(|consumer| consumer.commit_offset(offset))
+                    .map(|()| Response::Done)
+            }
+            Request::ConsumerOffset(name) => {
+                self.queue.consumer_offset(&name).map(Response::Offset)
+            }
+            Request::Consumers => self.queue.consumers().map(Response::Consumers),
+            Request::Trim(limit) => self.queue.discard_upto(limit).map(|_| Response::Done),
+            Request::Replicate(_) => unreachable!("replication is handled by serve"),
+        };
+        res.unwrap_or_else(|e| {
+            debug!("Request failed: {}", e);
+            Response::Error(e)
+        })
+    }
+
+    fn open_consumer(&mut self, name: String) -> Result<u32> {
+        if let Some(id) = self.consumers.iter().position(|c| c.0 == name) {
+            return Ok(id as u32);
+        }
+        if self.consumers.len() >= MAX_SESSION_CONSUMERS {
+            let msg = format!("no more than {} consumers may be opened", MAX_SESSION_CONSUMERS);
+            return Err(ErrorKind::Remote(msg).into());
+        }
+        let consumer = try!(self.queue.consumer(&name));
+        self.consumers.push((name, consumer));
+        Ok((self.consumers.len() - 1) as u32)
+    }
+
+    fn consumer(&mut self, id: u32) -> Result<&mut Consumer> {
+        self.consumers
+            .get_mut(id as usize)
+            .map(|c| &mut c.1)
+            .ok_or_else(|| ErrorKind::Remote(format!("no consumer {} opened", id)).into())
+    }
+}
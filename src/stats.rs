@@ -0,0 +1,120 @@
+//! A summary of what a queue holds and how much space it takes, for sizing
+//! disks and spotting runaway growth.
+
+use lmdb_zero::Database;
+
+use errors::Result;
+use {Queue, WRITER_NEXT, decode_data_key, mdb_maybe, read_offset, totals};
+
+/// What `Queue::stats` found.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Stats {
+    /// The number of records held, and the total size of their messages.
+    pub records: u64,
+    pub payload_bytes: u64,
+    /// The offsets of the first and last records held.
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+    /// The last offset handed out to a producer.
+    pub writer_next: u64,
+    pub env: EnvStats,
+    /// Page usage of each of the queue's databases.
+    pub databases: Vec<DbStats>,
+}
+
+/// The LMDB environment as a whole.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct EnvStats {
+    pub map_size: u64,
+    pub page_size: u64,
+    /// The highest page number written so far.
+    pub last_page: u64,
+    /// Reader slots in use, and available.
+    pub readers: u32,
+    pub max_readers: u32,
+}
+
+impl EnvStats {
+    /// The part of the map written so far.
+    pub fn used_bytes(&self) -> u64 {
+        (self.last_page + 1) * self.page_size
+    }
+
+    /// The part of the map not yet written. Pages freed within the used
+    /// part are reused before this is, so aren't counted.
+    pub fn free_bytes(&self) -> u64 {
+        self.map_size.saturating_sub(self.used_bytes())
+    }
+}
+
+/// One database's B-tree.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DbStats {
+    pub name: String,
+    pub entries: u64,
+    pub depth: u32,
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    pub overflow_pages: u64,
+}
+
+impl DbStats {
+    pub fn pages(&self) -> u64 {
+        self.branch_pages + self.leaf_pages + self.overflow_pages
+    }
+}
+
+impl Queue {
+    /// Reads the running totals of records and payload, and gathers LMDB's
+    /// statistics for the environment and each database. None of this
+    /// depends on reading the records themselves.
+    pub fn stats(&self) -> Result<Stats> {
+        let data = self.data_db();
+        let meta = self.producers_db();
+        let dbs: [(&str, &Database); 3] = [("prod", meta),
+                                           ("cons", self.consumers_db()),
+                                           ("data", data)];
+        let info = try!(self.env().info());
+        let env = EnvStats {
+            map_size: info.mapsize as u64,
+            page_size: try!(self.env().stat()).psize as u64,
+            last_page: info.last_pgno as u64,
+            readers: info.numreaders,
+            max_readers: info.maxreaders,
+        };
+        self.read(|txn| {
+            let mut databases = Vec::with_capacity(dbs.len());
+            for db in &dbs {
+                let stat = try!(txn.db_stat(db.1));
+                databases.push(DbStats {
+                    name: db.0.to_string(),
+                    entries: stat.entries as u64,
+                    depth: stat.depth,
+                    branch_pages: stat.branch_pages as u64,
+                    leaf_pages: stat.leaf_pages as u64,
+                    overflow_pages: stat.overflow_pages as u64,
+                });
+            }
+            let mut cursor = try!(txn.cursor(data));
+            let access = txn.access();
+            let first = match try!(mdb_maybe(cursor.first::<[u8], [u8]>(&access))) {
+                Some((k, _)) => Some(try!(decode_data_key(k, 0))),
+                None => None,
+            };
+            let last = match try!(mdb_maybe(cursor.last::<[u8], [u8]>(&access))) {
+                Some((k, _)) => Some(try!(decode_data_key(k, first.unwrap_or(0)))),
+                None => None,
+            };
+            let totals = try!(totals::read(meta, &access));
+            Ok(Stats {
+                records: totals.records,
+                payload_bytes: totals.payload_bytes,
+                first,
+                last,
+                writer_next: try!(read_offset(meta, &access, WRITER_NEXT)),
+                env: env.clone(),
+                databases,
+            })
+        })
+    }
+}
@@ -0,0 +1,607 @@
+//! A STOMP 1.2 front-end, serving queues as destinations.
+//!
+//! `SEND` produces its body to the destination's queue. A `SUBSCRIBE`
+//! reads on from the committed offset of a named consumer: the one given by
+//! the `consumer` header, or else the one named by the subscription's `id`.
+//! Each `MESSAGE` carries its offset as its `message-id`.
+//!
+//! The ack modes map onto commits as follows:
+//!
+//! * `auto`: messages are committed as they are sent.
+//! * `client`: an `ACK` commits up to and including the acknowledged
+//!   message, just as `Consumer::commit_upto` does.
+//! * `client-individual`: an `ACK` acknowledges just that message, and the
+//!   consumer is committed up to the first message still unacknowledged.
+//!
+//! A `NACK` leaves a message unacknowledged, so it is sent again once the
+//! consumer next subscribes. Transactions are supported: their `SEND`s are
+//! produced in a single batch, and their `ACK`s applied, on `COMMIT`.
+//! Heart-beating isn't, and there is no authentication.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::Arc;
+use std::thread;
+
+use errors::Result;
+use {Consumer, Queue, RECHECK_INTERVAL};
+
+// Largest frame we'll accept.
+const MAX_FRAME: usize = 64 << 20;
+// Messages a subscription may have sent but unacknowledged.
+const MAX_PENDING: usize = 256;
+// Entries read from a queue at a time when delivering.
+const DELIVERY_BATCH: usize = 64;
+
+/// Serves queues to STOMP clients, as described in `stomp`. Each
+/// connection is handled on its own thread.
+#[derive(Debug)]
+pub struct StompServer {
+    destinations: BTreeMap<String, Queue>,
+    listener: TcpListener,
+}
+
+impl StompServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<StompServer> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(StompServer {
+            destinations: BTreeMap::new(),
+            listener,
+        })
+    }
+
+    /// Serves `queue` as the destination `name`, such as `/queue/orders`.
+    pub fn destination(mut self, name: &str, queue: &Queue) -> Self {
+        self.destinations.insert(name.to_string(), queue.clone());
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> Result<()> {
+        let destinations = Arc::new(self.destinations.clone());
+        for stream in self.listener.incoming() {
+            let stream = try!(stream);
+            let peer = try!(stream.peer_addr());
+            try!(stream.set_nodelay(true));
+            debug!("Accepted connection from {:?}", peer);
+            let destinations = destinations.clone();
+            try!(thread::Builder::new()
+                     .name("lmqueue-stomp".to_string())
+                     .spawn(move || {
+                         if let Err(e) = serve(&destinations, stream) {
+                             warn!("Connection from {:?} failed: {}", peer, e);
+                         }
+                     }));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    fn new(command: &str) -> Frame {
+        Frame {
+            command: command.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &str, value: &str) -> Frame {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Repeated headers take their first value.
+    fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|h| h.0 == name).map(|h| &*h.1)
+    }
+
+    fn require(&self, name: &str) -> ::std::result::Result<&str, String> {
+        self.get(name).ok_or_else(|| format!("{} frame has no {} header", self.command, name))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let escape = self.command != "CONNECTED";
+        let mut out = Vec::new();
+        out.extend_from_slice(self.command.as_bytes());
+        out.push(b'\n');
+        for header in &self.headers {
+            out.extend_from_slice(encode_header(&header.0, escape).as_bytes());
+            out.push(b':');
+            out.extend_from_slice(encode_header(&header.1, escape).as_bytes());
+            out.push(b'\n');
+        }
+        if !self.body.is_empty() {
+            out.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out.push(0);
+        out
+    }
+}
+
+fn encode_header(s: &str, escape: bool) -> String {
+    if !escape {
+        return s.to_string();
+    }
+    s.replace('\\', "\\\\").replace('\r', "\\r").replace('\n', "\\n").replace(':', "\\c")
+}
+
+fn decode_header(s: &str, escape: bool) -> ::std::result::Result<String, String> {
+    if !escape {
+        return Ok(s.to_string());
+    }
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some('c') => out.push(':'),
+            other => return Err(format!("undefined escape sequence \\{}", other.unwrap_or(' '))),
+        }
+    }
+    Ok(out)
+}
+
+// Reads frames from a stream with a read timeout, keeping any partial frame
+// until the rest arrives.
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    // The next frame, or `None` if one hasn't arrived by the timeout. A
+    // malformed frame is reported as `Ok(Err(reason))`, and the client hanging
+    // up as an `UnexpectedEof` error.
+    fn next(&mut self,
+            stream: &mut TcpStream)
+            -> io::Result<::std::result::Result<Option<Frame>, String>> {
+        loop {
+            match self.parse() {
+                Ok(Some(frame)) => return Ok(Ok(Some(frame))),
+                Ok(None) => (),
+                Err(reason) => return Ok(Err(reason)),
+            }
+            let mut chunk = [0; 4096];
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client hung up"))
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Ok(Ok(None)),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn parse(&mut self) -> ::std::result::Result<Option<Frame>, String> {
+        // Heart-beats, and the ends of lines after frames.
+        let skip = self.buf.iter().take_while(|&&b| b == b'\n' || b == b'\r').count();
+        self.buf.drain(..skip);
+        // Whichever blank line comes first ends the headers; the body may
+        // hold either.
+        let separator = [&b"\n\n"[..], &b"\r\n\r\n"[..]]
+                            .iter()
+                            .filter_map(|sep| find(&self.buf, sep).map(|i| (i, sep.len())))
+                            .min();
+        let (head_len, separator_len) = match separator {
+            Some(found) => found,
+            None if self.buf.len() > MAX_FRAME => return Err("frame too large".to_string()),
+            None => return Ok(None),
+        };
+        let head = try!(str::from_utf8(&self.buf[..head_len])
+                            .map_err(|_| "frame headers are not UTF-8".to_string()));
+        let mut lines = head.lines();
+        let command = lines.next().unwrap_or("").trim_end_matches('\r').to_string();
+        let escape = command != "CONNECT" && command != "STOMP";
+        let mut headers = Vec::new();
+        for line in lines {
+            let line = line.trim_end_matches('\r');
+            let colon = try!(line.find(':').ok_or_else(|| format!("bad header {:?}", line)));
+            headers.push((try!(decode_header(&line[..colon], escape)),
+                          try!(decode_header(&line[colon + 1..], escape))));
+        }
+        let body_start = head_len + separator_len;
+        let length = headers.iter()
+                            .find(|h| h.0 == "content-length")
+                            .map(|h| h.1.parse::<usize>());
+        let body_len = match length {
+            Some(Ok(len)) if len <= MAX_FRAME => len,
+            Some(_) => return Err("bad content-length".to_string()),
+            None => {
+                match self.buf[body_start..].iter().position(|&b| b == 0) {
+                    Some(len) => len,
+                    None if self.buf.len() > MAX_FRAME => {
+                        return Err("frame too large".to_string())
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+        if self.buf.len() < body_start + body_len + 1 {
+            return Ok(None);
+        }
+        if self.buf[body_start + body_len] != 0 {
+            return Err("frame body is not terminated".to_string());
+        }
+        let body = self.buf[body_start..body_start + body_len].to_vec();
+        self.buf.drain(..body_start + body_len + 1);
+        Ok(Some(Frame {
+            command,
+            headers,
+            body,
+        }))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum AckMode {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+struct Subscription {
+    id: String,
+    destination: String,
+    consumer: Consumer,
+    mode: AckMode,
+    // The last offset sent, and the last committed.
+    position: u64,
+    committed: u64,
+    // Offsets sent but not yet acknowledged.
+    pending: BTreeSet<u64>,
+}
+
+impl Subscription {
+    fn ack(&mut self, offset: u64) -> Result<()> {
+        if self.mode == AckMode::Client {
+            self.pending = self.pending.split_off(&(offset + 1));
+        } else {
+            self.pending.remove(&offset);
+        }
+        self.commit()
+    }
+
+    // Commits as far as every message sent has been acknowledged.
+    fn commit(&mut self) -> Result<()> {
+        let upto = match self.pending.iter().next() {
+            Some(&first) => first - 1,
+            None => self.position,
+        };
+        if upto > self.committed {
+            try!(self.consumer.commit_offset(upto));
+            self.committed = upto;
+        }
+        Ok(())
+    }
+}
+
+enum Action {
+    Send(String, Vec<u8>),
+    Ack(String),
+}
+
+struct Session<'a> {
+    destinations: &'a BTreeMap<String, Queue>,
+    subscriptions: Vec<Subscription>,
+    transactions: HashMap<String, Vec<Action>>,
+}
+
+fn serve(destinations: &BTreeMap<String, Queue>, mut stream: TcpStream) -> Result<()> {
+    try!(stream.set_read_timeout(Some(RECHECK_INTERVAL)));
+    let mut reader = FrameReader { buf: Vec::new() };
+    let mut session = Session {
+        destinations,
+        subscriptions: Vec::new(),
+        transactions: HashMap::new(),
+    };
+    let mut connected = false;
+    loop {
+        if connected {
+            try!(session.deliver(&mut stream));
+        }
+        let frame = match reader.next(&mut stream) {
+            Ok(Ok(Some(frame))) => frame,
+            Ok(Ok(None)) => continue,
+            Ok(Err(reason)) => return refuse(&mut stream, None, &reason),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        trace!("Frame: {}", frame.command);
+        let res = match (&*frame.command, connected) {
+            ("CONNECT", false) | ("STOMP", false) => {
+                // Without accept-version, a client only speaks STOMP 1.0.
+                let versions = frame.get("accept-version").unwrap_or("1.0");
+                if !versions.split(',').any(|v| v == "1.2") {
+                    let reply = Frame::new("ERROR")
+                                    .header("version", "1.2")
+                                    .header("message", "supported protocol versions are 1.2");
+                    try!(stream.write_all(&reply.encode()));
+                    return Ok(());
+                }
+                connected = true;
+                let reply = Frame::new("CONNECTED")
+                                .header("version", "1.2")
+                                .header("heart-beat", "0,0")
+                                .header("server", "lmqueue");
+                try!(stream.write_all(&reply.encode()));
+                continue;
+            }
+            (_, false) => Err("expected CONNECT".to_string()),
+            ("DISCONNECT", true) => {
+                try!(send_receipt(&mut stream, &frame));
+                break;
+            }
+            (_, true) => try!(session.handle(&frame)),
+        };
+        match res {
+            Ok(()) => try!(send_receipt(&mut stream, &frame)),
+            Err(reason) => return refuse(&mut stream, frame.get("receipt"), &reason),
+        }
+    }
+    debug!("Connection closed");
+    Ok(())
+}
+
+// Reports a failure to the client, then gives up on the connection.
+fn refuse(stream: &mut TcpStream, receipt: Option<&str>, reason: &str) -> Result<()> {
+    debug!("Refusing client: {}", reason);
+    let mut frame = Frame::new("ERROR").header("message", reason);
+    if let Some(receipt) = receipt {
+        frame = frame.header("receipt-id", receipt);
+    }
+    try!(stream.write_all(&frame.encode()));
+    Ok(())
+}
+
+fn send_receipt(stream: &mut TcpStream, frame: &Frame) -> io::Result<()> {
+    match frame.get("receipt") {
+        Some(receipt) => {
+            stream.write_all(&Frame::new("RECEIPT").header("receipt-id", receipt).encode())
+        }
+        None => Ok(()),
+    }
+}
+
+// The outer error is for failures of the queue; the inner one refuses the
+// client's frame.
+type Handled = Result<::std::result::Result<(), String>>;
+
+impl<'a> Session<'a> {
+    fn handle(&mut self, frame: &Frame) -> Handled {
+        let transaction = frame.get("transaction");
+        match &*frame.command {
+            "SEND" => {
+                let destination = match frame.require("destination") {
+                    Ok(destination) => destination,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                let queue = match self.destinations.get(destination) {
+                    Some(queue) => queue,
+                    None => return Ok(Err(format!("no such destination {:?}", destination))),
+                };
+                match transaction {
+                    Some(tx) => {
+                        self.in_transaction(tx, Action::Send(destination.to_string(),
+                                                             frame.body.clone()))
+                    }
+                    None => {
+                        try!(queue.producer().produce(&frame.body));
+                        Ok(Ok(()))
+                    }
+                }
+            }
+            "SUBSCRIBE" => self.subscribe(frame),
+            "UNSUBSCRIBE" => {
+                let id = match frame.require("id") {
+                    Ok(id) => id,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                match self.subscriptions.iter().position(|s| s.id == id) {
+                    Some(i) => {
+                        self.subscriptions.remove(i);
+                        Ok(Ok(()))
+                    }
+                    None => Ok(Err(format!("no subscription {:?}", id))),
+                }
+            }
+            "ACK" => {
+                let id = match frame.require("id") {
+                    Ok(id) => id,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                match transaction {
+                    Some(tx) => self.in_transaction(tx, Action::Ack(id.to_string())),
+                    None => self.ack(id),
+                }
+            }
+            // Left unacknowledged, so sent again on the next subscription.
+            "NACK" => {
+                match frame.require("id") {
+                    Ok(_) => Ok(Ok(())),
+                    Err(reason) => Ok(Err(reason)),
+                }
+            }
+            "BEGIN" => {
+                let tx = match frame.require("transaction") {
+                    Ok(tx) => tx,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                if self.transactions.contains_key(tx) {
+                    return Ok(Err(format!("transaction {:?} already begun", tx)));
+                }
+                self.transactions.insert(tx.to_string(), Vec::new());
+                Ok(Ok(()))
+            }
+            "COMMIT" => {
+                let actions = match frame.require("transaction")
+                                         .and_then(|tx| self.end_transaction(tx)) {
+                    Ok(actions) => actions,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                self.commit(actions)
+            }
+            "ABORT" => {
+                match frame.require("transaction").and_then(|tx| self.end_transaction(tx)) {
+                    Ok(_) => Ok(Ok(())),
+                    Err(reason) => Ok(Err(reason)),
+                }
+            }
+            command => Ok(Err(format!("unknown command {:?}", command))),
+        }
+    }
+
+    fn in_transaction(&mut self, tx: &str, action: Action) -> Handled {
+        match self.transactions.get_mut(tx) {
+            Some(actions) => {
+                actions.push(action);
+                Ok(Ok(()))
+            }
+            None => Ok(Err(format!("no transaction {:?}", tx))),
+        }
+    }
+
+    fn end_transaction(&mut self, tx: &str) -> ::std::result::Result<Vec<Action>, String> {
+        self.transactions.remove(tx).ok_or_else(|| format!("no transaction {:?}", tx))
+    }
+
+    // Produces a transaction's messages, a batch per destination, then
+    // applies its acknowledgements.
+    fn commit(&mut self, actions: Vec<Action>) -> Handled {
+        let mut batches: BTreeMap<&str, Vec<&[u8]>> = BTreeMap::new();
+        for action in &actions {
+            if let Action::Send(ref destination, ref body) = *action {
+                batches.entry(destination).or_default().push(body);
+            }
+        }
+        for (destination, batch) in batches {
+            try!(self.destinations[destination].producer().produce_batch(&batch));
+        }
+        for action in &actions {
+            if let Action::Ack(ref id) = *action {
+                match try!(self.ack(id)) {
+                    Ok(()) => (),
+                    refused => return Ok(refused),
+                }
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    fn subscribe(&mut self, frame: &Frame) -> Handled {
+        let (id, destination) = match frame.require("id")
+                                           .and_then(|id| {
+                                               frame.require("destination").map(|d| (id, d))
+                                           }) {
+            Ok(headers) => headers,
+            Err(reason) => return Ok(Err(reason)),
+        };
+        let queue = match self.destinations.get(destination) {
+            Some(queue) => queue,
+            None => return Ok(Err(format!("no such destination {:?}", destination))),
+        };
+        let mode = match frame.get("ack").unwrap_or("auto") {
+            "auto" => AckMode::Auto,
+            "client" => AckMode::Client,
+            "client-individual" => AckMode::ClientIndividual,
+            mode => return Ok(Err(format!("unknown ack mode {:?}", mode))),
+        };
+        if self.subscriptions.iter().any(|s| s.id == id) {
+            return Ok(Err(format!("subscription {:?} already exists", id)));
+        }
+        let consumer = try!(queue.consumer(frame.get("consumer").unwrap_or(id)));
+        let offset = consumer.offset;
+        self.subscriptions.push(Subscription {
+            id: id.to_string(),
+            destination: destination.to_string(),
+            consumer,
+            mode,
+            position: offset,
+            committed: offset,
+            pending: BTreeSet::new(),
+        });
+        Ok(Ok(()))
+    }
+
+    // Acknowledges the message with ack ID `id`, which names the
+    // subscription and the offset.
+    fn ack(&mut self, id: &str) -> Handled {
+        let parsed = id.rfind(':').and_then(|i| {
+            id[i + 1..].parse::<u64>().ok().map(|offset| (&id[..i], offset))
+        });
+        let (sub, offset) = match parsed {
+            Some(parsed) => parsed,
+            None => return Ok(Err(format!("bad ack ID {:?}", id))),
+        };
+        match self.subscriptions.iter_mut().find(|s| s.id == sub) {
+            Some(ref mut subscription) if subscription.pending.contains(&offset) => {
+                try!(subscription.ack(offset));
+                Ok(Ok(()))
+            }
+            // Already acknowledged.
+            Some(_) => Ok(Ok(())),
+            None => Ok(Err(format!("no subscription {:?}", sub))),
+        }
+    }
+
+    // Sends each subscription whatever has arrived for it.
+    fn deliver(&mut self, stream: &mut TcpStream) -> Result<()> {
+        for subscription in &mut self.subscriptions {
+            if subscription.pending.len() >= MAX_PENDING {
+                continue;
+            }
+            let limit = ::std::cmp::min(DELIVERY_BATCH, MAX_PENDING - subscription.pending.len());
+            let entries = try!(subscription.consumer
+                                           .queue
+                                           .entries(subscription.position + 1, limit));
+            if entries.is_empty() {
+                continue;
+            }
+            let mut out = Vec::new();
+            for entry in entries {
+                let offset = entry.offset.to_string();
+                let mut message = Frame::new("MESSAGE")
+                                      .header("subscription", &subscription.id)
+                                      .header("message-id", &offset)
+                                      .header("destination", &subscription.destination);
+                if subscription.mode != AckMode::Auto {
+                    message = message.header("ack", &format!("{}:{}", subscription.id, offset));
+                    subscription.pending.insert(entry.offset);
+                }
+                message.body = entry.data;
+                out.extend_from_slice(&message.encode());
+                subscription.position = entry.offset;
+            }
+            try!(stream.write_all(&out));
+            try!(subscription.commit());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Running totals of the records a queue holds and the size of their
+//! messages, kept in `prod` under `record-count` and `payload-bytes`. They
+//! are updated in the same transaction as the records they count, so can be
+//! read without scanning `data`.
+
+use lmdb_zero::{ConstAccessor, Database, WriteAccessor, WriteTransaction};
+
+use errors::Result;
+use {Queue, envelope, mdb_maybe, read_offset, write_offset};
+
+const RECORD_COUNT: &str = "record-count";
+const PAYLOAD_BYTES: &str = "payload-bytes";
+
+/// How much a queue holds, as returned by `Queue::totals`.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct Totals {
+    /// The number of records held.
+    pub records: u64,
+    /// The total size of their messages, not counting checksums.
+    pub payload_bytes: u64,
+}
+
+pub fn read(meta: &Database, access: &ConstAccessor) -> Result<Totals> {
+    Ok(Totals {
+        records: try!(read_offset(meta, access, RECORD_COUNT)),
+        payload_bytes: try!(read_offset(meta, access, PAYLOAD_BYTES)),
+    })
+}
+
+fn write(meta: &Database, access: &mut WriteAccessor, totals: Totals) -> Result<()> {
+    try!(write_offset(meta, access, RECORD_COUNT, totals.records));
+    write_offset(meta, access, PAYLOAD_BYTES, totals.payload_bytes)
+}
+
+/// Counts `records` more records, with `payload_bytes` between them.
+pub fn add(meta: &Database,
+           access: &mut WriteAccessor,
+           records: u64,
+           payload_bytes: u64)
+           -> Result<()> {
+    let mut totals = try!(read(meta, access));
+    totals.records += records;
+    totals.payload_bytes += payload_bytes;
+    write(meta, access, totals)
+}
+
+/// Stops counting `records` records, with `payload_bytes` between them.
+pub fn remove(meta: &Database,
+              access: &mut WriteAccessor,
+              records: u64,
+              payload_bytes: u64)
+              -> Result<()> {
+    let mut totals = try!(read(meta, access));
+    totals.records = totals.records.saturating_sub(records);
+    totals.payload_bytes = totals.payload_bytes.saturating_sub(payload_bytes);
+    write(meta, access, totals)
+}
+
+/// Migrates from format version 2, which kept no totals.
+pub fn count_records(queue: &Queue, txn: &WriteTransaction) -> Result<()> {
+    let mut totals = Totals::default();
+    {
+        let mut cursor = try!(txn.cursor(queue.data_db()));
+        let access = txn.access();
+        let mut curr = try!(mdb_maybe(cursor.first::<[u8], [u8]>(&access)));
+        while let Some((_, v)) = curr {
+            totals.records += 1;
+            totals.payload_bytes += envelope::payload_len(v) as u64;
+            curr = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&access)));
+        }
+    }
+    debug!("Counted {:?}", totals);
+    write(queue.producers_db(), &mut txn.access(), totals)
+}
+
+impl Queue {
+    /// The number of records held and the size of their messages. These
+    /// are kept up to date as records are produced and discarded, so this
+    /// takes the same time however large the queue is.
+    pub fn totals(&self) -> Result<Totals> {
+        let meta = self.producers_db();
+        self.read(|txn| read(meta, &txn.access()))
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+
+use errors::{Error, ErrorKind, Result};
+use totals::{self, Totals};
+use {Queue, WRITER_NEXT, decode_key, decode_offset, envelope, mdb_maybe};
+
+/// Something wrong found by `Queue::verify`.
+#[derive(Debug,Clone,Eq,PartialEq)]
+pub enum Problem {
+    /// A key in the data database is not an offset. `after` is the last
+    /// good offset before it, or zero if there is none.
+    BadKey { after: u64 },
+    /// The record at `offset` is too short to hold a checksum.
+    Truncated { offset: u64 },
+    /// The record at `offset` does not match its checksum.
+    ChecksumMismatch {
+        offset: u64,
+        stored: u32,
+        computed: u32,
+    },
+    /// `writer-next` can't be decoded.
+    BadWriterNext,
+    /// `writer-next` is behind the last record, so producing would collide
+    /// with existing records.
+    WriterBehind { writer_next: u64, last: u64 },
+    /// The running totals kept in `prod` don't match the records found.
+    WrongTotals { stored: Totals, counted: Totals },
+}
+
+/// The outcome of `Queue::verify`.
+#[derive(Debug,Clone,Default)]
+pub struct Verification {
+    /// The number of records examined.
+    pub records: u64,
+    /// The highest offset found.
+    pub last: Option<u64>,
+    pub problems: Vec<Problem>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Queue {
+    /// Reads every record in the queue, checking that its key is a valid
+    /// offset and that it matches its checksum, that `writer-next` is not
+    /// behind the last record, and that the running totals add up. Runs in
+    /// a single read transaction, so sees a consistent snapshot.
+    pub fn verify(&self) -> Result<Verification> {
+        let data = self.data_db();
+        let meta = self.producers_db();
+        self.read(|txn| {
+            let mut report = Verification::default();
+            let mut counted = Totals::default();
+            let mut cursor = try!(txn.cursor(data));
+            let access = txn.access();
+            let mut curr = try!(mdb_maybe(cursor.first::<[u8], [u8]>(&access)));
+            while let Some((k, v)) = curr {
+                report.records += 1;
+                counted.records += 1;
+                counted.payload_bytes += envelope::payload_len(v) as u64;
+                match decode_key(k) {
+                    Some(offset) => {
+                        report.last = Some(offset);
+                        if let Err(e) = envelope::unseal(offset, v) {
+                            report.problems.push(try!(record_problem(offset, e)));
+                        }
+                    }
+                    None => {
+                        report.problems.push(Problem::BadKey { after: report.last.unwrap_or(0) })
+                    }
+                }
+                curr = try!(mdb_maybe(cursor.next::<[u8], [u8]>(&access)));
+            }
+
+            let writer_next = match try!(mdb_maybe(access.get::<str, [u8]>(meta, WRITER_NEXT))) {
+                Some(val) => decode_offset(val, WRITER_NEXT).ok(),
+                None => Some(0),
+            };
+            match (writer_next, report.last) {
+                (None, _) => report.problems.push(Problem::BadWriterNext),
+                (Some(writer_next), Some(last)) if writer_next < last => {
+                    report.problems.push(Problem::WriterBehind {
+                        writer_next,
+                        last,
+                    })
+                }
+                _ => (),
+            }
+            let stored = try!(totals::read(meta, &access));
+            if stored != counted {
+                report.problems.push(Problem::WrongTotals {
+                    stored,
+                    counted,
+                })
+            }
+            Ok(report)
+        })
+    }
+}
+
+// Anything other than a damaged record means we couldn't check it at all.
+fn record_problem(offset: u64, e: Error) -> Result<Problem> {
+    match *e.kind() {
+        ErrorKind::CorruptRecord { .. } => Ok(Problem::Truncated { offset }),
+        ErrorKind::ChecksumMismatch { offset, stored, computed } => {
+            Ok(Problem::ChecksumMismatch {
+                offset,
+                stored,
+                computed,
+            })
+        }
+        _ => Err(e),
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BadKey { after } => write!(f, "undecodable key after offset {}", after),
+            Problem::Truncated { offset } => write!(f, "record {} is truncated", offset),
+            Problem::ChecksumMismatch { offset, stored, computed } => {
+                write!(f,
+                       "record {} has checksum {:08x}, expected {:08x}",
+                       offset,
+                       computed,
+                       stored)
+            }
+            Problem::BadWriterNext => write!(f, "{} is corrupt", WRITER_NEXT),
+            Problem::WriterBehind { writer_next, last } => {
+                write!(f,
+                       "{} is {}, behind the last record at {}",
+                       WRITER_NEXT,
+                       writer_next,
+                       last)
+            }
+            Problem::WrongTotals { stored, counted } => {
+                write!(f,
+                       "totals are {} records of {} bytes, but found {} records of {} bytes",
+                       stored.records,
+                       stored.payload_bytes,
+                       counted.records,
+                       counted.payload_bytes)
+            }
+        }
+    }
+}
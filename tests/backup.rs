@@ -0,0 +1,133 @@
+extern crate lmqueue;
+extern crate lmdb_zero;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use lmqueue::{ErrorKind, QueueOptions, FORMAT_VERSION};
+
+fn all_data(queue: &lmqueue::Queue) -> Vec<Vec<u8>> {
+    let mut cons = queue.consumer("backup-test").expect("consumer");
+    let mut out = Vec::new();
+    while let Some(entry) = cons.poll().expect("poll") {
+        out.push(entry.data);
+    }
+    out
+}
+
+#[test]
+fn can_back_up_while_producing() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let backups = tempdir::TempDir::new("backups").expect("backup-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+
+    let done = Arc::new(AtomicBool::new(false));
+    let producer = {
+        let mut prod = queue.producer();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut n = 0u64;
+            while !done.load(Ordering::SeqCst) {
+                prod.produce(n.to_string().as_bytes()).expect("produce");
+                n += 1;
+            }
+        })
+    };
+    let target = backups.path().join("copy");
+    queue.backup_to(&target, false).expect("backup");
+    done.store(true, Ordering::SeqCst);
+    producer.join().expect("producer thread");
+
+    let copy = lmqueue::Queue::open(&target).expect("open copy");
+    assert!(copy.verify().expect("verify").is_ok());
+    // The copy holds some unbroken prefix of what was produced.
+    let data = all_data(&copy);
+    let expected = (0..data.len()).map(|n| n.to_string().into_bytes()).collect::<Vec<_>>();
+    assert_eq!(data, expected);
+}
+
+#[test]
+fn compacting_backup_keeps_remaining_entries() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let backups = tempdir::TempDir::new("backups").expect("backup-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    for _ in 0..64 {
+        prod.produce(&[0; 1024]).expect("produce");
+    }
+    prod.produce(b"last").expect("produce");
+    let cons = queue.consumer("default").expect("consumer");
+    cons.discard_upto(64).expect("discard");
+
+    let target = backups.path().join("compact");
+    queue.backup_to(&target, true).expect("backup");
+
+    let copy = lmqueue::Queue::open(&target).expect("open copy");
+    assert_eq!(all_data(&copy), vec![b"last".to_vec()]);
+}
+
+#[test]
+fn restore_refuses_to_overwrite_a_queue() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let backups = tempdir::TempDir::new("backups").expect("backup-dir");
+    let target = backups.path().join("copy");
+    {
+        let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+        queue.producer().produce(b"0").expect("produce");
+        queue.backup_to(&target, false).expect("backup");
+    }
+
+    let err = QueueOptions::new().restore(&target, dir.path()).expect_err("restore should fail");
+    match *err.kind() {
+        ErrorKind::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+        ref other => panic!("expected AlreadyExists, got {:?}", other),
+    }
+
+    let restored_dir = backups.path().join("restored");
+    let restored = QueueOptions::new().restore(&target, &restored_dir).expect("restore");
+    assert_eq!(all_data(&restored), vec![b"0".to_vec()]);
+}
+
+#[test]
+fn restore_checks_format_version() {
+    env_logger::init().unwrap_or(());
+    let backups = tempdir::TempDir::new("backups").expect("backup-dir");
+    let backup = backups.path().join("copy");
+    {
+        let dir = tempdir::TempDir::new("store").expect("store-dir");
+        let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+        queue.backup_to(&backup, false).expect("backup");
+    }
+    {
+        let mut b = lmdb_zero::EnvBuilder::new().expect("env builder");
+        b.set_maxdbs(3).expect("maxdbs");
+        let env = Arc::new(unsafe {
+            b.open(backup.to_str().expect("path string"),
+                   lmdb_zero::open::Flags::empty(),
+                   0o600)
+             .expect("env")
+        });
+        let db = lmdb_zero::Database::open(env.clone(),
+                                           Some("prod"),
+                                           &lmdb_zero::DatabaseOptions::new(lmdb_zero::db::CREATE))
+                     .expect("prod db");
+        let txn = lmdb_zero::WriteTransaction::new(env.clone()).expect("txn");
+        let future = [0, 0, 0, 0, 0, 0, 0, (FORMAT_VERSION + 1) as u8];
+        txn.access()
+           .put(&db, &b"format-version"[..], &future[..], lmdb_zero::put::Flags::empty())
+           .expect("put");
+        txn.commit().expect("commit");
+    }
+
+    let restored_dir = backups.path().join("restored");
+    let err = QueueOptions::new().restore(&backup, &restored_dir).expect_err("restore should fail");
+    assert!(matches!(*err.kind(), ErrorKind::UnsupportedFormat { .. }));
+    assert!(!restored_dir.join("data.mdb").exists());
+}
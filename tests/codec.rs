@@ -0,0 +1,117 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::str;
+
+use lmqueue::{Codec, ErrorKind, TypedConsumer, TypedProducer};
+
+#[derive(Debug,Clone,Copy)]
+struct Utf8Codec;
+
+impl Codec<String> for Utf8Codec {
+    type Error = str::Utf8Error;
+
+    fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> {
+        Ok(value.as_bytes().to_vec())
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> {
+        str::from_utf8(bytes).map(|s| s.to_string())
+    }
+}
+
+#[test]
+fn can_produce_and_consume_typed_values() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = TypedProducer::new(queue.producer(), Utf8Codec);
+    let mut cons = TypedConsumer::new(queue.consumer("default").expect("consumer"), Utf8Codec);
+    prod.produce(&"hello".to_string()).expect("produce");
+    prod.produce(&"world".to_string()).expect("produce");
+
+    let first = cons.poll().expect("poll").expect("some entry");
+    assert_eq!(first.value, "hello");
+    cons.commit_upto(&first).expect("commit");
+    assert_eq!(queue.consumer_offset("default").expect("offset"), first.offset);
+
+    assert_eq!(cons.poll().expect("poll").map(|e| e.value), Some("world".to_string()));
+    assert_eq!(cons.poll().expect("poll").map(|e| e.value), None);
+}
+
+#[test]
+fn reports_decode_failure_with_offset() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut raw = queue.producer();
+    raw.produce(b"ok").expect("produce");
+    raw.produce(b"\xff\xfe").expect("produce");
+    raw.produce(b"fine").expect("produce");
+
+    let mut cons = TypedConsumer::new(queue.consumer("default").expect("consumer"), Utf8Codec);
+    let first = cons.poll().expect("poll").expect("some entry");
+    assert_eq!(first.value, "ok");
+
+    let err = cons.poll().expect_err("decode should fail");
+    match *err.kind() {
+        ErrorKind::Decode { offset, .. } => assert_eq!(offset, first.offset + 1),
+        ref other => panic!("expected Decode, got {:?}", other),
+    }
+    assert_eq!(err.consumer(), Some("default"));
+    assert_eq!(err.path(), Some(dir.path()));
+
+    // The bad entry is skipped over.
+    assert_eq!(cons.poll().expect("poll").map(|e| e.value), Some("fine".to_string()));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn can_use_json_codec() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = TypedProducer::new(queue.producer(), lmqueue::JsonCodec);
+    prod.produce(&("answer".to_string(), 42u32)).expect("produce");
+
+    let mut raw = queue.consumer("raw").expect("consumer");
+    assert_eq!(raw.poll().expect("poll").map(|e| e.data), Some(b"[\"answer\",42]".to_vec()));
+
+    let mut cons = TypedConsumer::new(queue.consumer("default").expect("consumer"),
+                                      lmqueue::JsonCodec);
+    let entry: lmqueue::TypedEntry<(String, u32)> = cons.poll().expect("poll").expect("entry");
+    assert_eq!(entry.value, ("answer".to_string(), 42));
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn can_use_bincode_codec() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = TypedProducer::new(queue.producer(), lmqueue::BincodeCodec);
+    prod.produce(&vec![1u64, 2, 3]).expect("produce");
+
+    let mut cons = TypedConsumer::new(queue.consumer("default").expect("consumer"),
+                                      lmqueue::BincodeCodec);
+    let entry: lmqueue::TypedEntry<Vec<u64>> = cons.poll().expect("poll").expect("entry");
+    assert_eq!(entry.value, vec![1, 2, 3]);
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn reports_bincode_decode_failure() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    queue.producer().produce(b"\x01").expect("produce");
+
+    let mut cons: TypedConsumer<u64, _> =
+        TypedConsumer::new(queue.consumer("default").expect("consumer"),
+                           lmqueue::BincodeCodec);
+    let err = cons.poll().expect_err("decode should fail");
+    match *err.kind() {
+        ErrorKind::Decode { offset, .. } => assert_eq!(offset, 1),
+        ref other => panic!("expected Decode, got {:?}", other),
+    }
+}
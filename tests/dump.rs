@@ -0,0 +1,97 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io;
+
+use lmqueue::ErrorKind;
+
+fn dump_of(queue: &lmqueue::Queue) -> Vec<u8> {
+    let mut out = Vec::new();
+    queue.dump_to(&mut out).expect("dump");
+    out
+}
+
+#[test]
+fn load_preserves_offsets_and_consumers() {
+    env_logger::init().unwrap_or(());
+    let src = tempdir::TempDir::new("src").expect("src-dir");
+    let dst = tempdir::TempDir::new("dst").expect("dst-dir");
+    let queue = lmqueue::Queue::open(src.path()).expect("queue");
+    let mut prod = queue.producer();
+    for n in 0..5u8 {
+        prod.produce(&[n]).expect("produce");
+    }
+    let mut cons = queue.consumer("one").expect("consumer");
+    let first = cons.poll().expect("poll").expect("entry");
+    cons.commit_upto(&first).expect("commit");
+    cons.discard_upto(2).expect("discard");
+
+    let dump = dump_of(&queue);
+    let copy = lmqueue::Queue::open(dst.path()).expect("copy");
+    assert_eq!(copy.load_from(&dump[..]).expect("load"), 3);
+
+    assert_eq!(copy.consumer_offset("one").expect("offset"), first.offset);
+    let mut cons = copy.consumer("two").expect("consumer");
+    let mut entries = Vec::new();
+    while let Some(entry) = cons.poll().expect("poll") {
+        entries.push((entry.offset, entry.data));
+    }
+    assert_eq!(entries, vec![(3, vec![2]), (4, vec![3]), (5, vec![4])]);
+
+    copy.producer().produce(b"next").expect("produce");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.offset), Some(6));
+}
+
+#[test]
+fn refuses_to_load_into_used_queue() {
+    env_logger::init().unwrap_or(());
+    let src = tempdir::TempDir::new("src").expect("src-dir");
+    let queue = lmqueue::Queue::open(src.path()).expect("queue");
+    queue.producer().produce(b"0").expect("produce");
+
+    let dump = dump_of(&queue);
+    let err = queue.load_from(&dump[..]).expect_err("load should fail");
+    assert_eq!(err.path(), Some(src.path()));
+    match *err.kind() {
+        ErrorKind::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+        ref other => panic!("expected AlreadyExists, got {:?}", other),
+    }
+}
+
+#[test]
+fn rejects_truncated_dump() {
+    env_logger::init().unwrap_or(());
+    let src = tempdir::TempDir::new("src").expect("src-dir");
+    let dst = tempdir::TempDir::new("dst").expect("dst-dir");
+    let queue = lmqueue::Queue::open(src.path()).expect("queue");
+    queue.producer().produce(b"0").expect("produce");
+
+    let dump = dump_of(&queue);
+    let copy = lmqueue::Queue::open(dst.path()).expect("copy");
+    let err = copy.load_from(&dump[..dump.len() - 1]).expect_err("load should fail");
+    match *err.kind() {
+        ErrorKind::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        ref other => panic!("expected UnexpectedEof, got {:?}", other),
+    }
+}
+
+#[test]
+fn rejects_corrupted_record() {
+    env_logger::init().unwrap_or(());
+    let src = tempdir::TempDir::new("src").expect("src-dir");
+    let dst = tempdir::TempDir::new("dst").expect("dst-dir");
+    let queue = lmqueue::Queue::open(src.path()).expect("queue");
+    queue.producer().produce(b"hello").expect("produce");
+
+    let mut dump = dump_of(&queue);
+    // The last byte of the message, just before the end marker.
+    let at = dump.len() - 2;
+    dump[at] ^= 0x01;
+    let copy = lmqueue::Queue::open(dst.path()).expect("copy");
+    let err = copy.load_from(&dump[..]).expect_err("load should fail");
+    match *err.kind() {
+        ErrorKind::ChecksumMismatch { offset, .. } => assert_eq!(offset, 1),
+        ref other => panic!("expected ChecksumMismatch, got {:?}", other),
+    }
+}
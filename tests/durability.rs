@@ -0,0 +1,122 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::env;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::Duration;
+
+use lmqueue::{Durability, QueueOptions};
+
+const CRASH_DIR: &str = "LMQUEUE_CRASH_DIR";
+const CRASH_DURABILITY: &str = "LMQUEUE_CRASH_DURABILITY";
+
+fn durability_named(name: &str) -> Durability {
+    match name {
+        "full" => Durability::Full,
+        "no-meta-sync" => Durability::NoMetaSync,
+        "async" => Durability::Async,
+        other => panic!("unknown durability: {:?}", other),
+    }
+}
+
+// Only does anything when re-run by `crash_after_producing`: it produces
+// three messages, and then dies without unwinding or closing the
+// environment.
+#[test]
+fn crashing_producer() {
+    let (dir, durability) = match (env::var(CRASH_DIR), env::var(CRASH_DURABILITY)) {
+        (Ok(dir), Ok(durability)) => (dir, durability),
+        _ => return,
+    };
+    let queue = QueueOptions::new()
+                    .durability(durability_named(&durability))
+                    .open(&dir)
+                    .expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+    prod.produce(b"2").expect("produce");
+    process::abort();
+}
+
+fn crash_after_producing(dir: &Path, durability: &str) {
+    let status = Command::new(env::current_exe().expect("current_exe"))
+                     .args(["--exact", "crashing_producer", "--nocapture", "--test-threads=1"])
+                     .env(CRASH_DIR, dir)
+                     .env(CRASH_DURABILITY, durability)
+                     .status()
+                     .expect("spawn crashing producer");
+    assert!(!status.success(), "producer should have crashed: {:?}", status);
+}
+
+fn assert_survives_process_crash(durability: &str) {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    crash_after_producing(dir.path(), durability);
+
+    let queue = QueueOptions::new()
+                    .durability(durability_named(durability))
+                    .open(dir.path())
+                    .expect("queue");
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"1".to_vec()));
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"2".to_vec()));
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None);
+}
+
+#[test]
+fn full_durability_survives_process_crash() {
+    assert_survives_process_crash("full");
+}
+
+#[test]
+fn no_meta_sync_survives_process_crash() {
+    assert_survives_process_crash("no-meta-sync");
+}
+
+#[test]
+fn async_survives_process_crash() {
+    assert_survives_process_crash("async");
+}
+
+#[test]
+fn durability_is_reported_by_queue() {
+    env_logger::init().unwrap_or(());
+    for durability in &[Durability::Full, Durability::NoMetaSync, Durability::Async] {
+        let dir = tempdir::TempDir::new("store").expect("store-dir");
+        let queue = QueueOptions::new().durability(*durability).open(dir.path()).expect("queue");
+        assert_eq!(queue.durability().expect("durability"), *durability);
+    }
+}
+
+#[test]
+fn can_sync_explicitly() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = QueueOptions::new().durability(Durability::Async).open(dir.path()).expect("queue");
+    queue.producer().produce(b"0").expect("produce");
+    queue.sync(true).expect("sync");
+    queue.sync(false).expect("sync");
+}
+
+#[test]
+fn can_sync_periodically() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    {
+        let queue = QueueOptions::new()
+                        .durability(Durability::Async)
+                        .sync_every(Duration::from_millis(1))
+                        .open(dir.path())
+                        .expect("queue");
+        queue.producer().produce(b"0").expect("produce");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
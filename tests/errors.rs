@@ -0,0 +1,151 @@
+extern crate lmqueue;
+extern crate lmdb_zero;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::sync::Arc;
+
+use lmqueue::{ErrorKind, QueueOptions};
+
+#[test]
+fn reports_map_full_with_queue_path() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = QueueOptions::new()
+                    .map_size(64 << 10)
+                    .max_map_size(64 << 10)
+                    .open(dir.path())
+                    .expect("queue");
+    let mut prod = queue.producer();
+    let err = (0..1024)
+                  .map(|_| prod.produce(&[0; 4096]))
+                  .filter_map(|r| r.err())
+                  .next()
+                  .expect("map should fill up");
+
+    match *err.kind() {
+        ErrorKind::MapFull => (),
+        ref other => panic!("expected MapFull, got {:?}", other),
+    }
+    assert_eq!(err.path(), Some(dir.path()));
+    assert_eq!(err.consumer(), None);
+}
+
+#[test]
+fn reports_trimmed_gap_and_moves_on() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+    prod.produce(b"2").expect("produce");
+
+    let mut cons = queue.consumer("one").expect("consumer");
+    let first = cons.poll().expect("poll").expect("some entry");
+    cons.commit_upto(&first).expect("commit");
+    cons.discard_upto(first.offset + 1).expect("discard");
+
+    let err = cons.poll().expect_err("poll should notice the gap");
+    match *err.kind() {
+        ErrorKind::TrimmedGap { expected, available } => {
+            assert_eq!(expected, first.offset + 1);
+            assert_eq!(available, first.offset + 2);
+        }
+        ref other => panic!("expected TrimmedGap, got {:?}", other),
+    }
+    assert_eq!(err.path(), Some(dir.path()));
+    assert_eq!(err.consumer(), Some("one"));
+
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"2".to_vec()));
+}
+
+#[test]
+fn new_consumer_starts_after_trimmed_entries() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+
+    let mut cons = queue.consumer("one").expect("consumer");
+    cons.discard_upto(1).expect("discard");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"1".to_vec()));
+}
+
+#[test]
+fn reports_missing_consumer() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+
+    let err = queue.consumer_offset("nobody").expect_err("no such consumer");
+    match *err.kind() {
+        ErrorKind::NotFound => (),
+        ref other => panic!("expected NotFound, got {:?}", other),
+    }
+    assert_eq!(err.consumer(), Some("nobody"));
+}
+
+#[test]
+fn reports_committed_consumer_offset() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    queue.producer().produce(b"0").expect("produce");
+    let mut cons = queue.consumer("one").expect("consumer");
+    let entry = cons.poll().expect("poll").expect("some entry");
+    cons.commit_upto(&entry).expect("commit");
+
+    assert_eq!(queue.consumer_offset("one").expect("offset"), entry.offset);
+}
+
+#[test]
+fn reports_corrupt_record() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    lmqueue::Queue::open(dir.path()).expect("queue");
+    {
+        let mut b = lmdb_zero::EnvBuilder::new().expect("env builder");
+        b.set_maxdbs(3).expect("maxdbs");
+        let env = Arc::new(unsafe {
+            b.open(dir.path().to_str().expect("path string"),
+                   lmdb_zero::open::Flags::empty(),
+                   0o600)
+             .expect("env")
+        });
+        let db = lmdb_zero::Database::open(env.clone(),
+                                           Some("data"),
+                                           &lmdb_zero::DatabaseOptions::new(lmdb_zero::db::CREATE))
+                     .expect("data db");
+        let txn = lmdb_zero::WriteTransaction::new(env.clone()).expect("txn");
+        txn.access()
+           .put(&db, &b"bad"[..], &b"0"[..], lmdb_zero::put::Flags::empty())
+           .expect("put");
+        txn.commit().expect("commit");
+    }
+
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut cons = queue.consumer("one").expect("consumer");
+    let err = cons.poll().expect_err("poll should fail");
+    match *err.kind() {
+        ErrorKind::CorruptRecord { offset } => assert_eq!(offset, 1),
+        ref other => panic!("expected CorruptRecord, got {:?}", other),
+    }
+    assert_eq!(err.consumer(), Some("one"));
+}
+
+#[cfg(unix)]
+#[test]
+fn reports_invalid_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = OsStr::from_bytes(b"/tmp/not-\xffutf8");
+    let err = lmqueue::Queue::open(path).expect_err("open should fail");
+    match *err.kind() {
+        ErrorKind::InvalidPath(_) => (),
+        ref other => panic!("expected InvalidPath, got {:?}", other),
+    }
+}
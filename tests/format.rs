@@ -0,0 +1,101 @@
+extern crate lmqueue;
+extern crate lmdb_zero;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use lmqueue::{ErrorKind, QueueOptions, Totals, FORMAT_VERSION};
+
+// Writes `key` => `val` into the named database, bypassing lmqueue.
+fn put_raw(dir: &Path, db: &str, key: &[u8], val: &[u8]) {
+    let mut b = lmdb_zero::EnvBuilder::new().expect("env builder");
+    b.set_maxdbs(3).expect("maxdbs");
+    let env = Arc::new(unsafe {
+        b.open(dir.to_str().expect("path string"),
+               lmdb_zero::open::Flags::empty(),
+               0o600)
+         .expect("env")
+    });
+    let db = lmdb_zero::Database::open(env.clone(),
+                                       Some(db),
+                                       &lmdb_zero::DatabaseOptions::new(lmdb_zero::db::CREATE))
+                 .expect("db");
+    let txn = lmdb_zero::WriteTransaction::new(env.clone()).expect("txn");
+    txn.access().put(&db, key, val, lmdb_zero::put::Flags::empty()).expect("put");
+    txn.commit().expect("commit");
+}
+
+#[test]
+fn new_queue_records_current_version() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    assert_eq!(queue.format_version().expect("version"), FORMAT_VERSION);
+}
+
+#[test]
+fn unversioned_queue_must_be_migrated() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    put_raw(dir.path(), "data", &[0, 0, 0, 0, 0, 0, 0, 1], b"legacy");
+    put_raw(dir.path(), "prod", b"writer-next", &[0, 0, 0, 0, 0, 0, 0, 1]);
+
+    let err = lmqueue::Queue::open(dir.path()).expect_err("open should fail");
+    match *err.kind() {
+        ErrorKind::OutdatedFormat { found, current } => {
+            assert_eq!(found, 1);
+            assert_eq!(current, FORMAT_VERSION);
+        }
+        ref other => panic!("expected OutdatedFormat, got {:?}", other),
+    }
+
+    assert_eq!(QueueOptions::new().migrate(dir.path()).expect("migrate"), 1);
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    assert_eq!(queue.format_version().expect("version"), FORMAT_VERSION);
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"legacy".to_vec()));
+    assert!(queue.verify().expect("verify").is_ok());
+    assert_eq!(queue.totals().expect("totals"),
+               Totals {
+                   records: 1,
+                   payload_bytes: 6,
+               });
+
+    queue.producer().produce(b"new").expect("produce");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"new".to_vec()));
+}
+
+#[test]
+fn rejects_future_version() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let future = FORMAT_VERSION + 1;
+    put_raw(dir.path(), "prod", b"format-version", &[0, 0, 0, 0, 0, 0, 0, future as u8]);
+
+    let err = lmqueue::Queue::open(dir.path()).expect_err("open should fail");
+    match *err.kind() {
+        ErrorKind::UnsupportedFormat { found, supported } => {
+            assert_eq!(found, future);
+            assert_eq!(supported, FORMAT_VERSION);
+        }
+        ref other => panic!("expected UnsupportedFormat, got {:?}", other),
+    }
+    assert_eq!(err.path(), Some(dir.path()));
+
+    let err = QueueOptions::new().migrate(dir.path()).expect_err("migrate should fail");
+    assert!(matches!(*err.kind(), ErrorKind::UnsupportedFormat { .. }));
+}
+
+#[test]
+fn migrating_current_queue_is_a_no_op() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    lmqueue::Queue::open(dir.path()).expect("queue").producer().produce(b"0").expect("produce");
+
+    assert_eq!(QueueOptions::new().migrate(dir.path()).expect("migrate"), FORMAT_VERSION);
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
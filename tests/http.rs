@@ -0,0 +1,150 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use lmqueue::HttpServer;
+
+fn serve(queue: &lmqueue::Queue) -> SocketAddr {
+    let server = HttpServer::bind(queue, "127.0.0.1:0").expect("bind");
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+// Makes a request, returning the status and body.
+fn request(addr: SocketAddr, method: &str, target: &str, body: &[u8]) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    write!(stream,
+           "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n",
+           method,
+           target,
+           body.len())
+        .expect("write head");
+    stream.write_all(body).expect("write body");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("read response");
+    let status = response[9..12].parse().expect("status");
+    let body = response.split("\r\n\r\n").nth(1).expect("body").to_string();
+    (status, body)
+}
+
+#[test]
+fn can_produce_read_and_commit() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    assert_eq!(request(addr, "POST", "/messages", b"hello"),
+               (201, "{\"offset\":1}".to_string()));
+    assert_eq!(request(addr, "POST", "/messages", b"world"),
+               (201, "{\"offset\":2}".to_string()));
+
+    assert_eq!(request(addr, "GET", "/messages?from=2", b""),
+               (200, "{\"entries\":[{\"offset\":2,\"data\":\"d29ybGQ=\"}]}".to_string()));
+    assert_eq!(request(addr, "GET", "/consumers/a%20b/messages?limit=1", b""),
+               (200, "{\"entries\":[{\"offset\":1,\"data\":\"aGVsbG8=\"}]}".to_string()));
+
+    assert_eq!(request(addr, "POST", "/consumers/a%20b/commit?offset=1", b"").0, 204);
+    assert_eq!(queue.consumer_offset("a b").expect("offset"), 1);
+    assert_eq!(request(addr, "GET", "/consumers", b""),
+               (200,
+                "{\"consumers\":[{\"name\":\"a b\",\"offset\":1,\"lag\":1}]}".to_string()));
+    assert_eq!(request(addr, "GET", "/stats", b""),
+               (200,
+                "{\"entries\":2,\"first_offset\":1,\"last_offset\":2,\"consumers\":1}"
+                    .to_string()));
+}
+
+#[test]
+fn reports_errors() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    assert_eq!(request(addr, "GET", "/consumers/nobody", b"").0, 404);
+    assert_eq!(request(addr, "GET", "/nowhere", b"").0, 404);
+    assert_eq!(request(addr, "DELETE", "/messages", b"").0, 405);
+    assert_eq!(request(addr, "GET", "/messages?from=x", b""),
+               (400, "{\"error\":\"bad from\"}".to_string()));
+    assert_eq!(request(addr, "POST", "/consumers/one/commit", b"").0, 400);
+}
+
+#[test]
+fn long_poll_waits_for_entries() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    let producer = queue.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        producer.producer().produce(b"late").expect("produce");
+    });
+    assert_eq!(request(addr, "GET", "/consumers/one/messages?timeout=10000", b""),
+               (200, "{\"entries\":[{\"offset\":1,\"data\":\"bGF0ZQ==\"}]}".to_string()));
+}
+
+#[test]
+fn streams_server_sent_events() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+    let mut prod = queue.producer();
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    stream.write_all(b"GET /consumers/one/events HTTP/1.1\r\nLast-Event-ID: 1\r\n\r\n")
+          .expect("write request");
+    let mut lines = BufReader::new(stream).lines().map(|l| l.expect("line"));
+    assert_eq!(lines.next(), Some("HTTP/1.1 200 OK".to_string()));
+    let events = lines.skip_while(|l| !l.is_empty())
+                      .skip(1)
+                      .take(6)
+                      .inspect(|_| {
+                          // The second event arrives once we've seen the first.
+                          if queue.entries(3, 1).expect("entries").is_empty() {
+                              prod.produce(b"2").expect("produce");
+                          }
+                      })
+                      .collect::<Vec<_>>();
+    assert_eq!(events, vec!["id: 2", "data: MQ==", "", "id: 3", "data: Mg==", ""]);
+}
+
+#[test]
+fn copes_with_offsets_and_timeouts_at_their_limits() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+    queue.producer().produce(b"0").expect("produce");
+
+    // Far more milliseconds than an `Instant` can be moved on by.
+    let target = format!("/consumers/one/messages?timeout={}", u64::MAX);
+    assert_eq!(request(addr, "GET", &target, b""),
+               (200, "{\"entries\":[{\"offset\":1,\"data\":\"MA==\"}]}".to_string()));
+
+    // Nothing can come after the last offset there is.
+    let target = format!("/consumers/last/commit?offset={}", u64::MAX);
+    assert_eq!(request(addr, "POST", &target, b"").0, 204);
+    assert_eq!(request(addr, "GET", "/consumers/last/messages?timeout=10000", b""),
+               (200, "{\"entries\":[]}".to_string()));
+
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    write!(stream,
+           "GET /consumers/one/events HTTP/1.1\r\nLast-Event-ID: {}\r\n\r\n",
+           u64::MAX)
+        .expect("write request");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("read response");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+}
@@ -0,0 +1,305 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+extern crate byteorder;
+extern crate crc32c;
+
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use lmqueue::KafkaServer;
+
+fn serve(queue: &lmqueue::Queue) -> SocketAddr {
+    let server = KafkaServer::bind("127.0.0.1:0").expect("bind").topic("events", queue);
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+fn string(buf: &mut Vec<u8>, s: &str) {
+    buf.write_i16::<BigEndian>(s.len() as i16).unwrap();
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_string(r: &mut Cursor<Vec<u8>>) -> String {
+    let len = r.read_i16::<BigEndian>().unwrap();
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+fn varint(buf: &mut Vec<u8>, v: i64) {
+    let mut v = ((v << 1) ^ (v >> 63)) as u64;
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(r: &mut Cursor<Vec<u8>>) -> i64 {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = r.read_u8().unwrap();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return (value >> 1) as i64 ^ -((value & 1) as i64);
+        }
+        shift += 7;
+    }
+}
+
+// Sends a request, returning the response body after the correlation ID.
+fn call(stream: &mut TcpStream, api_key: i16, version: i16, body: &[u8]) -> Cursor<Vec<u8>> {
+    let mut req = Vec::new();
+    req.write_i16::<BigEndian>(api_key).unwrap();
+    req.write_i16::<BigEndian>(version).unwrap();
+    req.write_i32::<BigEndian>(7).unwrap();
+    string(&mut req, "test");
+    req.extend_from_slice(body);
+    stream.write_i32::<BigEndian>(req.len() as i32).unwrap();
+    stream.write_all(&req).unwrap();
+
+    let len = stream.read_i32::<BigEndian>().unwrap();
+    let mut resp = vec![0; len as usize];
+    stream.read_exact(&mut resp).unwrap();
+    let mut resp = Cursor::new(resp);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 7);
+    resp
+}
+
+fn record_batch(values: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.write_i16::<BigEndian>(0).unwrap();
+    body.write_i32::<BigEndian>(values.len() as i32 - 1).unwrap();
+    body.write_i64::<BigEndian>(0).unwrap();
+    body.write_i64::<BigEndian>(0).unwrap();
+    body.write_i64::<BigEndian>(-1).unwrap();
+    body.write_i16::<BigEndian>(-1).unwrap();
+    body.write_i32::<BigEndian>(-1).unwrap();
+    body.write_i32::<BigEndian>(values.len() as i32).unwrap();
+    for (i, value) in values.iter().enumerate() {
+        let mut record = vec![0];
+        varint(&mut record, 0);
+        varint(&mut record, i as i64);
+        varint(&mut record, -1);
+        varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        varint(&mut record, 0);
+        varint(&mut body, record.len() as i64);
+        body.extend_from_slice(&record);
+    }
+    let mut batch = Vec::new();
+    batch.write_i64::<BigEndian>(0).unwrap();
+    batch.write_i32::<BigEndian>(9 + body.len() as i32).unwrap();
+    batch.write_i32::<BigEndian>(0).unwrap();
+    batch.push(2);
+    batch.write_u32::<BigEndian>(crc32c::crc32c(&body)).unwrap();
+    batch.extend_from_slice(&body);
+    batch
+}
+
+// Produces `values` to partition 0 with Produce v3, returning the error
+// code and base offset.
+fn produce(stream: &mut TcpStream, values: &[&[u8]]) -> (i16, i64) {
+    let records = record_batch(values);
+    let mut body = Vec::new();
+    body.write_i16::<BigEndian>(-1).unwrap();
+    body.write_i16::<BigEndian>(1).unwrap();
+    body.write_i32::<BigEndian>(1000).unwrap();
+    body.write_i32::<BigEndian>(1).unwrap();
+    string(&mut body, "events");
+    body.write_i32::<BigEndian>(1).unwrap();
+    body.write_i32::<BigEndian>(0).unwrap();
+    body.write_i32::<BigEndian>(records.len() as i32).unwrap();
+    body.extend_from_slice(&records);
+    let mut resp = call(stream, 0, 3, &body);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(read_string(&mut resp), "events");
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    (resp.read_i16::<BigEndian>().unwrap(), resp.read_i64::<BigEndian>().unwrap())
+}
+
+// Fetches from `offset` with Fetch v4, returning the error code, the high
+// watermark and the offsets and values of the records.
+fn fetch(stream: &mut TcpStream, offset: i64) -> (i16, i64, Vec<(i64, Vec<u8>)>) {
+    let mut body = Vec::new();
+    body.write_i32::<BigEndian>(-1).unwrap();
+    body.write_i32::<BigEndian>(0).unwrap();
+    body.write_i32::<BigEndian>(1).unwrap();
+    body.write_i32::<BigEndian>(1 << 20).unwrap();
+    body.push(0);
+    body.write_i32::<BigEndian>(1).unwrap();
+    string(&mut body, "events");
+    body.write_i32::<BigEndian>(1).unwrap();
+    body.write_i32::<BigEndian>(0).unwrap();
+    body.write_i64::<BigEndian>(offset).unwrap();
+    body.write_i32::<BigEndian>(1 << 20).unwrap();
+    let mut resp = call(stream, 1, 4, &body);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(read_string(&mut resp), "events");
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    let error = resp.read_i16::<BigEndian>().unwrap();
+    let high_watermark = resp.read_i64::<BigEndian>().unwrap();
+    let _last_stable = resp.read_i64::<BigEndian>().unwrap();
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    let len = resp.read_i32::<BigEndian>().unwrap();
+    let mut records = Vec::new();
+    if len > 0 {
+        let base = resp.read_i64::<BigEndian>().unwrap();
+        let _len = resp.read_i32::<BigEndian>().unwrap();
+        let _epoch = resp.read_i32::<BigEndian>().unwrap();
+        assert_eq!(resp.read_u8().unwrap(), 2);
+        let crc = resp.read_u32::<BigEndian>().unwrap();
+        let start = resp.position() as usize;
+        assert_eq!(crc32c::crc32c(&resp.get_ref()[start..]), crc);
+        let mut header = [0; 2 + 4 + 8 + 8 + 8 + 2 + 4];
+        resp.read_exact(&mut header).unwrap();
+        for _ in 0..resp.read_i32::<BigEndian>().unwrap() {
+            let _len = read_varint(&mut resp);
+            let _attributes = resp.read_u8().unwrap();
+            let _timestamp = read_varint(&mut resp);
+            let delta = read_varint(&mut resp);
+            assert_eq!(read_varint(&mut resp), -1);
+            let mut value = vec![0; read_varint(&mut resp) as usize];
+            resp.read_exact(&mut value).unwrap();
+            assert_eq!(read_varint(&mut resp), 0);
+            records.push((base + delta, value));
+        }
+    }
+    (error, high_watermark, records)
+}
+
+// Asks for the earliest (-2) or latest (-1) offset with ListOffsets v1.
+fn list_offset(stream: &mut TcpStream, timestamp: i64) -> i64 {
+    let mut body = Vec::new();
+    body.write_i32::<BigEndian>(-1).unwrap();
+    body.write_i32::<BigEndian>(1).unwrap();
+    string(&mut body, "events");
+    body.write_i32::<BigEndian>(1).unwrap();
+    body.write_i32::<BigEndian>(0).unwrap();
+    body.write_i64::<BigEndian>(timestamp).unwrap();
+    let mut resp = call(stream, 2, 1, &body);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(read_string(&mut resp), "events");
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    assert_eq!(resp.read_i16::<BigEndian>().unwrap(), 0);
+    let _timestamp = resp.read_i64::<BigEndian>().unwrap();
+    resp.read_i64::<BigEndian>().unwrap()
+}
+
+#[test]
+fn describes_itself() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+    let mut stream = TcpStream::connect(addr).expect("connect");
+
+    let mut resp = call(&mut stream, 18, 0, &[]);
+    assert_eq!(resp.read_i16::<BigEndian>().unwrap(), 0);
+    let apis = (0..resp.read_i32::<BigEndian>().unwrap())
+                   .map(|_| {
+                       (resp.read_i16::<BigEndian>().unwrap(),
+                        resp.read_i16::<BigEndian>().unwrap(),
+                        resp.read_i16::<BigEndian>().unwrap())
+                   })
+                   .collect::<Vec<_>>();
+    assert!(apis.contains(&(0, 3, 7)));
+
+    // Too new a version is refused in a form the client can read.
+    let mut resp = call(&mut stream, 18, 3, &[]);
+    assert_eq!(resp.read_i16::<BigEndian>().unwrap(), 35);
+
+    let mut body = Vec::new();
+    body.write_i32::<BigEndian>(-1).unwrap();
+    let mut resp = call(&mut stream, 3, 1, &body);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    assert_eq!(read_string(&mut resp), "127.0.0.1");
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), addr.port() as i32);
+    assert_eq!(resp.read_i16::<BigEndian>().unwrap(), -1);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(resp.read_i16::<BigEndian>().unwrap(), 0);
+    assert_eq!(read_string(&mut resp), "events");
+}
+
+#[test]
+fn can_produce_and_fetch() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut stream = TcpStream::connect(serve(&queue)).expect("connect");
+
+    assert_eq!(produce(&mut stream, &[b"a", b"b"]), (0, 0));
+    assert_eq!(produce(&mut stream, &[b"c"]), (0, 2));
+    let mut cons = queue.consumer("local").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"a".to_vec()));
+
+    assert_eq!(fetch(&mut stream, 1),
+               (0, 3, vec![(1, b"b".to_vec()), (2, b"c".to_vec())]));
+    assert_eq!(fetch(&mut stream, 3), (0, 3, vec![]));
+    assert_eq!(fetch(&mut stream, 4).0, 1);
+
+    assert_eq!(list_offset(&mut stream, -2), 0);
+    assert_eq!(list_offset(&mut stream, -1), 3);
+    queue.discard_upto(1).expect("trim");
+    assert_eq!(list_offset(&mut stream, -2), 1);
+    assert_eq!(fetch(&mut stream, 0).0, 1);
+}
+
+#[test]
+fn group_offsets_are_consumer_offsets() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut stream = TcpStream::connect(serve(&queue)).expect("connect");
+    produce(&mut stream, &[b"a", b"b"]);
+
+    let mut body = Vec::new();
+    string(&mut body, "group");
+    body.write_i32::<BigEndian>(-1).unwrap();
+    string(&mut body, "");
+    body.write_i64::<BigEndian>(-1).unwrap();
+    body.write_i32::<BigEndian>(1).unwrap();
+    string(&mut body, "events");
+    body.write_i32::<BigEndian>(1).unwrap();
+    body.write_i32::<BigEndian>(0).unwrap();
+    body.write_i64::<BigEndian>(1).unwrap();
+    body.write_i16::<BigEndian>(-1).unwrap();
+    let mut resp = call(&mut stream, 8, 2, &body);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(read_string(&mut resp), "events");
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+    assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+    assert_eq!(resp.read_i16::<BigEndian>().unwrap(), 0);
+
+    // Having read Kafka offset 0, the group's next is 1, which is the
+    // queue offset of the entry it read.
+    assert_eq!(queue.consumer_offset("group").expect("offset"), 1);
+    let mut cons = queue.consumer("group").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"b".to_vec()));
+
+    for &(group, expected) in &[("group", 1), ("nobody", -1)] {
+        let mut body = Vec::new();
+        string(&mut body, group);
+        body.write_i32::<BigEndian>(1).unwrap();
+        string(&mut body, "events");
+        body.write_i32::<BigEndian>(1).unwrap();
+        body.write_i32::<BigEndian>(0).unwrap();
+        let mut resp = call(&mut stream, 9, 1, &body);
+        assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+        assert_eq!(read_string(&mut resp), "events");
+        assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 1);
+        assert_eq!(resp.read_i32::<BigEndian>().unwrap(), 0);
+        assert_eq!(resp.read_i64::<BigEndian>().unwrap(), expected);
+    }
+}
@@ -0,0 +1,101 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use lmqueue::QueueOptions;
+
+const SMALL_MAP: usize = 64 << 10;
+const MESSAGE_SIZE: usize = 4096;
+const MESSAGE_COUNT: usize = 256;
+
+fn message(n: usize) -> Vec<u8> {
+    vec![(n % 256) as u8; MESSAGE_SIZE]
+}
+
+#[test]
+fn grows_map_when_full() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = QueueOptions::new().map_size(SMALL_MAP).open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    for n in 0..MESSAGE_COUNT {
+        prod.produce(&message(n)).expect("produce");
+    }
+
+    let mut cons = queue.consumer("default").expect("consumer");
+    for n in 0..MESSAGE_COUNT {
+        assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(message(n)));
+    }
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None);
+}
+
+#[test]
+fn fails_when_full_at_max_map_size() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = QueueOptions::new()
+                    .map_size(SMALL_MAP)
+                    .max_map_size(2 * SMALL_MAP)
+                    .open(dir.path())
+                    .expect("queue");
+    let mut prod = queue.producer();
+    let produced = (0..MESSAGE_COUNT).take_while(|&n| prod.produce(&message(n)).is_ok()).count();
+    assert!(produced > 0);
+    assert!(produced < MESSAGE_COUNT);
+
+    // Whatever made it in before the map filled up is still readable.
+    let mut cons = queue.consumer("default").expect("consumer");
+    for n in 0..produced {
+        assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(message(n)));
+    }
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None);
+}
+
+#[test]
+fn other_handles_adopt_grown_map() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    // Stand-ins for two processes sharing the queue.
+    let writer = QueueOptions::new().map_size(SMALL_MAP).open(dir.path()).expect("writer");
+    let reader = QueueOptions::new().map_size(SMALL_MAP).open(dir.path()).expect("reader");
+    let mut cons = reader.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None);
+
+    let mut prod = writer.producer();
+    for n in 0..MESSAGE_COUNT {
+        prod.produce(&message(n)).expect("produce");
+    }
+
+    for n in 0..MESSAGE_COUNT {
+        assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(message(n)));
+    }
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None);
+}
+
+#[test]
+fn can_grow_while_shared_between_threads() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = QueueOptions::new().map_size(SMALL_MAP).open(dir.path()).expect("queue");
+    let threads = (0..4)
+                      .map(|_| {
+                          let queue = queue.clone();
+                          std::thread::spawn(move || {
+                              let mut prod = queue.producer();
+                              for n in 0..MESSAGE_COUNT / 4 {
+                                  prod.produce(&message(n)).expect("produce");
+                              }
+                          })
+                      })
+                      .collect::<Vec<_>>();
+    for t in threads {
+        t.join().expect("producer thread");
+    }
+
+    let mut cons = queue.consumer("default").expect("consumer");
+    let mut seen = 0;
+    while cons.poll().expect("poll").is_some() {
+        seen += 1;
+    }
+    assert_eq!(seen, MESSAGE_COUNT);
+}
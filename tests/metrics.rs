@@ -0,0 +1,64 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use lmqueue::MetricsServer;
+
+#[test]
+fn counts_what_the_queue_does() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    queue.producer().produce_batch(&[&b"one"[..], &b"three"[..]]).expect("produce");
+    let mut consumer = queue.consumer("reader").expect("consumer");
+    let entry = consumer.poll().expect("poll").expect("entry");
+    consumer.commit_upto(&entry).expect("commit");
+    consumer.poll().expect("poll").expect("entry");
+    assert_eq!(consumer.poll().expect("poll"), None);
+    queue.discard_upto(1).expect("discard");
+
+    let metrics = queue.metrics().expect("metrics");
+    assert_eq!((metrics.produced_messages, metrics.produced_bytes), (2, 8));
+    assert_eq!((metrics.polls, metrics.empty_polls), (3, 1));
+    assert_eq!((metrics.trims, metrics.trimmed_messages), (1, 1));
+    assert_eq!(metrics.produce_latency.count, 1);
+    // The produce, the commit and the trim, at least.
+    assert!(metrics.commit_latency.count >= 3);
+    assert!(metrics.map_used > 0 && metrics.map_used <= metrics.map_size);
+    assert_eq!(metrics.writer_next, 2);
+    assert_eq!(metrics.consumers.get("reader"), Some(&1));
+
+    let text = lmqueue::render_metrics(&[("q", &metrics)]);
+    assert!(text.contains("# TYPE lmqueue_produced_messages_total counter\n"));
+    assert!(text.contains("lmqueue_produced_messages_total{queue=\"q\"} 2\n"));
+    assert!(text.contains("lmqueue_produce_duration_seconds_bucket{queue=\"q\",le=\"+Inf\"} 1\n"));
+    assert!(text.contains("lmqueue_consumer_offset{queue=\"q\",consumer=\"reader\"} 1\n"));
+}
+
+fn get(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).expect("write");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("read");
+    response
+}
+
+#[test]
+fn serves_prometheus_text() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    queue.producer().produce(b"0").expect("produce");
+    let server = MetricsServer::bind("127.0.0.1:0").expect("bind").queue("orders", &queue);
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+
+    let response = get(addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("lmqueue_writer_next{queue=\"orders\"} 1\n"));
+    assert!(get(addr, "/").starts_with("HTTP/1.1 404 "));
+}
@@ -0,0 +1,169 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use lmqueue::MqttServer;
+
+fn serve(queue: &lmqueue::Queue) -> SocketAddr {
+    let server = MqttServer::bind("127.0.0.1:0").expect("bind").route("sensors/#", queue);
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+fn string(buf: &mut Vec<u8>, s: &str) {
+    buf.push((s.len() >> 8) as u8);
+    buf.push(s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    // Connects, returning the client and the session present flag.
+    fn connect(addr: SocketAddr, id: &str, clean: bool) -> (Client, bool) {
+        let stream = TcpStream::connect(addr).expect("connect");
+        stream.set_read_timeout(Some(Duration::from_secs(10))).expect("timeout");
+        let mut client = Client { stream };
+        let mut body = Vec::new();
+        string(&mut body, "MQTT");
+        body.push(4);
+        body.push(if clean { 0x02 } else { 0 });
+        body.extend_from_slice(&[0, 60]);
+        string(&mut body, id);
+        client.send(0x10, &body);
+        let (header, body) = client.recv();
+        assert_eq!((header, body[1]), (0x20, 0));
+        (client, body[0] == 1)
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) {
+        assert!(body.len() < 128);
+        let mut packet = vec![header, body.len() as u8];
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet).expect("write");
+    }
+
+    fn recv(&mut self) -> (u8, Vec<u8>) {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header).expect("read header");
+        assert!(header[1] < 128);
+        let mut body = vec![0; header[1] as usize];
+        self.stream.read_exact(&mut body).expect("read body");
+        (header[0], body)
+    }
+
+    fn subscribe(&mut self, filter: &str, qos: u8) {
+        let mut body = vec![0, 1];
+        string(&mut body, filter);
+        body.push(qos);
+        self.send(0x82, &body);
+        assert_eq!(self.recv(), (0x90, vec![0, 1, qos]));
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) {
+        let mut body = Vec::new();
+        string(&mut body, topic);
+        body.extend_from_slice(&[0, 7]);
+        body.extend_from_slice(payload);
+        self.send(0x32, &body);
+        assert_eq!(self.recv(), (0x40, vec![0, 7]));
+    }
+
+    // Receives a QoS 1 publish, returning its topic, payload and packet ID.
+    fn receive(&mut self) -> (String, Vec<u8>, u16) {
+        let (header, body) = self.recv();
+        assert_eq!(header & 0xf6, 0x32);
+        let len = ((body[0] as usize) << 8) | body[1] as usize;
+        let topic = String::from_utf8(body[2..2 + len].to_vec()).expect("topic");
+        let id = ((body[2 + len] as u16) << 8) | body[3 + len] as u16;
+        (topic, body[4 + len..].to_vec(), id)
+    }
+
+    fn ack(&mut self, id: u16) {
+        self.send(0x40, &[(id >> 8) as u8, id as u8]);
+    }
+
+    fn disconnect(mut self) {
+        self.send(0xe0, &[]);
+    }
+}
+
+fn wait_for_offset(queue: &lmqueue::Queue, name: &str, offset: u64) {
+    for _ in 0..100 {
+        if queue.consumer_offset(name).ok() == Some(offset) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("{:?} never reached {}", name, offset);
+}
+
+#[test]
+fn delivers_published_messages() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    let (mut sub, present) = Client::connect(addr, "sub", true);
+    assert!(!present);
+    sub.subscribe("sensors/+/temp", 1);
+    let (mut publisher, _) = Client::connect(addr, "pub", true);
+    publisher.publish("sensors/a/humidity", b"skipped");
+    publisher.publish("sensors/a/temp", b"21");
+    publisher.publish("elsewhere", b"dropped");
+    assert_eq!(queue.entries(0, 10).expect("entries").len(), 2);
+
+    let (topic, payload, id) = sub.receive();
+    assert_eq!((&*topic, &*payload), ("sensors/a/temp", &b"21"[..]));
+    sub.ack(id);
+    wait_for_offset(&queue, "mqtt:sub\u{0}1sensors/+/temp", 2);
+
+    // A clean session leaves nothing behind.
+    sub.disconnect();
+    for _ in 0..100 {
+        if queue.consumers().expect("consumers").is_empty() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("clean session left consumers behind");
+}
+
+#[test]
+fn persistent_sessions_redeliver_unacknowledged_messages() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    let (mut sub, _) = Client::connect(addr, "device", false);
+    sub.subscribe("sensors/#", 1);
+    let (mut publisher, _) = Client::connect(addr, "pub", true);
+    publisher.publish("sensors/a", b"one");
+    publisher.publish("sensors/b", b"two");
+
+    let (_, payload, id) = sub.receive();
+    assert_eq!(payload, b"one");
+    sub.ack(id);
+    let (_, payload, _) = sub.receive();
+    assert_eq!(payload, b"two");
+    wait_for_offset(&queue, "mqtt:device\u{0}1sensors/#", 1);
+    // Lost before acknowledging the second.
+    drop(sub);
+
+    let (mut sub, present) = Client::connect(addr, "device", false);
+    assert!(present);
+    let (topic, payload, id) = sub.receive();
+    assert_eq!((&*topic, &*payload), ("sensors/b", &b"two"[..]));
+    sub.ack(id);
+    wait_for_offset(&queue, "mqtt:device\u{0}1sensors/#", 2);
+}
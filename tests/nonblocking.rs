@@ -0,0 +1,96 @@
+#![cfg(feature = "async")]
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+extern crate futures;
+extern crate tokio;
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .build()
+        .expect("runtime")
+}
+
+#[test]
+fn can_stream_produced_entries() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+
+    let stream = queue.consumer("default").expect("consumer").into_stream();
+    let entries = runtime().block_on(stream.take(2).collect::<Vec<_>>());
+    let data = entries.into_iter().map(|e| e.expect("entry").data).collect::<Vec<_>>();
+    assert_eq!(data, vec![b"0".to_vec(), b"1".to_vec()]);
+}
+
+#[test]
+fn stream_wakes_on_commit() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    // Long enough that only a commit notification could wake us in time.
+    let stream = queue.consumer("default")
+                      .expect("consumer")
+                      .into_stream()
+                      .poll_interval(Duration::from_secs(3600));
+
+    let producer_queue = queue.clone();
+    let producer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        producer_queue.producer().produce(b"0").expect("produce");
+    });
+
+    let rt = runtime();
+    let _guard = rt.enter();
+    let next = rt.block_on(tokio::time::timeout(Duration::from_secs(10), stream.into_future()));
+    let (entry, _) = next.expect("woken before timeout");
+    assert_eq!(entry.map(|e| e.expect("entry").data), Some(b"0".to_vec()));
+    producer.join().expect("producer thread");
+}
+
+#[test]
+fn can_commit_from_stream() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    queue.producer().produce(b"0").expect("produce");
+    queue.producer().produce(b"1").expect("produce");
+
+    let rt = runtime();
+    let mut stream = queue.consumer("default").expect("consumer").into_stream();
+    let entry = rt.block_on(stream.next()).expect("some entry").expect("entry");
+    rt.block_on(stream.commit_upto(&entry)).expect("commit");
+
+    assert_eq!(queue.consumer_offset("default").expect("offset"), entry.offset);
+}
+
+#[test]
+fn can_produce_through_sink() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut sink = queue.producer().into_sink().capacity(2);
+
+    let rt = runtime();
+    let msgs = (0..5).map(|n| Ok(vec![n as u8])).collect::<Vec<_>>();
+    rt.block_on(sink.send_all(&mut futures::stream::iter(msgs))).expect("send_all");
+    rt.block_on(sink.close()).expect("close");
+    // Each buffer-full goes in as one batch.
+    let metrics = queue.metrics().expect("metrics");
+    assert_eq!(metrics.produced_messages, 5);
+    assert!(metrics.produce_latency.count <= 3, "{:?}", metrics.produce_latency);
+
+    let mut cons = queue.consumer("default").expect("consumer");
+    for n in 0..5 {
+        assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(vec![n as u8]));
+    }
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None);
+}
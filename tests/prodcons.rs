@@ -325,3 +325,134 @@ fn removing_non_consumer_is_noop() {
         assert_eq!(consumers.get("default"), None);
     }
 }
+
+#[test]
+fn can_share_queue_between_producer_and_consumer() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path().to_str().expect("path string")).expect("queue");
+    let mut prod = queue.producer();
+    let mut cons = queue.consumer("default").expect("consumer");
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"1".to_vec()));
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), None)
+}
+
+#[test]
+fn can_consume_from_cloned_queue_across_threads() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path().to_str().expect("path string")).expect("queue");
+    let producer_queue = queue.clone();
+    std::thread::spawn(move || {
+        let mut prod = producer_queue.producer();
+        prod.produce(b"0").expect("produce");
+    }).join().expect("producer thread");
+
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
+
+#[test]
+fn queue_consumer_resumes_at_commit_point() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path().to_str().expect("path string")).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"0").expect("produce");
+    prod.produce(b"1").expect("produce");
+
+    {
+        let mut cons = queue.consumer("default").expect("consumer");
+        let entry = cons.poll().expect("poll").expect("some entry");
+        cons.commit_upto(&entry).expect("commit");
+    }
+
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"1".to_vec()));
+}
+
+#[test]
+fn can_open_queue_with_options() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::QueueOptions::new()
+                    .map_size(1 << 20)
+                    .max_readers(16)
+                    .file_mode(0o600)
+                    .no_readahead(true)
+                    .open(dir.path())
+                    .expect("queue");
+    let mut prod = queue.producer();
+    let mut cons = queue.consumer("default").expect("consumer");
+    prod.produce(b"0").expect("produce");
+
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
+
+#[test]
+fn can_open_queue_without_subdir() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let path = dir.path().join("queue.mdb");
+    {
+        let queue = lmqueue::QueueOptions::new().no_subdir(true).open(&path).expect("queue");
+        queue.producer().produce(b"0").expect("produce");
+    }
+    assert!(path.is_file());
+
+    let queue = lmqueue::QueueOptions::new().no_subdir(true).open(&path).expect("queue");
+    let mut cons = queue.consumer("default").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
+
+#[test]
+fn can_produce_at_an_explicit_offset() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce_at(5, b"5").expect("produce_at");
+    prod.produce_at(3, b"3").expect("produce_at");
+    assert_eq!(prod.produce(b"6").expect("produce"), 6);
+
+    let offsets: Vec<u64> = queue.entries(0, 10)
+                                 .expect("entries")
+                                 .iter()
+                                 .map(|e| e.offset)
+                                 .collect();
+    assert_eq!(offsets, vec![3, 5, 6]);
+}
+
+#[test]
+fn produce_at_behind_a_consumer_is_not_seen_by_it() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce_at(5, b"5").expect("produce_at");
+    let mut ahead = queue.consumer("ahead").expect("consumer");
+    assert_eq!(ahead.poll().expect("poll").map(|e| e.offset), Some(5));
+
+    prod.produce_at(3, b"3").expect("produce_at");
+    assert_eq!(ahead.poll().expect("poll"), None);
+    let mut behind = queue.consumer("behind").expect("consumer");
+    assert_eq!(behind.poll().expect("poll").map(|e| e.offset), Some(3));
+}
+
+#[test]
+fn produce_at_refuses_to_overwrite() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"original").expect("produce");
+    match prod.produce_at(1, b"replacement") {
+        Err(ref e) if matches!(*e.kind(), lmqueue::ErrorKind::OffsetConflict { offset: 1 }) => (),
+        other => panic!("expected an offset conflict, got {:?}", other),
+    }
+    assert_eq!(queue.entries(1, 1).expect("entries")[0].data, b"original");
+}
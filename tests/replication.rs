@@ -0,0 +1,94 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use lmqueue::{ErrorKind, Follower, Queue, QueueOptions, Server};
+
+fn serve(queue: &Queue) -> SocketAddr {
+    let server = Server::bind(queue, "127.0.0.1:0").expect("bind");
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+fn wait_until<F: Fn() -> bool>(what: &str, f: F) {
+    for _ in 0..250 {
+        if f() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("timed out waiting until {}", what);
+}
+
+#[test]
+fn follower_replicates_records_and_offsets_until_promoted() {
+    env_logger::init().unwrap_or(());
+    let leader_dir = tempdir::TempDir::new("leader").expect("leader-dir");
+    let follower_dir = tempdir::TempDir::new("follower").expect("follower-dir");
+    let leader = Queue::open(leader_dir.path()).expect("leader");
+    let follower = Queue::open(follower_dir.path()).expect("follower");
+    let addr = serve(&leader);
+
+    let mut producer = leader.producer();
+    producer.produce_batch(&[b"one", b"two"]).expect("produce");
+    let running = Follower::new(&follower, &addr.to_string()).expect("follower");
+    let replicator = thread::spawn(move || running.run().expect("run"));
+    producer.produce(b"three").expect("produce");
+    let mut consumer = leader.consumer("reader").expect("consumer");
+    consumer.poll().expect("poll");
+    let entry = consumer.poll().expect("poll").expect("entry");
+    consumer.commit_upto(&entry).expect("commit");
+
+    wait_until("caught up", || {
+        follower.entries(0, 10).expect("entries") == leader.entries(0, 10).expect("entries") &&
+        follower.consumers().expect("consumers") == leader.consumers().expect("consumers")
+    });
+    let status = follower.replication().expect("status").expect("following");
+    assert_eq!(status.leader, addr.to_string());
+    assert_eq!(status.lag(), 0);
+
+    // Trimming on the leader carries over too.
+    leader.discard_upto(1).expect("discard");
+    wait_until("trimmed", || follower.entries(0, 1).expect("entries")[0].offset == 2);
+
+    match follower.producer().produce(b"local") {
+        Err(ref e) if matches!(*e.kind(), ErrorKind::Following { .. }) => (),
+        other => panic!("expected the follower to refuse, got {:?}", other),
+    }
+    assert!(follower.promote().expect("promote"));
+    replicator.join().expect("replicator");
+    assert_eq!(follower.replication().expect("status"), None);
+    assert_eq!(follower.producer().produce(b"four").expect("produce"), 4);
+}
+
+#[test]
+fn producers_can_wait_for_a_follower() {
+    env_logger::init().unwrap_or(());
+    let leader_dir = tempdir::TempDir::new("leader").expect("leader-dir");
+    let follower_dir = tempdir::TempDir::new("follower").expect("follower-dir");
+    let leader = QueueOptions::new()
+                     .follower_ack_timeout(Duration::from_millis(100))
+                     .open(leader_dir.path())
+                     .expect("leader");
+    let addr = serve(&leader);
+
+    // With no follower, the record is kept, but we're told it isn't safe.
+    match leader.producer().produce(b"alone") {
+        Err(ref e) if matches!(*e.kind(), ErrorKind::ReplicationTimeout { offset: 1 }) => (),
+        other => panic!("expected a replication timeout, got {:?}", other),
+    }
+    assert_eq!(leader.entries(0, 10).expect("entries").len(), 1);
+
+    let follower = Queue::open(follower_dir.path()).expect("follower");
+    let running = Follower::new(&follower, &addr.to_string()).expect("follower");
+    thread::spawn(move || running.run());
+    wait_until("caught up", || follower.entries(0, 10).expect("entries").len() == 1);
+
+    let offset = leader.producer().produce(b"replicated").expect("produce");
+    assert_eq!(follower.entries(offset, 1).expect("entries")[0].data, b"replicated");
+}
@@ -0,0 +1,229 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use lmqueue::RespServer;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Value {
+    Status(String),
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Value>),
+    Nil,
+}
+
+use Value::*;
+
+fn bulk(s: &str) -> Value {
+    Bulk(s.as_bytes().to_vec())
+}
+
+fn entry(id: &str, data: &str) -> Value {
+    Array(vec![bulk(id), Array(vec![bulk("data"), bulk(data)])])
+}
+
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    fn connect(addr: SocketAddr) -> Conn {
+        let stream = TcpStream::connect(addr).expect("connect");
+        Conn {
+            reader: BufReader::new(stream.try_clone().expect("clone")),
+            writer: stream,
+        }
+    }
+
+    fn call(&mut self, args: &[&str]) -> Value {
+        let mut req = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            req.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        self.writer.write_all(&req).expect("write");
+        self.read()
+    }
+
+    fn read(&mut self) -> Value {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("read line");
+        let rest = line[1..].trim_end().to_string();
+        match line.as_bytes()[0] {
+            b'+' => Status(rest),
+            b'-' => Error(rest),
+            b':' => Int(rest.parse().expect("int")),
+            b'$' => {
+                let len: usize = rest.parse().expect("len");
+                let mut buf = vec![0; len + 2];
+                self.reader.read_exact(&mut buf).expect("bulk");
+                buf.truncate(len);
+                Bulk(buf)
+            }
+            b'*' if rest == "-1" => Nil,
+            b'*' => Array((0..rest.parse().expect("count")).map(|_| self.read()).collect()),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn serve(queue: &lmqueue::Queue) -> SocketAddr {
+    let server = RespServer::bind(queue, "127.0.0.1:0").expect("bind");
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+#[test]
+fn can_add_and_read_ranges() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut conn = Conn::connect(serve(&queue));
+
+    assert_eq!(conn.call(&["PING"]), Status("PONG".to_string()));
+    assert_eq!(conn.call(&["XADD", "s", "*", "data", "a"]), bulk("1-0"));
+    assert_eq!(conn.call(&["XADD", "s", "*", "data", "b"]), bulk("2-0"));
+    assert_eq!(conn.call(&["XADD", "s", "*", "data", "c"]), bulk("3-0"));
+    assert_eq!(conn.call(&["XLEN", "s"]), Int(3));
+    match conn.call(&["XADD", "s", "5-0", "data", "d"]) {
+        Error(_) => (),
+        other => panic!("explicit ID accepted: {:?}", other),
+    }
+
+    assert_eq!(conn.call(&["XRANGE", "s", "-", "+"]),
+               Array(vec![entry("1-0", "a"), entry("2-0", "b"), entry("3-0", "c")]));
+    assert_eq!(conn.call(&["XRANGE", "s", "(1-0", "+", "COUNT", "1"]),
+               Array(vec![entry("2-0", "b")]));
+    assert_eq!(conn.call(&["XRANGE", "s", "2", "(3-0"]), Array(vec![entry("2-0", "b")]));
+
+    assert_eq!(conn.call(&["XREAD", "STREAMS", "s", "2-0"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("3-0", "c")])])]));
+    assert_eq!(conn.call(&["XREAD", "STREAMS", "s", "$"]), Nil);
+
+    assert_eq!(conn.call(&["XTRIM", "s", "MAXLEN", "1"]), Int(2));
+    assert_eq!(conn.call(&["XRANGE", "s", "-", "+"]), Array(vec![entry("3-0", "c")]));
+    assert_eq!(queue.entries(0, 10).expect("entries").len(), 1);
+}
+
+#[test]
+fn blocking_read_waits_for_entries() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut conn = Conn::connect(serve(&queue));
+
+    let producer = queue.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        producer.producer().produce(b"late").expect("produce");
+    });
+    assert_eq!(conn.call(&["XREAD", "BLOCK", "0", "STREAMS", "s", "$"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("1-0", "late")])])]));
+}
+
+#[test]
+fn blocking_longer_than_can_be_represented_waits() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut conn = Conn::connect(serve(&queue));
+
+    let producer = queue.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        producer.producer().produce(b"late").expect("produce");
+    });
+    let block = u64::MAX.to_string();
+    assert_eq!(conn.call(&["XREAD", "BLOCK", &block, "STREAMS", "s", "$"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("1-0", "late")])])]));
+    assert_eq!(conn.call(&["PING"]), Status("PONG".to_string()));
+}
+
+#[test]
+fn groups_map_to_consumers() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce(b"a").expect("produce");
+    prod.produce(b"b").expect("produce");
+    let mut conn = Conn::connect(serve(&queue));
+
+    match conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]) {
+        Error(ref e) if e.starts_with("NOGROUP") => (),
+        other => panic!("read from missing group: {:?}", other),
+    }
+    assert_eq!(conn.call(&["XGROUP", "CREATE", "s", "g", "0"]), Status("OK".to_string()));
+    match conn.call(&["XGROUP", "CREATE", "s", "g", "0"]) {
+        Error(ref e) if e.starts_with("BUSYGROUP") => (),
+        other => panic!("created group twice: {:?}", other),
+    }
+
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "COUNT", "1", "STREAMS", "s", ">"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("1-0", "a")])])]));
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("2-0", "b")])])]));
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]), Nil);
+    // Both are pending until acknowledged.
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", "0"]),
+               Array(vec![Array(vec![bulk("s"),
+                                     Array(vec![entry("1-0", "a"), entry("2-0", "b")])])]));
+
+    assert_eq!(conn.call(&["XACK", "s", "g", "1-0"]), Int(1));
+    assert_eq!(queue.consumer_offset("g").expect("offset"), 1);
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", "0"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("2-0", "b")])])]));
+
+    // A new connection picks up from the committed offset.
+    let mut other = Conn::connect(serve(&queue));
+    assert_eq!(other.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![entry("2-0", "b")])])]));
+
+    assert_eq!(conn.call(&["XGROUP", "DESTROY", "s", "g"]), Int(1));
+    assert!(queue.consumer_offset("g").is_err());
+}
+
+#[test]
+fn maxlen_counts_entries_across_gaps() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut prod = queue.producer();
+    prod.produce_at(2, b"a").expect("produce");
+    prod.produce_at(5, b"b").expect("produce");
+    prod.produce_at(9, b"c").expect("produce");
+    let mut conn = Conn::connect(serve(&queue));
+
+    assert_eq!(conn.call(&["XTRIM", "s", "MAXLEN", "2"]), Int(1));
+    assert_eq!(conn.call(&["XRANGE", "s", "-", "+"]),
+               Array(vec![entry("5-0", "b"), entry("9-0", "c")]));
+    assert_eq!(conn.call(&["XTRIM", "s", "MAXLEN", "2"]), Int(0));
+}
+
+#[test]
+fn ids_after_the_last_possible_are_empty() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    queue.producer().produce(b"a").expect("produce");
+    let mut conn = Conn::connect(serve(&queue));
+    let max = u64::MAX.to_string();
+
+    assert_eq!(conn.call(&["XRANGE", "s", &format!("({}", max), "+"]), Array(vec![]));
+    assert_eq!(conn.call(&["XRANGE", "s", &format!("{}-1", max), "+"]), Array(vec![]));
+    assert_eq!(conn.call(&["XREAD", "STREAMS", "s", &max]), Nil);
+    assert_eq!(conn.call(&["XGROUP", "CREATE", "s", "g", "0"]), Status("OK".to_string()));
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", &max]),
+               Array(vec![Array(vec![bulk("s"), Array(vec![])])]));
+    assert_eq!(conn.call(&["XACK", "s", "g", &max]), Int(1));
+    assert_eq!(conn.call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]), Nil);
+    assert_eq!(conn.call(&["PING"]), Status("PONG".to_string()));
+}
@@ -0,0 +1,218 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lmqueue::{AnyQueue, Client, ErrorKind, Server};
+
+fn serve(queue: &lmqueue::Queue) -> SocketAddr {
+    let server = Server::bind(queue, "127.0.0.1:0").expect("bind");
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+#[test]
+fn can_produce_and_consume_remotely() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let client = Client::connect(serve(&queue)).expect("connect");
+
+    let mut prod = client.producer();
+    assert_eq!(prod.produce(b"0").expect("produce"), 1);
+    assert_eq!(prod.produce_batch(&[b"1", b"2"]).expect("produce batch"), vec![2, 3]);
+
+    let mut cons = client.consumer("one").expect("consumer");
+    let first = cons.poll().expect("poll").expect("some entry");
+    assert_eq!(first.data, b"0");
+    cons.commit_upto(&first).expect("commit");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"1".to_vec()));
+
+    assert_eq!(client.consumer_offset("one").expect("offset"), 1);
+    assert_eq!(queue.consumer_offset("one").expect("local offset"), 1);
+    assert_eq!(client.consumers().expect("consumers").into_iter().collect::<Vec<_>>(),
+               vec![("one".to_string(), 1)]);
+
+    // Entries produced remotely are visible locally, too.
+    let mut local = queue.consumer("local").expect("consumer");
+    assert_eq!(local.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
+
+#[test]
+fn long_poll_wakes_on_produce() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+    let mut cons = Client::connect(addr).expect("connect").consumer("one").expect("consumer");
+
+    let producer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        Client::connect(addr).expect("connect").producer().produce(b"0").expect("produce");
+    });
+    let start = Instant::now();
+    let entry = cons.poll_timeout(Duration::from_secs(10)).expect("poll");
+    assert_eq!(entry.map(|e| e.data), Some(b"0".to_vec()));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    producer.join().expect("producer thread");
+
+    let start = Instant::now();
+    assert_eq!(cons.poll_timeout(Duration::from_millis(50)).expect("poll"), None);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[test]
+fn polls_wait_no_longer_than_the_server_allows() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let server = Server::bind(&queue, "127.0.0.1:0")
+                     .expect("bind")
+                     .max_poll_timeout(Duration::from_millis(100));
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    let client = Client::connect(addr).expect("connect");
+    let mut cons = client.consumer("one").expect("consumer");
+
+    // Far more milliseconds than an `Instant` can be moved on by.
+    let forever = Duration::from_millis(u64::MAX);
+    assert_eq!(cons.poll_timeout(forever).expect("poll"), None);
+    client.producer().produce(b"0").expect("produce");
+    assert_eq!(cons.poll_timeout(forever).expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
+
+#[test]
+fn limits_the_consumers_a_connection_opens() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let client = Client::connect(serve(&queue)).expect("connect");
+
+    // The same name doesn't count twice.
+    for _ in 0..300 {
+        client.consumer("same").expect("consumer");
+    }
+    for n in 1..256 {
+        client.consumer(&n.to_string()).expect("consumer");
+    }
+    match client.consumer("one too many") {
+        Err(ref e) if matches!(*e.kind(), ErrorKind::Remote(_)) => (),
+        other => panic!("expected the server to refuse, got {:?}", other),
+    }
+}
+
+#[test]
+fn reports_remote_errors() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let client = Client::connect(serve(&queue)).expect("connect");
+
+    let err = client.consumer_offset("nobody").expect_err("no such consumer");
+    assert!(matches!(*err.kind(), ErrorKind::NotFound));
+    assert_eq!(err.consumer(), Some("nobody"));
+
+    let mut prod = client.producer();
+    for n in 0..3u8 {
+        prod.produce(&[n]).expect("produce");
+    }
+    let mut cons = client.consumer("one").expect("consumer");
+    let first = cons.poll().expect("poll").expect("entry");
+    client.discard_upto(2).expect("trim");
+    let err = cons.poll().expect_err("poll should notice the gap");
+    match *err.kind() {
+        ErrorKind::TrimmedGap { expected, available } => {
+            assert_eq!(expected, first.offset + 1);
+            assert_eq!(available, 3);
+        }
+        ref other => panic!("expected TrimmedGap, got {:?}", other),
+    }
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(vec![2]));
+}
+
+#[cfg(unix)]
+#[test]
+fn can_serve_over_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let sock_dir = tempdir::TempDir::new("sock").expect("sock-dir");
+    let sock = sock_dir.path().join("q.sock");
+    let server = lmqueue::UnixServer::bind(&queue, &sock, 0o600).expect("bind");
+    let mode = std::fs::metadata(&sock).expect("metadata").permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // Nothing is left over from binding it.
+    let names: Vec<_> = std::fs::read_dir(sock_dir.path())
+                            .expect("read dir")
+                            .map(|e| e.expect("entry").file_name())
+                            .collect();
+    assert_eq!(names, vec![std::ffi::OsString::from("q.sock")]);
+    thread::spawn(move || server.run().expect("run"));
+
+    let client = Client::open(&format!("unix://{}", sock.display())).expect("open");
+    assert_eq!(client.producer().produce(b"0").expect("produce"), 1);
+    let mut cons = client.consumer("one").expect("consumer");
+    assert_eq!(cons.poll().expect("poll").map(|e| e.data), Some(b"0".to_vec()));
+}
+
+#[cfg(unix)]
+#[test]
+fn replaces_stale_unix_socket() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let sock_dir = tempdir::TempDir::new("sock").expect("sock-dir");
+    let sock = sock_dir.path().join("q.sock");
+    // A socket nobody is listening on any more.
+    drop(std::os::unix::net::UnixListener::bind(&sock).expect("bind stale"));
+    assert!(sock.exists());
+
+    let server = lmqueue::UnixServer::bind(&queue, &sock, 0o600).expect("bind");
+    // But a live one is left alone.
+    assert!(lmqueue::UnixServer::bind(&queue, &sock, 0o600).is_err());
+    drop(server);
+    assert!(!sock.exists());
+
+    // Nor is anything that isn't a socket.
+    std::fs::write(&sock, b"keep").expect("write");
+    assert!(lmqueue::UnixServer::bind(&queue, &sock, 0o600).is_err());
+    assert_eq!(std::fs::read(&sock).expect("read"), b"keep");
+}
+
+#[test]
+fn opens_local_and_served_queues_alike() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let local = AnyQueue::open(dir.path().to_str().expect("utf-8 path")).expect("open local");
+    let queue = match local {
+        AnyQueue::Local(ref queue) => queue.clone(),
+        ref other => panic!("expected a local queue, got {:?}", other),
+    };
+    let remote = AnyQueue::open(&format!("tcp://{}", serve(&queue))).expect("open remote");
+
+    for (n, q) in [&local, &remote].iter().enumerate() {
+        let offset = q.producer().produce(&[n as u8]).expect("produce");
+        let mut cons = q.consumer(&n.to_string()).expect("consumer");
+        let entry = cons.poll_timeout(Duration::from_millis(10)).expect("poll").expect("entry");
+        assert_eq!(entry.data, vec![0]);
+        cons.commit_upto(&entry).expect("commit");
+        assert_eq!(q.consumer_offset(&n.to_string()).expect("offset"), 1);
+        assert_eq!(offset, n as u64 + 1);
+    }
+    assert_eq!(remote.consumers().expect("consumers"), local.consumers().expect("consumers"));
+    remote.discard_upto(1).expect("trim");
+    assert_eq!(queue.entries(0, 10).expect("entries").len(), 1);
+}
+
+#[test]
+fn rejects_unknown_address_schemes() {
+    env_logger::init().unwrap_or(());
+    assert!(Client::open("udp://127.0.0.1:7373").is_err());
+    assert!(AnyQueue::open("udp://127.0.0.1:7373").is_err());
+}
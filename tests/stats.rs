@@ -0,0 +1,32 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+#[test]
+fn reports_contents_and_space() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+
+    let stats = queue.stats().expect("stats");
+    assert_eq!((stats.records, stats.payload_bytes), (0, 0));
+    assert_eq!((stats.first, stats.last, stats.writer_next), (None, None, 0));
+
+    queue.producer().produce_batch(&[&b"a"[..], &b"bb"[..], &b"ccc"[..]]).expect("produce");
+    queue.discard_upto(1).expect("discard");
+    let entry = queue.entries(2, 1).expect("entries").remove(0);
+    queue.consumer("reader").expect("consumer").commit_upto(&entry).expect("commit");
+
+    let stats = queue.stats().expect("stats");
+    assert_eq!((stats.records, stats.payload_bytes), (2, 5));
+    assert_eq!((stats.first, stats.last, stats.writer_next), (Some(2), Some(3), 3));
+    assert!(stats.env.page_size > 0);
+    assert!(stats.env.used_bytes() + stats.env.free_bytes() == stats.env.map_size);
+    assert!(stats.env.readers <= stats.env.max_readers);
+    let entries: Vec<(&str, u64)> = stats.databases
+                                         .iter()
+                                         .map(|db| (&*db.name, db.entries))
+                                         .collect();
+    assert_eq!(entries[1..], [("cons", 1), ("data", 2)]);
+    assert!(stats.databases[2].pages() > 0);
+}
@@ -0,0 +1,210 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use lmqueue::StompServer;
+
+fn serve(queue: &lmqueue::Queue) -> SocketAddr {
+    let server = StompServer::bind("127.0.0.1:0").expect("bind").destination("/queue/q", queue);
+    let addr = server.local_addr().expect("local addr");
+    thread::spawn(move || server.run().expect("run"));
+    addr
+}
+
+#[derive(Debug)]
+struct Frame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    fn get(&self, name: &str) -> &str {
+        self.headers.iter().find(|h| h.0 == name).map(|h| &*h.1).expect(name)
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).expect("connect");
+        stream.set_read_timeout(Some(Duration::from_secs(10))).expect("timeout");
+        let mut client = Client { stream };
+        client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], b"");
+        let frame = client.recv();
+        assert_eq!(frame.command, "CONNECTED");
+        assert_eq!(frame.get("version"), "1.2");
+        client
+    }
+
+    fn send(&mut self, command: &str, headers: &[(&str, &str)], body: &[u8]) {
+        let mut out = format!("{}\n", command).into_bytes();
+        for h in headers {
+            out.extend_from_slice(format!("{}:{}\n", h.0, h.1).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(body);
+        out.push(0);
+        self.stream.write_all(&out).expect("write");
+    }
+
+    // Sends a frame asking for a receipt, and waits for it.
+    fn call(&mut self, command: &str, headers: &[(&str, &str)], body: &[u8]) {
+        let mut headers = headers.to_vec();
+        headers.push(("receipt", "r"));
+        self.send(command, &headers, body);
+        let frame = self.recv();
+        assert_eq!(frame.command, "RECEIPT", "{:?}", frame);
+        assert_eq!(frame.get("receipt-id"), "r");
+    }
+
+    fn recv(&mut self) -> Frame {
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\n\n") {
+            self.stream.read_exact(&mut byte).expect("read head");
+            if head.is_empty() && byte[0] == b'\n' {
+                continue;
+            }
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).expect("utf-8");
+        let mut lines = head.trim_end().lines();
+        let command = lines.next().expect("command").to_string();
+        let headers: Vec<(String, String)> = lines.map(|l| {
+                                                      let i = l.find(':').expect("colon");
+                                                      (l[..i].to_string(), l[i + 1..].to_string())
+                                                  })
+                                                  .collect();
+        let mut body = Vec::new();
+        loop {
+            self.stream.read_exact(&mut byte).expect("read body");
+            if byte[0] == 0 {
+                break;
+            }
+            body.push(byte[0]);
+        }
+        Frame {
+            command,
+            headers,
+            body,
+        }
+    }
+}
+
+fn wait_for_offset(queue: &lmqueue::Queue, name: &str, offset: u64) {
+    for _ in 0..100 {
+        if queue.consumer_offset(name).ok() == Some(offset) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("{:?} never reached {}", name, offset);
+}
+
+#[test]
+fn delivers_sent_messages() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    let mut client = Client::connect(addr);
+    client.call("SEND", &[("destination", "/queue/q")], b"hello");
+    client.call("BEGIN", &[("transaction", "t")], b"");
+    client.call("SEND", &[("destination", "/queue/q"), ("transaction", "t")], b"one");
+    client.call("SEND", &[("destination", "/queue/q"), ("transaction", "t")], b"two");
+    assert_eq!(queue.entries(0, 10).expect("entries").len(), 1);
+    client.call("COMMIT", &[("transaction", "t")], b"");
+    assert_eq!(queue.entries(0, 10).expect("entries").len(), 3);
+
+    client.call("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/q")], b"");
+    for (offset, body) in vec!["hello", "one", "two"].into_iter().enumerate() {
+        let frame = client.recv();
+        assert_eq!(frame.command, "MESSAGE");
+        assert_eq!(frame.get("subscription"), "0");
+        assert_eq!(frame.get("message-id"), (offset + 1).to_string());
+        assert_eq!(frame.body, body.as_bytes());
+    }
+    wait_for_offset(&queue, "0", 3);
+
+    client.send("SEND", &[("destination", "/queue/nowhere")], b"lost");
+    assert_eq!(client.recv().command, "ERROR");
+}
+
+#[test]
+fn client_acks_commit_cumulatively() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+    let mut producer = queue.producer();
+    for msg in &["a", "b", "c"] {
+        producer.produce(msg.as_bytes()).expect("produce");
+    }
+
+    let mut client = Client::connect(addr);
+    client.call("SUBSCRIBE",
+                &[("id", "s"), ("destination", "/queue/q"), ("ack", "client"),
+                  ("consumer", "workers")],
+                b"");
+    let acks: Vec<String> = (0..3).map(|_| client.recv().get("ack").to_string()).collect();
+    client.call("ACK", &[("id", &acks[1])], b"");
+    wait_for_offset(&queue, "workers", 2);
+    drop(client);
+
+    // The unacknowledged message is sent again.
+    let mut client = Client::connect(addr);
+    client.call("SUBSCRIBE",
+                &[("id", "s"), ("destination", "/queue/q"), ("ack", "client"),
+                  ("consumer", "workers")],
+                b"");
+    assert_eq!(client.recv().body, b"c");
+}
+
+#[test]
+fn individual_acks_commit_up_to_the_first_unacknowledged() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+    let mut producer = queue.producer();
+    for msg in &["a", "b", "c"] {
+        producer.produce(msg.as_bytes()).expect("produce");
+    }
+
+    let mut client = Client::connect(addr);
+    client.call("SUBSCRIBE",
+                &[("id", "s"), ("destination", "/queue/q"), ("ack", "client-individual")],
+                b"");
+    let acks: Vec<String> = (0..3).map(|_| client.recv().get("ack").to_string()).collect();
+    client.call("ACK", &[("id", &acks[1])], b"");
+    client.call("ACK", &[("id", &acks[2])], b"");
+    assert_eq!(queue.consumer_offset("s").unwrap_or(0), 0);
+    client.call("ACK", &[("id", &acks[0])], b"");
+    assert_eq!(queue.consumer_offset("s").expect("offset"), 3);
+}
+
+#[test]
+fn crlf_frames_may_hold_blank_lines() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let addr = serve(&queue);
+
+    let mut client = Client::connect(addr);
+    client.stream
+          .write_all(b"SEND\r\ndestination:/queue/q\r\nreceipt:r\r\n\r\nfirst\n\nsecond\0")
+          .expect("write");
+    let frame = client.recv();
+    assert_eq!(frame.command, "RECEIPT", "{:?}", frame);
+    assert_eq!(queue.entries(0, 10).expect("entries")[0].data, b"first\n\nsecond");
+}
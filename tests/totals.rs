@@ -0,0 +1,54 @@
+extern crate lmqueue;
+extern crate tempdir;
+extern crate env_logger;
+
+use lmqueue::Totals;
+
+fn totals(records: u64, payload_bytes: u64) -> Totals {
+    Totals {
+        records,
+        payload_bytes,
+    }
+}
+
+#[test]
+fn follow_produce_and_discard() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    assert_eq!(queue.totals().expect("totals"), totals(0, 0));
+
+    let mut producer = queue.producer();
+    producer.produce_batch(&[&b"a"[..], &b"bb"[..], &b"ccc"[..]]).expect("produce");
+    producer.produce_at(10, b"dddd").expect("produce at");
+    assert_eq!(queue.totals().expect("totals"), totals(4, 10));
+
+    // A conflicting write is rolled back, totals and all.
+    assert!(producer.produce_at(10, b"eeeee").is_err());
+    assert_eq!(queue.totals().expect("totals"), totals(4, 10));
+
+    queue.discard_upto(2).expect("discard");
+    assert_eq!(queue.totals().expect("totals"), totals(2, 7));
+    queue.discard_upto(2).expect("discard");
+    assert_eq!(queue.totals().expect("totals"), totals(2, 7));
+    queue.discard_upto(10).expect("discard");
+    assert_eq!(queue.totals().expect("totals"), totals(0, 0));
+    assert!(queue.verify().expect("verify").is_ok());
+}
+
+#[test]
+fn are_counted_again_on_load() {
+    env_logger::init().unwrap_or(());
+    let src = tempdir::TempDir::new("src").expect("src-dir");
+    let dst = tempdir::TempDir::new("dst").expect("dst-dir");
+    let queue = lmqueue::Queue::open(src.path()).expect("queue");
+    queue.producer().produce_batch(&[&b"one"[..], &b"two"[..], &b"three"[..]]).expect("produce");
+    queue.discard_upto(1).expect("discard");
+
+    let mut dump = Vec::new();
+    queue.dump_to(&mut dump).expect("dump");
+    let copy = lmqueue::Queue::open(dst.path()).expect("copy");
+    copy.load_from(&dump[..]).expect("load");
+    assert_eq!(copy.totals().expect("totals"), totals(2, 8));
+    assert_eq!(copy.stats().expect("stats").payload_bytes, 8);
+}
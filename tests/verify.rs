@@ -0,0 +1,132 @@
+extern crate lmqueue;
+extern crate lmdb_zero;
+extern crate byteorder;
+extern crate crc32c;
+extern crate tempdir;
+extern crate env_logger;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use lmqueue::{ErrorKind, Problem, Totals};
+
+// Writes `key` => `val` into the named database, bypassing lmqueue.
+fn put_raw(dir: &Path, db: &str, key: &[u8], val: &[u8]) {
+    let mut b = lmdb_zero::EnvBuilder::new().expect("env builder");
+    b.set_maxdbs(3).expect("maxdbs");
+    let env = Arc::new(unsafe {
+        b.open(dir.to_str().expect("path string"),
+               lmdb_zero::open::Flags::empty(),
+               0o600)
+         .expect("env")
+    });
+    let db = lmdb_zero::Database::open(env.clone(),
+                                       Some(db),
+                                       &lmdb_zero::DatabaseOptions::new(lmdb_zero::db::CREATE))
+                 .expect("db");
+    let txn = lmdb_zero::WriteTransaction::new(env.clone()).expect("txn");
+    txn.access().put(&db, key, val, lmdb_zero::put::Flags::empty()).expect("put");
+    txn.commit().expect("commit");
+}
+
+fn produce_all(dir: &Path, msgs: &[&[u8]]) {
+    let queue = lmqueue::Queue::open(dir).expect("queue");
+    let mut prod = queue.producer();
+    for msg in msgs {
+        prod.produce(msg).expect("produce");
+    }
+}
+
+#[test]
+fn clean_queue_verifies() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    produce_all(dir.path(), &[b"0", b"1", b""]);
+
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let report = queue.verify().expect("verify");
+    assert_eq!(report.records, 3);
+    assert_eq!(report.last, Some(3));
+    assert!(report.is_ok(), "problems: {:?}", report.problems);
+}
+
+#[test]
+fn poll_rejects_corrupted_record() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    produce_all(dir.path(), &[b"hello", b"world"]);
+    // A flipped bit in the payload of the first record.
+    let mut record = lmqueue_record(b"hello");
+    record[4] ^= 0x01;
+    put_raw(dir.path(), "data", &[0, 0, 0, 0, 0, 0, 0, 1], &record);
+
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let mut cons = queue.consumer("one").expect("consumer");
+    let err = cons.poll().expect_err("poll should fail");
+    match *err.kind() {
+        ErrorKind::ChecksumMismatch { offset, .. } => assert_eq!(offset, 1),
+        ref other => panic!("expected ChecksumMismatch, got {:?}", other),
+    }
+    assert_eq!(err.consumer(), Some("one"));
+
+    let report = queue.verify().expect("verify");
+    assert_eq!(report.records, 2);
+    match report.problems[..] {
+        [Problem::ChecksumMismatch { offset: 1, .. }] => (),
+        ref other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn reports_bad_keys_and_truncated_records() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    produce_all(dir.path(), &[b"0", b"1"]);
+    put_raw(dir.path(), "data", &[0, 0, 0, 0, 0, 0, 0, 2], b"ab");
+    put_raw(dir.path(), "data", &[0, 0, 0, 0, 0, 0, 0, 2, 0], &lmqueue_record(b"x"));
+
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let report = queue.verify().expect("verify");
+    // Writing behind lmqueue's back also throws out its running totals.
+    let stored = Totals {
+        records: 2,
+        payload_bytes: 2,
+    };
+    let counted = Totals {
+        records: 3,
+        payload_bytes: 2,
+    };
+    assert_eq!(report.problems,
+               vec![Problem::Truncated { offset: 2 },
+                    Problem::BadKey { after: 2 },
+                    Problem::WrongTotals {
+                        stored,
+                        counted,
+                    }]);
+}
+
+#[test]
+fn reports_writer_behind_last_record() {
+    env_logger::init().unwrap_or(());
+    let dir = tempdir::TempDir::new("store").expect("store-dir");
+    produce_all(dir.path(), &[b"0", b"1", b"2"]);
+    put_raw(dir.path(), "prod", b"writer-next", &[0, 0, 0, 0, 0, 0, 0, 1]);
+
+    let queue = lmqueue::Queue::open(dir.path()).expect("queue");
+    let report = queue.verify().expect("verify");
+    assert_eq!(report.problems,
+               vec![Problem::WriterBehind {
+                        writer_next: 1,
+                        last: 3,
+                    }]);
+}
+
+// What lmqueue stores for `msg`: a big-endian CRC32C, then the message.
+fn lmqueue_record(msg: &[u8]) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.write_u32::<BigEndian>(crc32c::crc32c(msg)).expect("write checksum");
+    record.extend_from_slice(msg);
+    record
+}